@@ -1,10 +1,17 @@
 extern crate nom_locate;
 
 use std::collections::hash_map::HashMap;
+use std::collections::BTreeMap;
 use nom_locate::LocatedSpan;
+use serde::{Serialize, Serializer};
 
 pub type Span<'a> = LocatedSpan<&'a str>;
-#[derive(Debug, PartialEq, Clone)]
+
+// HashMaps are serialized in key order so that dumps can be diffed.
+fn ordered_map<S: Serializer, K: Ord + Serialize, V: Serialize>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error> {
+  map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct SafeSpan { pub offset: usize, pub line: u32 }
 
 pub trait Safe {
@@ -17,14 +24,14 @@ impl <'a> Safe for Span<'a> {
   }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
 pub enum Usage {
   Read,
   Write,
 }
 
 // TODO: consider making references Rc<Vec<Type>> so this is copiable.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Clone, Debug, PartialEq)]
 pub enum Type {
   Int,
   String,
@@ -37,7 +44,7 @@ pub enum Type {
   NewType(String, Box<Type>)
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
 pub enum ListenerKind {
   OnChange,
   OnWrite
@@ -52,7 +59,7 @@ impl ListenerKind {
   }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct Handle {
   pub position: SafeSpan,
   pub name: String,
@@ -70,14 +77,14 @@ impl Handle {
   }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct CopyTo {
   pub state: String,
   pub submodule_index: usize,
   pub submodule_state: String,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct WriteToTuple {
   pub state: String,
   pub tuple_fields: Vec<String>,
@@ -85,7 +92,14 @@ pub struct WriteToTuple {
   pub tuple_index: usize,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct MergeInto {
+  pub state: String,
+  pub target: String,
+  pub policy: MergePolicy,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub enum Operator {
   Equality,
   Inequality,
@@ -121,7 +135,7 @@ impl Operator {
   }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct Expression {
   pub value: ExpressionValue,
   pub is_terminated: bool,
@@ -196,6 +210,10 @@ impl Expression {
     let write_to_tuple = WriteToTuple { state: state.to_string(), tuple_fields: tuple_fields.clone(), tuple_id, tuple_index };
     Expression::terminated(ExpressionValue { info: ExpressionValueEnum::WriteToTuple(write_to_tuple), position })
   }
+  pub fn merge_into(position: SafeSpan, state: &str, target: &str, policy: &MergePolicy) -> Self {
+    let merge_into = MergeInto { state: state.to_string(), target: target.to_string(), policy: policy.clone() };
+    Expression::terminated(ExpressionValue { info: ExpressionValueEnum::MergeInto(merge_into), position })
+  }
 }
 
 pub struct Expr<'a> {
@@ -265,13 +283,13 @@ impl <'a> Expr<'a> {
   }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct ExpressionValue {
   pub info: ExpressionValueEnum,
   pub position: SafeSpan
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub enum ExpressionValueEnum {
   // statement-like
   Output(OutputExpression),
@@ -297,6 +315,20 @@ pub enum ExpressionValueEnum {
 
   CopyToSubModule(CopyTo),
   WriteToTuple(WriteToTuple),
+  MergeInto(MergeInto),
+}
+
+impl ExpressionValue {
+  // The type of a literal (or a tuple of literals); None for anything that needs evaluating.
+  pub fn literal_type(&self) -> Option<Type> {
+    match &self.info {
+      ExpressionValueEnum::IntLiteral(_) => Some(Type::Int),
+      ExpressionValueEnum::StringLiteral(_) => Some(Type::String),
+      ExpressionValueEnum::CharLiteral(_) => Some(Type::Char),
+      ExpressionValueEnum::Tuple(members) => members.iter().map(|member| member.literal_type()).collect::<Option<Vec<_>>>().map(Type::Tuple),
+      _ => None
+    }
+  }
 }
 
 impl From<Expression> for ExpressionValue {
@@ -305,71 +337,73 @@ impl From<Expression> for ExpressionValue {
   }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct OutputExpression {
   pub output: String,
   pub expression: Box<ExpressionValue>,
   pub and_return: bool,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct LetExpression {
   pub var_name: String,
   pub expression: Box<ExpressionValue>,
   pub is_update: bool
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct IfExpression {
   pub test: Box<ExpressionValue>,
   pub if_true: Box<ExpressionValue>,
   pub if_false: Box<ExpressionValue>
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct WhileExpression {
   pub test: Box<ExpressionValue>,
   pub body: Box<ExpressionValue>
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct Listener {
   pub trigger: String,
   pub kind: ListenerKind,
   pub implementation: ExpressionValue,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct ModuleInfo {
   pub module: Module,
+  #[serde(serialize_with = "ordered_map")]
   pub handle_map: HashMap<String, String>,
   pub params: ParamAssignment,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct ExampleInfo {
   pub value: Expression,
   pub is_update: bool,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct Example {
+  pub position: SafeSpan,
   pub inputs: HashMap<String, ExampleInfo>,
   pub expected: HashMap<String, ExampleInfo>,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct Examples {
   pub examples: Vec<Example>,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct ValueParam {
   pub name: String,
   pub vp_type: Type,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct Module {
   pub name: String,
   pub handles: Vec<Handle>,
@@ -378,7 +412,14 @@ pub struct Module {
   pub examples: Examples,
   pub value_params: Vec<ValueParam>,
   pub graph: Vec<GraphDirective>,
-  pub tuples: HashMap<usize, usize>
+  #[serde(serialize_with = "ordered_map")]
+  pub tuples: HashMap<usize, usize>,
+  // values that <Module>_init writes into handles as pending updates
+  pub initial_values: Vec<(String, ExpressionValue)>,
+  // the declared bound on update passes for a graph with feedback loops
+  pub iteration_bound: Option<u64>,
+  // handles added by graph_to_module to wire up submodules, rather than declared
+  pub synthetic_handles: Vec<String>
 }
 
 /**
@@ -387,7 +428,9 @@ pub struct Module {
  *  current updates bitmap
  *  (optional) tuple initialization bitmap
  *  [ param_value ]
- *  [ submodule pointer ]
+ *  [ submodule state ]
+ *  [ callback, userdata ] for each output, on the top-level module only
+ * ir_gen::c_header writes this out as a C struct.
  */
 impl Module {
   pub fn minidump(&self) -> String {
@@ -411,6 +454,12 @@ impl Module {
     self.handles.len() * 2
   }
 
+  // The bit after the handles' bits is set when a submodule gives up without settling during an update. The next
+  // update clears it.
+  pub fn not_converged_bit(&self) -> usize {
+    self.handles.len()
+  }
+
   pub fn idx_for_tuple_field(&self) -> usize {
     if self.tuples.len() == 0 {
       panic!("Should not be retrieving tuple field idx for modules with no tuples");
@@ -439,6 +488,14 @@ impl Module {
     self.offset_for_value_params() + self.value_params.len()
   }
 
+  pub fn offset_for_callbacks(&self) -> usize {
+    self.offset_for_submodules() + self.submodules.len()
+  }
+
+  pub fn idx_for_callback(&self, field: &str) -> Option<usize> {
+    self.outputs().iter().position(|handle| handle.name == field).map(|idx| self.offset_for_callbacks() + 2 * idx)
+  }
+
   pub fn outputs(&self) -> Vec<&Handle> {
     self.handles.iter().filter(|&handle| handle.is_output()).collect()
   }
@@ -483,11 +540,11 @@ impl Module {
     value_params: Vec<ValueParam>,
     graph: Vec<GraphDirective>
   ) -> Self {
-    Module { name: name.to_string(), handles, listeners, submodules, examples, value_params, graph, tuples: HashMap::new() }
+    Module { name: name.to_string(), handles, listeners, submodules, examples, value_params, graph, tuples: HashMap::new(), initial_values: Vec::new(), iteration_bound: None, synthetic_handles: Vec::new() }
   }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub enum ModuleSpecifier {
   This,
   Module(String),
@@ -496,7 +553,7 @@ pub enum ModuleSpecifier {
   Name(String),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct ParamAssignment {
   pub params: Vec<Expression>
 }
@@ -507,11 +564,15 @@ impl ParamAssignment {
   }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub enum GraphModuleInfo {
   Module(ModuleSpecifier, ParamAssignment),
   Field(ModuleSpecifier, String),
   Tuple(Vec<GraphModuleInfo>),
+  // a constant source, e.g. 42 -> Threshold
+  Literal(Expression),
+  // |x| expression
+  Transform(String, Expression),
 }
 
 impl GraphModuleInfo {
@@ -527,26 +588,62 @@ impl GraphModuleInfo {
   }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// How writes from several submodules into the same handle are combined. Writes are merged while the handle has
+// an update pending; once the update has been processed the next write starts afresh.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub enum MergePolicy {
+  LastWriteWins,
+  FirstWriteWins,
+  // |pending, incoming| expression
+  Combine(String, String, Box<ExpressionValue>),
+}
+
+// The condition for one arm of a switch.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub enum SwitchCase {
+  // the switched module wrote this output
+  Output(String),
+  // |x| predicate
+  When(String, Expression),
+  // _
+  Otherwise,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub enum GraphDirective {
   Chain(Vec<GraphModuleInfo>),
+  // merge field: policy;
+  Merge(String, MergePolicy),
+  // switch source { case -> chain; ... }
+  Switch(GraphModuleInfo, Vec<(SwitchCase, Vec<GraphModuleInfo>)>),
+  // iterate 16; - allows feedback loops, which get at most this many update passes to settle
+  Iterate(u64),
+}
+
+// graph Name<params> { ... } - a piece of graph that other graphs can splice in by name
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct GraphFragment {
+  pub name: String,
+  pub params: Vec<String>,
+  pub graph: Vec<GraphDirective>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Debug, PartialEq)]
 pub struct Use {
   pub name: String,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct NewType {
   pub name: String,
   pub nt_type: Type,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Debug, PartialEq)]
 pub enum TopLevel {
   Module(Module),
   Graph(GraphDirective),
+  Fragment(GraphFragment),
   Use(Use),
   NewType(NewType),
 }
@@ -588,6 +685,15 @@ pub fn graphs(ast: &Vec<TopLevel>) -> Vec<&GraphDirective> {
   }).collect()
 }
 
+pub fn fragments(ast: &Vec<TopLevel>) -> Vec<&GraphFragment> {
+  ast.iter().filter_map(|top_level| {
+    match top_level {
+      TopLevel::Fragment(f) => Some(f),
+      _ => None
+    }
+  }).collect()
+}
+
 pub fn newtypes(ast: &Vec<TopLevel>) -> Vec<&NewType> {
   ast.iter().filter_map(|top_level| {
     match top_level {
@@ -0,0 +1,153 @@
+// Graphviz renderings of resolved graphs and of module hierarchies (--emit graph-dot).
+
+use super::ast;
+use super::graph::{Graph, SimpleEndpoint, ArrowInfo};
+
+use std::collections::HashMap;
+
+fn quote(s: &str) -> String {
+  format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn node_id(endpoint: &SimpleEndpoint) -> String {
+  match endpoint {
+    SimpleEndpoint::Module(idx) => format!("m{}", idx),
+    SimpleEndpoint::Connection(idx) => format!("c{}", idx),
+    SimpleEndpoint::Handle(idx) => format!("h{}", idx),
+  }
+}
+
+fn edge(from: &str, to: &str, label: Option<&String>) -> String {
+  match label {
+    Some(label) => format!("  {} -> {} [label={}];", from, to, quote(label)),
+    None => format!("  {} -> {};", from, to),
+  }
+}
+
+// Modules are boxes, connections are bare labels and handles are ellipses.
+pub fn graph_to_dot(name: &str, graph: &Graph) -> String {
+  let mut lines = vec!(format!("digraph {} {{", quote(name)));
+  let local_names: HashMap<usize, &String> = graph.names.iter().map(|(local_name, idx)| (*idx, local_name)).collect();
+  for (idx, module) in graph.modules.iter().enumerate() {
+    let label = match local_names.get(&idx) {
+      Some(local_name) => format!("${}:{}", local_name, module.name),
+      None => module.name.clone(),
+    };
+    lines.push(format!("  m{} [shape=box, label={}];", idx, quote(&label)));
+  }
+  for (idx, connection) in graph.connections.iter().enumerate() {
+    lines.push(format!("  c{} [shape=plaintext, label={}];", idx, quote(connection)));
+  }
+  for (idx, handle) in graph.handles.iter().enumerate() {
+    lines.push(format!("  h{} [shape=ellipse, label={}];", idx, quote(&format!("{}: {:?}", handle.name, handle.h_type))));
+  }
+  for arrow in graph.arrows() {
+    let label = match arrow.info {
+      ArrowInfo::None => None,
+      ArrowInfo::TupleConstructor(id, position) => Some(format!("tuple {} .{}", id, position)),
+      ArrowInfo::TupleDestructor(position) => Some(format!(".{}", position)),
+      ArrowInfo::Transform(idx) => Some(format!("|{}|", graph.transforms[idx].param)),
+      ArrowInfo::SwitchCase(switch, case) => Some(format!("switch {} case {}", switch, case)),
+    };
+    // an arrow to or from a tuple is drawn as an edge per member
+    for from in arrow.from.all() {
+      for to in arrow.to.all() {
+        lines.push(edge(&node_id(from), &node_id(to), label.as_ref()));
+      }
+    }
+  }
+  lines.push("}".to_string());
+  lines.join("\n") + "\n"
+}
+
+// Each module is a cluster of its handles, with its submodules nested inside. Synthetic handles are dashed,
+// and handle_map entries are edges between a submodule's handles and the parent's.
+pub fn module_to_dot(module: &ast::Module) -> String {
+  let mut lines = vec!(format!("digraph {} {{", quote(&module.name)));
+  let mut edges = Vec::new();
+  module_cluster(module, "m", &mut lines, &mut edges);
+  lines.append(&mut edges);
+  lines.push("}".to_string());
+  lines.join("\n") + "\n"
+}
+
+fn module_cluster(module: &ast::Module, prefix: &str, lines: &mut Vec<String>, edges: &mut Vec<String>) {
+  lines.push(format!("  subgraph cluster_{} {{", prefix));
+  lines.push(format!("  label={};", quote(&module.name)));
+  for (idx, handle) in module.handles.iter().enumerate() {
+    let style = if module.synthetic_handles.contains(&handle.name) { ", style=dashed" } else { "" };
+    lines.push(format!("  {}_h{} [shape=ellipse, label={}{}];", prefix, idx, quote(&format!("{}: {:?}", handle.name, handle.h_type)), style));
+  }
+  for (submodule_idx, submodule) in module.submodules.iter().enumerate() {
+    let sub_prefix = format!("{}_{}", prefix, submodule_idx);
+    module_cluster(&submodule.module, &sub_prefix, lines, edges);
+    let mut mapping: Vec<(&String, &String)> = submodule.handle_map.iter().collect();
+    mapping.sort();
+    for (sub_handle, handle) in mapping {
+      let sub_idx = submodule.module.idx_for_field(sub_handle);
+      let idx = module.idx_for_field(handle);
+      if let (Some(sub_idx), Some(idx)) = (sub_idx, idx) {
+        let sub_node = format!("{}_h{}", sub_prefix, sub_idx);
+        let node = format!("{}_h{}", prefix, idx);
+        if submodule.module.handles[sub_idx].is_input() {
+          edges.push(edge(&node, &sub_node, None));
+        } else {
+          edges.push(edge(&sub_node, &node, None));
+        }
+      }
+    }
+  }
+  lines.push("  }".to_string());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::{parser, graph_builder, graph_to_module};
+
+  static PIPELINE_STRING: &str = "
+module Pairer {
+  input: reads Int;
+  pair: writes (Int, Int);
+}
+
+module Tens {
+  input: reads Int;
+  tens: writes Int;
+}
+
+Pairer -> (Tens, Tens);
+";
+
+  fn resolved() -> (Vec<ast::TopLevel>, Graph) {
+    let (_, ast) = parser::parse(PIPELINE_STRING).unwrap();
+    let mut graph = graph_builder::make_graph(ast::graphs(&ast), &Vec::new()).unwrap();
+    let main = ast::Module::create("Main", Vec::new(), Vec::new(), Vec::new(), ast::Examples { examples: Vec::new() }, Vec::new(), Vec::new());
+    graph_builder::resolve_graph(&main, &ast::modules(&ast), &mut graph).unwrap();
+    (ast, graph)
+  }
+
+  #[test]
+  fn graphs_render_every_node_and_arrow() {
+    let (_, graph) = resolved();
+    let dot = graph_to_dot("Main", &graph);
+    assert!(dot.starts_with("digraph \"Main\" {\n"));
+    assert!(dot.ends_with("}\n"));
+    for (idx, module) in graph.modules.iter().enumerate() {
+      assert!(dot.contains(&format!("  m{} [shape=box, label={}];", idx, quote(&module.name))));
+    }
+    assert_eq!(dot.matches(" -> ").count(), graph.arrows().iter().map(|arrow| arrow.from.all().len() * arrow.to.all().len()).sum());
+  }
+
+  #[test]
+  fn module_hierarchies_show_handle_maps() {
+    let (ast, graph) = resolved();
+    let mut main = ast::Module::create("Main", Vec::new(), Vec::new(), Vec::new(), ast::Examples { examples: Vec::new() }, Vec::new(), Vec::new());
+    graph_to_module::graph_to_module(&mut main, graph, ast::modules(&ast)).unwrap();
+    let dot = module_to_dot(&main);
+    assert_eq!(dot.matches("subgraph cluster_").count(), 1 + main.submodules.len());
+    let mapped: usize = main.submodules.iter().map(|submodule| submodule.handle_map.len()).sum();
+    assert_eq!(dot.matches(" -> ").count(), mapped);
+    assert_eq!(dot.matches("style=dashed").count(), main.synthetic_handles.len());
+  }
+}
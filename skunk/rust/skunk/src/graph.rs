@@ -1,7 +1,8 @@
 
-use super::ast::{Type, ModuleSpecifier, ParamAssignment};
+use super::ast::{Type, ModuleSpecifier, ParamAssignment, MergePolicy, Expression, ExpressionValue, SwitchCase};
 
 use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
 use std::slice::from_ref;
 
 #[derive(Debug)]
@@ -10,7 +11,7 @@ pub struct Handle {
   pub h_type: Type
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum SimpleEndpoint {
   Module(usize),
   Connection(usize),
@@ -92,7 +93,18 @@ pub enum ArrowInfo {
   // are multiple TupleConstructors pointing to the same tuple, they can be
   // distinguished), and the constructing index (i.e. which part of the tuple
   // is built by this arrow)
-  TupleConstructor(usize, usize)
+  TupleConstructor(usize, usize),
+  // ArrowInfo::TupleDestructor tags an arrow from a tuple-typed handle to a
+  // reader of one of its components; the value is the component's index.
+  TupleDestructor(usize),
+  // ArrowInfo::Transform tags an arrow between two handles where the value
+  // written to the second is computed from the first by a |x| node; the value
+  // indexes Graph::transforms.
+  Transform(usize),
+  // ArrowInfo::SwitchCase tags an arrow from the handle holding a switched
+  // value to the handle for one of the switch's predicate cases; the values
+  // are the switch's index in Graph::switches and the case's index in it.
+  SwitchCase(usize, usize)
 }
 
 #[derive(Debug, PartialEq)]
@@ -114,18 +126,53 @@ impl GraphModule {
   }
 }
 
+// A |param| body node. The handle holds the node's result.
+#[derive(Debug)]
+pub struct Transform {
+  pub handle: usize,
+  pub param: String,
+  pub body: Expression
+}
+
+// The predicate cases of a switch node, as (case handle, case). Cases that select a module output are plain ports.
+#[derive(Debug)]
+pub struct Switch {
+  pub source: Endpoint,
+  pub cases: Vec<(usize, SwitchCase)>
+}
+
+// A _ case of a switch that also has output cases: it takes every output of the module that no case names. target
+// is the first node of the case's chain.
+#[derive(Debug)]
+pub struct OtherOutputs {
+  pub module: usize,
+  pub named: Vec<String>,
+  pub target: Endpoint
+}
+
 #[derive(Debug)]
 pub struct Graph {
   pub modules: Vec<GraphModule>,
   pub connections: Vec<String>,
   pub handles: Vec<Handle>,
   arrows: Vec<Arrow>,
-  pub names: HashMap<String, usize>
+  pub names: HashMap<String, usize>,
+  // declared merge policies for handles with several writers, by handle name
+  pub merge_policies: HashMap<String, MergePolicy>,
+  // (module index, connection index) for ports named explicitly in the graph ($a.output). Whether the arrow
+  // goes into or out of the module isn't known until the module definitions are available.
+  pub ports: Vec<(usize, usize)>,
+  // (handle index, value) for literal sources in the graph
+  pub constants: Vec<(usize, ExpressionValue)>,
+  pub transforms: Vec<Transform>,
+  pub switches: Vec<Switch>,
+  pub other_outputs: Vec<OtherOutputs>,
+  pub iteration_bound: Option<u64>
 }
 
 impl Graph {
   pub fn new() -> Graph {
-    Graph { modules: Vec::new(), connections: Vec::new(), handles: Vec::new(), arrows: Vec::new(), names: HashMap::new() }
+    Graph { modules: Vec::new(), connections: Vec::new(), handles: Vec::new(), arrows: Vec::new(), names: HashMap::new(), merge_policies: HashMap::new(), ports: Vec::new(), constants: Vec::new(), transforms: Vec::new(), switches: Vec::new(), other_outputs: Vec::new(), iteration_bound: None }
   }
 
   pub fn add_module(&mut self, module_name: &ModuleSpecifier, params: &ParamAssignment) -> Endpoint {
@@ -152,6 +199,88 @@ impl Graph {
     Endpoint::Simple(SimpleEndpoint::Handle(self.handles.len() - 1))
   }
 
+  // Each literal in the graph gets a handle of its own, which starts out holding the literal's value.
+  pub fn add_constant(&mut self, value: &ExpressionValue, handle_type: Type) -> Endpoint {
+    let handle = self.add_handle(&format!("const{}", self.constants.len()), handle_type);
+    self.constants.push((handle.handle_idx().unwrap(), value.clone()));
+    handle
+  }
+
+  // The handle's type is worked out from the node's neighbours in resolve_transforms.
+  pub fn add_transform(&mut self, param: &str, body: &Expression) -> Endpoint {
+    let handle = self.add_handle(&format!("lambda{}", self.transforms.len()), Type::Unresolved);
+    self.transforms.push(Transform { handle: handle.handle_idx().unwrap(), param: param.to_string(), body: body.clone() });
+    handle
+  }
+
+  pub fn add_switch(&mut self, source: &Endpoint) -> usize {
+    self.switches.push(Switch { source: source.clone(), cases: Vec::new() });
+    self.switches.len() - 1
+  }
+
+  // Like a |x| node's handle, a case handle's type is only known once the switched value's type is.
+  pub fn add_switch_case(&mut self, switch_idx: usize, case: &SwitchCase) -> Endpoint {
+    let handle = self.add_handle(&format!("switch{}.case{}", switch_idx, self.switches[switch_idx].cases.len()), Type::Unresolved);
+    self.switches[switch_idx].cases.push((handle.handle_idx().unwrap(), case.clone()));
+    handle
+  }
+
+  // Which outputs these are is only known once the module's definition is, so they're connected in resolve_graph.
+  pub fn add_other_outputs(&mut self, module: &Endpoint, named: Vec<String>, target: &Endpoint) {
+    self.other_outputs.push(OtherOutputs { module: module.module_idx().unwrap(), named, target: target.clone() });
+  }
+
+  // Handles for |x| nodes and switch cases, rather than for fields of the module.
+  pub fn is_node_handle(&self, handle_idx: usize) -> bool {
+    self.transforms.iter().any(|transform| transform.handle == handle_idx)
+      || self.switches.iter().any(|switch| switch.cases.iter().any(|(case_handle, _)| *case_handle == handle_idx))
+  }
+
+  // Each named port of a module is represented by a single connection, no matter how often it's mentioned.
+  pub fn add_port(&mut self, module: &Endpoint, port_name: &str) -> Endpoint {
+    let module_idx = module.module_idx().unwrap();
+    let existing = self.ports.iter().find(|(m_idx, c_idx)| *m_idx == module_idx && self.connections[*c_idx] == port_name);
+    if let Some((_, connection_idx)) = existing {
+      return Endpoint::Simple(SimpleEndpoint::Connection(*connection_idx));
+    }
+    let connection = self.add_connection(port_name);
+    self.ports.push((module_idx, connection.connection_idx().unwrap()));
+    connection
+  }
+
+  pub fn port_module(&self, connection_idx: usize) -> Option<usize> {
+    self.ports.iter().find(|(_, c_idx)| *c_idx == connection_idx).map(|(m_idx, _)| *m_idx)
+  }
+
+  pub fn handle_named(&self, handle_name: &str) -> Option<Endpoint> {
+    self.handles.iter().position(|handle| handle.name == handle_name).map(|idx| Endpoint::Simple(SimpleEndpoint::Handle(idx)))
+  }
+
+  // If module already writes its connection_name output into a handle (module -> connection -> handle), return
+  // that handle so additional readers can share it.
+  pub fn handle_for_module_output(&self, module: SimpleEndpoint, connection_name: &str) -> Option<Endpoint> {
+    for arrow in self.arrows_matching(EndpointSpec::Specific(module), EndpointSpec::AnyConnection) {
+      let connection = match arrow.to.simple_endpoint() {
+        Some(connection) => connection,
+        None => continue
+      };
+      if connection.connection_idx().map(|idx| self.connections[idx].as_str()) != Some(connection_name) {
+        continue;
+      }
+      if let Some(handle) = self.handle_for_connection(connection) {
+        return Some(handle);
+      }
+    }
+    None
+  }
+
+  // The handle that connection writes into (connection -> handle), if there is one.
+  pub fn handle_for_connection(&self, connection: SimpleEndpoint) -> Option<Endpoint> {
+    self.arrows_matching(EndpointSpec::Specific(connection), EndpointSpec::AnyHandle).iter()
+      .find(|arrow| arrow.info == ArrowInfo::None && arrow.from == Endpoint::Simple(connection))
+      .map(|arrow| arrow.to.clone())
+  }
+
   pub fn connect(&mut self, from: &Endpoint, to: &Endpoint) -> usize {
     assert!(self.endpoint_is_valid(from));
     assert!(self.endpoint_is_valid(to));
@@ -166,6 +295,27 @@ impl Graph {
     self.arrows.len() - 1
   }
 
+  pub fn connect_tuple_destructor(&mut self, from: &Endpoint, to: &Endpoint, idx: usize) -> usize {
+    assert!(self.endpoint_is_valid(from));
+    assert!(self.endpoint_is_valid(to));
+    self.arrows.push(Arrow::new_with_info(from, to, ArrowInfo::TupleDestructor(idx)));
+    self.arrows.len() - 1
+  }
+
+  pub fn connect_transform(&mut self, from: &Endpoint, to: &Endpoint, idx: usize) -> usize {
+    assert!(self.endpoint_is_valid(from));
+    assert!(self.endpoint_is_valid(to));
+    self.arrows.push(Arrow::new_with_info(from, to, ArrowInfo::Transform(idx)));
+    self.arrows.len() - 1
+  }
+
+  pub fn connect_switch_case(&mut self, from: &Endpoint, to: &Endpoint, switch_idx: usize, case_idx: usize) -> usize {
+    assert!(self.endpoint_is_valid(from));
+    assert!(self.endpoint_is_valid(to));
+    self.arrows.push(Arrow::new_with_info(from, to, ArrowInfo::SwitchCase(switch_idx, case_idx)));
+    self.arrows.len() - 1
+  }
+
   fn simple_endpoint_is_valid(&self, endpoint: SimpleEndpoint) -> bool {
     match endpoint {
       SimpleEndpoint::Module(idx) => idx < self.modules.len(),
@@ -178,13 +328,21 @@ impl Graph {
     endpoint.all().iter().all(|e| self.simple_endpoint_is_valid(*e))
   }
 
+  pub fn arrows(&self) -> &[Arrow] {
+    &self.arrows
+  }
+
   // Remove and return any arrows matching (from_spec -> to_spec).
   // Note that this will pick up structure/destructure arrows where one endpoint matches from_spec or to_spec.
   fn filter_arrows(&mut self, from_spec: EndpointSpec, to_spec: EndpointSpec) -> Vec<Arrow> {
+    self.filter_arrows_by(|arrow| arrow.from.matches_spec(from_spec) && arrow.to.matches_spec(to_spec))
+  }
+
+  fn filter_arrows_by(&mut self, predicate: impl Fn(&Arrow) -> bool) -> Vec<Arrow> {
     let mut remaining = Vec::new();
     let mut returning = Vec::new();
     self.arrows.drain(..).for_each(|arrow| {
-      if arrow.from.matches_spec(from_spec) && arrow.to.matches_spec(to_spec) {
+      if predicate(&arrow) {
         returning.push(arrow);
       } else {
         remaining.push(arrow);
@@ -216,6 +374,46 @@ impl Graph {
     lhs.chain(&mut self.arrows_matching(spec, EndpointSpec::Specific(endpoint)).iter().map(|endpoint| (&endpoint.from, *endpoint))).collect()
   }
 
+  pub fn endpoint_name(&self, endpoint: SimpleEndpoint) -> &str {
+    match endpoint {
+      SimpleEndpoint::Module(idx) => &self.modules[idx].name,
+      SimpleEndpoint::Connection(idx) => &self.connections[idx],
+      SimpleEndpoint::Handle(idx) => &self.handles[idx].name,
+    }
+  }
+
+  // The endpoints along some cycle of arrows, if there is one.
+  pub fn find_cycle(&self) -> Option<Vec<SimpleEndpoint>> {
+    let mut done = HashSet::new();
+    let mut path = Vec::new();
+    let starts = (0..self.modules.len()).map(SimpleEndpoint::Module).chain((0..self.handles.len()).map(SimpleEndpoint::Handle));
+    for start in starts {
+      if let Some(cycle) = self.cycle_from(start, &mut done, &mut path) {
+        return Some(cycle);
+      }
+    }
+    None
+  }
+
+  fn cycle_from(&self, endpoint: SimpleEndpoint, done: &mut HashSet<SimpleEndpoint>, path: &mut Vec<SimpleEndpoint>) -> Option<Vec<SimpleEndpoint>> {
+    if let Some(start) = path.iter().position(|on_path| *on_path == endpoint) {
+      return Some(path[start..].to_vec());
+    }
+    if done.contains(&endpoint) {
+      return None;
+    }
+    path.push(endpoint);
+    let successors: Vec<SimpleEndpoint> = self.arrows.iter().filter(|arrow| arrow.from.all().contains(&endpoint)).flat_map(|arrow| arrow.to.all().to_vec()).collect();
+    for next in successors {
+      if let Some(cycle) = self.cycle_from(next, done, path) {
+        return Some(cycle);
+      }
+    }
+    path.pop();
+    done.insert(endpoint);
+    None
+  }
+
   pub fn filter_module_to_module_connections(&mut self) -> Vec<Arrow> {
     self.filter_arrows(EndpointSpec::AnyModule, EndpointSpec::AnyModule)
   }
@@ -223,6 +421,28 @@ impl Graph {
   pub fn filter_module_to_handle_connections(&mut self) -> Vec<Arrow> {
     self.filter_arrows(EndpointSpec::AnyModule, EndpointSpec::AnyHandle)
   }
+
+  pub fn filter_handle_to_module_connections(&mut self) -> Vec<Arrow> {
+    self.filter_arrows(EndpointSpec::AnyHandle, EndpointSpec::AnyModule)
+  }
+
+  pub fn filter_arrows_into(&mut self, endpoint: SimpleEndpoint) -> Vec<Arrow> {
+    self.filter_arrows_by(|arrow| arrow.to == Endpoint::Simple(endpoint))
+  }
+
+  // Arrows into a tuple of endpoints, which split a tuple into its components.
+  pub fn filter_tuple_splits(&mut self) -> Vec<Arrow> {
+    self.filter_arrows_by(|arrow| if let Endpoint::Tuple(_) = arrow.to { true } else { false })
+  }
+
+  // Arrows between a named port and another port or module. Arrows between a port and a handle are already
+  // complete, so they're left in place.
+  pub fn filter_port_connections(&mut self) -> Vec<Arrow> {
+    let mut arrows = self.filter_arrows(EndpointSpec::AnyConnection, EndpointSpec::AnyConnection);
+    arrows.append(&mut self.filter_arrows(EndpointSpec::AnyConnection, EndpointSpec::AnyModule));
+    arrows.append(&mut self.filter_arrows(EndpointSpec::AnyModule, EndpointSpec::AnyConnection));
+    arrows
+  }
 }
 
 impl Arrow {
@@ -254,6 +474,34 @@ mod tests {
     assert_eq!(graph.arrows, vec!(Arrow::new(&m0, &c0), Arrow::new(&c0, &m1)));
   }
 
+  #[test]
+  fn handle_for_module_output_finds_existing_handle() {
+    let mut graph = Graph::new();
+    let m0 = graph.add_module(&ModuleSpecifier::Module("mod1".to_string()), &ParamAssignment::empty());
+    let c0 = graph.add_connection("output");
+    let h0 = graph.add_handle("h0", Type::Int);
+    graph.connect(&m0, &c0);
+    graph.connect(&c0, &h0);
+
+    let module = m0.simple_endpoint().unwrap();
+    assert_eq!(graph.handle_for_module_output(module, "output"), Some(h0));
+    assert_eq!(graph.handle_for_module_output(module, "error"), None);
+  }
+
+  #[test]
+  fn ports_are_shared_per_module() {
+    let mut graph = Graph::new();
+    let m0 = graph.add_module(&ModuleSpecifier::NamedModule("a".to_string(), "mod1".to_string()), &ParamAssignment::empty());
+    let m1 = graph.add_module(&ModuleSpecifier::Module("mod2".to_string()), &ParamAssignment::empty());
+    let p0 = graph.add_port(&m0, "output");
+    let p1 = graph.add_port(&m1, "output");
+    let m0_again = graph.add_module(&ModuleSpecifier::Name("a".to_string()), &ParamAssignment::empty());
+
+    assert_eq!(graph.add_port(&m0_again, "output"), p0);
+    assert_ne!(p0, p1);
+    assert_eq!(graph.port_module(p1.connection_idx().unwrap()), Some(1));
+  }
+
   #[test]
   fn module_spec_matches_any_module() {
     assert_eq!(SimpleEndpoint::Module(0).matches_spec(EndpointSpec::AnyModule), true);
@@ -8,12 +8,166 @@ pub enum GraphBuilderError {
   ModuleNotFound(String),
   HandleNotFound(String),
   MismatchedTypes,
+  // (from, to, the port-qualified connections that could be written instead)
+  AmbiguousConnection(String, String, Vec<String>),
+  NoMatchingConnection(String, String),
+  // (module, port)
+  PortNotFound(String, String),
+  WrongPortDirection(String, String),
+  // a |x| node whose input or output type can't be worked out from its neighbours
+  CannotInferType(String),
+  // (|x| node, type of its body, type of the inputs it feeds)
+  TransformTypeMismatch(String, ast::Type, ast::Type),
+  // the modules and handles around a feedback loop, in a graph without an iterate bound
+  Cycle(Vec<String>),
+  // (fragment, expected, provided)
+  FragmentParamCount(String, usize, usize),
+  // a fragment spliced between other nodes has to start and end with a chain
+  FragmentNotAChain(String),
+  RecursiveFragment(String),
 }
 
-pub fn make_graph(ast: Vec<&ast::GraphDirective>) -> graph::Graph {
+// Fragments nested deeper than this are assumed to include themselves.
+const MAX_FRAGMENT_DEPTH: usize = 32;
+
+pub fn make_graph(ast: Vec<&ast::GraphDirective>, fragments: &Vec<ast::GraphFragment>) -> Result<graph::Graph, GraphBuilderError> {
   let mut graph = graph::Graph::new();
-  ast.iter().for_each(|g| add_graph(&mut graph, g));
-  graph
+  let mut expansions = 0;
+  let directives = expand_fragments(ast.into_iter().cloned().collect(), fragments, &mut expansions, 0)?;
+  directives.iter().for_each(|g| add_graph(&mut graph, g));
+  Ok(graph)
+}
+
+// Splice the body of every fragment mentioned in a chain into that chain: A -> Fragment -> B joins A
+// to the start of the fragment's first chain, and the end of its last chain to B.
+fn expand_fragments(directives: Vec<ast::GraphDirective>, fragments: &Vec<ast::GraphFragment>, expansions: &mut usize, depth: usize) -> Result<Vec<ast::GraphDirective>, GraphBuilderError> {
+  let mut result = vec!();
+  for directive in directives {
+    let chain = match directive {
+      ast::GraphDirective::Chain(chain) => chain,
+      other => {
+        result.push(other);
+        continue;
+      }
+    };
+    let length = chain.len();
+    let mut current = vec!();
+    for (position, info) in chain.into_iter().enumerate() {
+      let (fragment, args) = match fragment_use(&info, fragments) {
+        Some(fragment_use) => fragment_use,
+        None => {
+          current.push(info);
+          continue;
+        }
+      };
+      if depth == MAX_FRAGMENT_DEPTH {
+        return Err(GraphBuilderError::RecursiveFragment(fragment.name.clone()));
+      }
+      let body = instantiate_fragment(fragment, args, *expansions)?;
+      *expansions += 1;
+      let mut body = expand_fragments(body, fragments, expansions, depth + 1)?;
+
+      if current.is_empty() && position == length - 1 {
+        // Not joined to anything, so the body can be anything.
+        result.append(&mut body);
+        continue;
+      }
+      let is_chain = |directive: Option<&ast::GraphDirective>| matches!(directive, Some(ast::GraphDirective::Chain(_)));
+      if !is_chain(body.first()) || !is_chain(body.last()) {
+        return Err(GraphBuilderError::FragmentNotAChain(fragment.name.clone()));
+      }
+      let tail = if body.len() > 1 { body.pop() } else { None };
+      if let ast::GraphDirective::Chain(mut head) = body.remove(0) {
+        current.append(&mut head);
+      }
+      if let Some(ast::GraphDirective::Chain(tail)) = tail {
+        result.push(ast::GraphDirective::Chain(current));
+        result.append(&mut body);
+        current = tail;
+      }
+    }
+    if !current.is_empty() {
+      result.push(ast::GraphDirective::Chain(current));
+    }
+  }
+  Ok(result)
+}
+
+fn fragment_use<'a>(info: &ast::GraphModuleInfo, fragments: &'a Vec<ast::GraphFragment>) -> Option<(&'a ast::GraphFragment, ast::ParamAssignment)> {
+  if let ast::GraphModuleInfo::Module(ast::ModuleSpecifier::Module(name), args) = info {
+    fragments.iter().find(|fragment| &fragment.name == name).map(|fragment| (fragment, args.clone()))
+  } else {
+    None
+  }
+}
+
+// A copy of the fragment's body for one use: parameters are replaced by the arguments given, and
+// $names get a prefix that's unique to this use so they can't collide with names outside it.
+fn instantiate_fragment(fragment: &ast::GraphFragment, args: ast::ParamAssignment, expansion: usize) -> Result<Vec<ast::GraphDirective>, GraphBuilderError> {
+  if args.params.len() != fragment.params.len() {
+    return Err(GraphBuilderError::FragmentParamCount(fragment.name.clone(), fragment.params.len(), args.params.len()));
+  }
+  let instance = FragmentInstance { prefix: format!("{}{}.", fragment.name, expansion), params: &fragment.params, args: &args.params };
+  Ok(fragment.graph.iter().map(|directive| instance.directive(directive)).collect())
+}
+
+struct FragmentInstance<'a> {
+  prefix: String,
+  params: &'a Vec<String>,
+  args: &'a Vec<ast::Expression>,
+}
+
+impl <'a> FragmentInstance<'a> {
+  fn directive(&self, directive: &ast::GraphDirective) -> ast::GraphDirective {
+    match directive {
+      ast::GraphDirective::Chain(chain) => ast::GraphDirective::Chain(self.chain(chain)),
+      ast::GraphDirective::Switch(source, cases) => ast::GraphDirective::Switch(
+        self.module_info(source),
+        cases.iter().map(|(case, chain)| (case.clone(), self.chain(chain))).collect()
+      ),
+      other => other.clone()
+    }
+  }
+
+  fn chain(&self, chain: &Vec<ast::GraphModuleInfo>) -> Vec<ast::GraphModuleInfo> {
+    chain.iter().map(|info| self.module_info(info)).collect()
+  }
+
+  fn module_info(&self, info: &ast::GraphModuleInfo) -> ast::GraphModuleInfo {
+    match info {
+      ast::GraphModuleInfo::Module(specifier, params) => ast::GraphModuleInfo::Module(
+        self.specifier(specifier),
+        ast::ParamAssignment { params: params.params.iter().map(|param| self.arg_for(param).unwrap_or_else(|| param.clone())).collect() }
+      ),
+      ast::GraphModuleInfo::Field(ast::ModuleSpecifier::This, name) => {
+        // A parameter used where a field would go is a constant source.
+        match self.params.iter().position(|param| param == name) {
+          Some(idx) => ast::GraphModuleInfo::Literal(self.args[idx].clone()),
+          None => info.clone()
+        }
+      }
+      ast::GraphModuleInfo::Field(specifier, port) => ast::GraphModuleInfo::Field(self.specifier(specifier), port.clone()),
+      ast::GraphModuleInfo::Tuple(members) => ast::GraphModuleInfo::Tuple(self.chain(members)),
+      other => other.clone()
+    }
+  }
+
+  fn specifier(&self, specifier: &ast::ModuleSpecifier) -> ast::ModuleSpecifier {
+    match specifier {
+      ast::ModuleSpecifier::NamedModule(local_name, module) => ast::ModuleSpecifier::NamedModule(self.prefix.clone() + local_name, module.clone()),
+      ast::ModuleSpecifier::Name(local_name) => ast::ModuleSpecifier::Name(self.prefix.clone() + local_name),
+      other => other.clone()
+    }
+  }
+
+  // Parameters stand in for whole module arguments, e.g. Char<sep>.
+  fn arg_for(&self, param: &ast::Expression) -> Option<ast::Expression> {
+    if let ast::ExpressionValueEnum::ReferenceToState(name) = &param.value.info {
+      self.params.iter().position(|p| p == name).map(|idx| self.args[idx].clone())
+    } else {
+      None
+    }
+  }
 }
 
 
@@ -24,17 +178,56 @@ pub fn add_graph(graph: &mut graph::Graph, ast: &ast::GraphDirective) {
         return;
       }
 
-      let mut prev_idx = add_graph_module_info(graph, &modules[0]);
-  
-      for i in 1..modules.len() {
-        let idx = add_graph_module_info(graph, &modules[i]);
-        graph.connect(&prev_idx, &idx);
-        prev_idx = idx;
+      let first = add_graph_module_info(graph, &modules[0]);
+      add_chain(graph, first, &modules[1..]);
+    }
+    ast::GraphDirective::Merge(h_name, policy) => {
+      // Make sure the field is in the graph so that resolve_handles will reject unknown names.
+      if graph.handle_named(h_name).is_none() {
+        graph.add_handle(h_name, ast::Type::Unresolved);
+      }
+      graph.merge_policies.insert(h_name.clone(), policy.clone());
+    }
+    ast::GraphDirective::Iterate(bound) => graph.iteration_bound = Some(*bound),
+    ast::GraphDirective::Switch(source, cases) => {
+      let source = add_graph_module_info(graph, source);
+      let named: Vec<String> = cases.iter().filter_map(|(case, _)| match case {
+        ast::SwitchCase::Output(port_name) => Some(port_name.clone()),
+        _ => None
+      }).collect();
+      let mut switch_idx = None;
+      for (case, chain) in cases {
+        let case_endpoint = match case {
+          // Routing on which output was written is just a port.
+          ast::SwitchCase::Output(port_name) => graph.add_port(&source, port_name),
+          // Alongside output cases, _ is every other output of the module.
+          ast::SwitchCase::Otherwise if !named.is_empty() => {
+            if let Some((first, rest)) = chain.split_first() {
+              let target = add_graph_module_info(graph, first);
+              graph.add_other_outputs(&source, named.clone(), &target);
+              add_chain(graph, target, rest);
+            }
+            continue;
+          }
+          _ => {
+            let switch_idx = *switch_idx.get_or_insert_with(|| graph.add_switch(&source));
+            graph.add_switch_case(switch_idx, case)
+          }
+        };
+        add_chain(graph, case_endpoint, chain);
       }
     }
   }
 }
 
+fn add_chain(graph: &mut graph::Graph, mut prev_idx: graph::Endpoint, modules: &[ast::GraphModuleInfo]) {
+  for module in modules {
+    let idx = add_graph_module_info(graph, module);
+    graph.connect(&prev_idx, &idx);
+    prev_idx = idx;
+  }
+}
+
 pub fn add_graph_module_info(graph: &mut graph::Graph, info: &ast::GraphModuleInfo) -> graph::Endpoint {
   match info {
     ast::GraphModuleInfo::Module(specifier, params) => graph.add_module(specifier, params),
@@ -51,17 +244,37 @@ pub fn add_graph_module_info(graph: &mut graph::Graph, info: &ast::GraphModuleIn
     }
     ast::GraphModuleInfo::Field(specifier, h_name) => {
       if specifier == &ast::ModuleSpecifier::This {
-        graph.add_handle(h_name, ast::Type::Unresolved)
+        // Every mention of a field refers to the same handle, so a field can feed several readers.
+        graph.handle_named(h_name).unwrap_or_else(|| graph.add_handle(h_name, ast::Type::Unresolved))
       } else {
-        todo!("can't add fields that aren't This.fields yet")
+        // A port of a submodule ($a.output, UnsignedInt.result); its direction is worked out in resolve_ports.
+        let module = graph.add_module(specifier, &ast::ParamAssignment::empty());
+        graph.add_port(&module, h_name)
       }
     }
-    _ => todo!("can't add {:?} to a graph yet", info)
+    ast::GraphModuleInfo::Literal(expression) => {
+      let value: ast::ExpressionValue = expression.clone().into();
+      let h_type = value.literal_type().unwrap_or(ast::Type::Unresolved);
+      graph.add_constant(&value, h_type)
+    }
+    ast::GraphModuleInfo::Transform(param, body) => graph.add_transform(param, body),
   }
 }
 
 pub fn resolve_graph(module: &ast::Module, sub_modules: &Vec<&ast::Module>, graph: &mut graph::Graph) -> Result<(), GraphBuilderError> {
   resolve_handles(module, graph)?;
+  resolve_other_outputs(sub_modules, graph)?;
+  let tuple_splits = graph.filter_tuple_splits();
+  let port_connections = graph.filter_port_connections();
+  resolve_ports(sub_modules, graph)?;
+  resolve_switches(sub_modules, graph)?;
+  resolve_transforms(sub_modules, graph)?;
+  for connection in port_connections {
+    expand_port_connection(sub_modules, graph, &connection)?;
+  }
+  for connection in tuple_splits {
+    expand_tuple_split(sub_modules, graph, &connection)?;
+  }
   let mut uid = 0;
   let connections = graph.filter_module_to_module_connections();
   let success: Result<Vec<_>, _> = connections.iter().map(|connection| expand_to_full_connection(sub_modules, graph, connection, &mut uid)).collect();
@@ -70,19 +283,213 @@ pub fn resolve_graph(module: &ast::Module, sub_modules: &Vec<&ast::Module>, grap
   for connection in m2h_connections {
     expand_to_full_connection(sub_modules, graph, &connection, &mut uid)?;
   }
-  dbg!(&graph);
-  Ok(())
+  let h2m_connections = graph.filter_handle_to_module_connections();
+  for connection in h2m_connections {
+    expand_to_full_connection(sub_modules, graph, &connection, &mut uid)?;
+  }
+  check_cycles(graph)
+}
+
+// Feedback loops only make sense if the graph says how long they may take to settle.
+fn check_cycles(graph: &graph::Graph) -> Result<(), GraphBuilderError> {
+  if graph.iteration_bound.is_some() {
+    return Ok(());
+  }
+  match graph.find_cycle() {
+    None => Ok(()),
+    Some(cycle) => Err(GraphBuilderError::Cycle(cycle.iter()
+      .filter(|endpoint| endpoint.connection_idx().is_none())
+      .map(|endpoint| graph.endpoint_name(*endpoint).to_string()).collect()))
+  }
 }
 
 fn resolve_handles(module: &ast::Module, graph: &mut graph::Graph) -> Result<(), GraphBuilderError> {
-  let unresolved_handles: Vec<_> = graph.handles.iter_mut().filter(|handle| handle.h_type == ast::Type::Unresolved).collect();
-  for mut handle in unresolved_handles {
+  // |x| nodes and switch cases aren't fields; resolve_transforms and resolve_switches deal with them.
+  let node_handles: Vec<usize> = (0..graph.handles.len()).filter(|idx| graph.is_node_handle(*idx)).collect();
+  let unresolved_handles: Vec<_> = graph.handles.iter_mut().enumerate()
+    .filter(|(idx, handle)| handle.h_type == ast::Type::Unresolved && !node_handles.contains(idx)).map(|(_, handle)| handle).collect();
+  for handle in unresolved_handles {
     let module_handle = module.handle_for_field(&handle.name).ok_or(GraphBuilderError::HandleNotFound(handle.name.clone()))?;
     handle.h_type = module_handle.h_type.clone();
   }
   Ok(())
 }
 
+// Each output that a switch's _ case stands for is a port feeding the first node of the case.
+fn resolve_other_outputs(modules: &Vec<&ast::Module>, graph: &mut graph::Graph) -> Result<(), GraphBuilderError> {
+  for idx in 0..graph.other_outputs.len() {
+    let module_idx = graph.other_outputs[idx].module;
+    let module = graph_module(modules, graph, module_idx)?;
+    let others: Vec<&ast::Handle> = module.outputs().into_iter().filter(|handle| !graph.other_outputs[idx].named.contains(&handle.name)).collect();
+    if others.is_empty() {
+      return Err(GraphBuilderError::NoMatchingConnection(module.name.clone(), "_".to_string()));
+    }
+    let module_endpoint = graph::Endpoint::Simple(graph::SimpleEndpoint::Module(module_idx));
+    let target = graph.other_outputs[idx].target.clone();
+    for handle in others {
+      let port = graph.add_port(&module_endpoint, &handle.name);
+      graph.connect(&port, &target);
+    }
+  }
+  Ok(())
+}
+
+fn resolve_ports(modules: &Vec<&ast::Module>, graph: &mut graph::Graph) -> Result<(), GraphBuilderError> {
+  for (module_idx, connection_idx) in graph.ports.clone() {
+    let module = graph_module(modules, graph, module_idx)?;
+    let port = port_handle(module, &graph.connections[connection_idx])?;
+    let module_endpoint = graph::Endpoint::Simple(graph::SimpleEndpoint::Module(module_idx));
+    let connection_endpoint = graph::Endpoint::Simple(graph::SimpleEndpoint::Connection(connection_idx));
+    if port.is_input() {
+      graph.connect(&connection_endpoint, &module_endpoint);
+    } else {
+      graph.connect(&module_endpoint, &connection_endpoint);
+    }
+  }
+  Ok(())
+}
+
+// Each |x| node is fed from a handle - a new one if its source is a module output. Its type is the type of its body
+// where that can be worked out, and has to match the inputs it feeds. Nodes are resolved in the order they were
+// written, so each node in a chain knows its source's type.
+fn resolve_transforms(modules: &Vec<&ast::Module>, graph: &mut graph::Graph) -> Result<(), GraphBuilderError> {
+  for transform_idx in 0..graph.transforms.len() {
+    let handle_idx = graph.transforms[transform_idx].handle;
+    let transform_handle = graph::Endpoint::Simple(graph::SimpleEndpoint::Handle(handle_idx));
+    let transform_name = graph.handles[handle_idx].name.clone();
+
+    let sources = graph.filter_arrows_into(graph::SimpleEndpoint::Handle(handle_idx));
+    if sources.len() != 1 {
+      return Err(GraphBuilderError::CannotInferType(transform_name));
+    }
+    let source = source_handle(modules, graph, &sources[0].from, &transform_name, &format!("{}.in", transform_name))?;
+    let source_type = graph.handles[source.handle_idx().unwrap()].h_type.clone();
+    let transform = &graph.transforms[transform_idx];
+    let body_type = transform_body_type(&transform.body.value, &transform.param, &source_type);
+
+    let targets: Vec<graph::Endpoint> = [graph::EndpointSpec::AnyModule, graph::EndpointSpec::AnyConnection, graph::EndpointSpec::AnyHandle].iter()
+      .flat_map(|spec| graph.endpoints_associated_with_endpoint(graph::SimpleEndpoint::Handle(handle_idx), *spec))
+      .filter(|(_, arrow)| arrow.from == transform_handle)
+      .map(|(endpoint, _)| endpoint.clone())
+      .collect();
+    let mut result_type = None;
+    for target in &targets {
+      // The next node in a chain checks this node's type against its own body when it's resolved.
+      if target.handle_idx().map_or(false, |idx| graph.transforms.iter().any(|transform| transform.handle == idx)) {
+        continue;
+      }
+      let (_, target_type) = node_neighbour(modules, graph, target, false, &transform_name)?;
+      if *result_type.get_or_insert(target_type.clone()) != target_type {
+        return Err(GraphBuilderError::MismatchedTypes);
+      }
+    }
+    graph.handles[handle_idx].h_type = match (body_type, result_type) {
+      (Some(body_type), Some(result_type)) if body_type != result_type =>
+        return Err(GraphBuilderError::TransformTypeMismatch(transform_name, body_type, result_type)),
+      (Some(h_type), _) | (None, Some(h_type)) => h_type,
+      (None, None) => return Err(GraphBuilderError::CannotInferType(transform_name))
+    };
+    graph.connect_transform(&source, &transform_handle, transform_idx);
+  }
+  Ok(())
+}
+
+// The type of a |x| node's body given the type of x, or None if it can't be worked out without compiling the body.
+fn transform_body_type(body: &ast::ExpressionValue, param: &str, param_type: &ast::Type) -> Option<ast::Type> {
+  match &body.info {
+    ast::ExpressionValueEnum::ReferenceToState(name) if name == param => Some(param_type.clone()),
+    ast::ExpressionValueEnum::Tuple(members) =>
+      members.iter().map(|member| transform_body_type(member, param, param_type)).collect::<Option<Vec<_>>>().map(ast::Type::Tuple),
+    ast::ExpressionValueEnum::TupleLookup(tuple, idx) => match transform_body_type(tuple, param, param_type)? {
+      ast::Type::Tuple(members) => members.get(*idx as usize).cloned(),
+      _ => None
+    },
+    ast::ExpressionValueEnum::BinaryOperator(lhs, op, _) => match op {
+      ast::Operator::Multiply | ast::Operator::Divide | ast::Operator::Add | ast::Operator::Subtract => transform_body_type(lhs, param, param_type),
+      _ => Some(ast::Type::Bool)
+    },
+    ast::ExpressionValueEnum::FunctionCall(name, _) => match name.as_str() {
+      "new" => Some(ast::Type::MemRegion),
+      "size" => Some(ast::Type::Int),
+      _ => None
+    },
+    _ => body.literal_type()
+  }
+}
+
+// Each switch's predicate cases are written from the handle holding the switched value, so they share its type.
+fn resolve_switches(modules: &Vec<&ast::Module>, graph: &mut graph::Graph) -> Result<(), GraphBuilderError> {
+  for switch_idx in 0..graph.switches.len() {
+    let source = graph.switches[switch_idx].source.clone();
+    let switch_name = format!("switch{}", switch_idx);
+    let source = source_handle(modules, graph, &source, &switch_name, &switch_name)?;
+    let source_type = graph.handles[source.handle_idx().unwrap()].h_type.clone();
+    for case_idx in 0..graph.switches[switch_idx].cases.len() {
+      let case_handle = graph.switches[switch_idx].cases[case_idx].0;
+      graph.handles[case_handle].h_type = source_type.clone();
+      graph.connect_switch_case(&source, &graph::Endpoint::Simple(graph::SimpleEndpoint::Handle(case_handle)), switch_idx, case_idx);
+    }
+  }
+  Ok(())
+}
+
+// The handle holding the value that feeds a |x| or switch node: the source itself if it's a handle, otherwise a new
+// handle written by the source port or the source module's only output.
+fn source_handle(modules: &Vec<&ast::Module>, graph: &mut graph::Graph, source: &graph::Endpoint, node_name: &str, handle_name: &str)
+    -> Result<graph::Endpoint, GraphBuilderError> {
+  let (source_name, source_type) = node_neighbour(modules, graph, source, true, node_name)?;
+  if source.handle_idx().is_some() {
+    return Ok(source.clone());
+  }
+  let handle = graph.add_handle(handle_name, source_type);
+  if source.module_idx().is_some() {
+    let source_connection = graph.add_connection(&source_name);
+    graph.connect(source, &source_connection);
+    graph.connect(&source_connection, &handle);
+  } else {
+    graph.connect(source, &handle);
+  }
+  Ok(handle)
+}
+
+// The name and type of whatever feeds a |x| or switch node (is_source) or is fed by it: a handle, a named port, or
+// the only output / input of a module.
+fn node_neighbour(modules: &Vec<&ast::Module>, graph: &graph::Graph, endpoint: &graph::Endpoint, is_source: bool, node_name: &str)
+    -> Result<(String, ast::Type), GraphBuilderError> {
+  if let Some(handle_idx) = endpoint.handle_idx() {
+    let handle = &graph.handles[handle_idx];
+    if handle.h_type == ast::Type::Unresolved {
+      return Err(GraphBuilderError::CannotInferType(node_name.to_string()));
+    }
+    return Ok((handle.name.clone(), handle.h_type.clone()));
+  }
+  let (module_idx, port) = port_arrow_end(graph, endpoint)?;
+  let module = graph_module(modules, graph, module_idx)?;
+  if let Some(port) = port {
+    let handle = port_handle(module, &port)?;
+    if handle.is_output() != is_source {
+      return Err(GraphBuilderError::WrongPortDirection(module.name.clone(), port));
+    }
+    return Ok((handle.name.clone(), handle.h_type.clone()));
+  }
+  let candidates = if is_source { module.outputs() } else { module.inputs() };
+  match candidates.len() {
+    1 => Ok((candidates[0].name.clone(), candidates[0].h_type.clone())),
+    0 => Err(GraphBuilderError::NoMatchingConnection(module.name.clone(), node_name.to_string())),
+    _ => Err(GraphBuilderError::AmbiguousConnection(module.name.clone(), node_name.to_string(),
+      candidates.iter().map(|handle| format!("{}.{}", module.name, handle.name)).collect()))
+  }
+}
+
+fn graph_module<'a>(modules: &Vec<&'a ast::Module>, graph: &graph::Graph, module_idx: usize) -> Result<&'a ast::Module, GraphBuilderError> {
+  let name = &graph.modules[module_idx].name;
+  find_module_by_name(modules, name).ok_or_else(|| GraphBuilderError::ModuleNotFound(name.clone()))
+}
+
+fn port_handle<'a>(module: &'a ast::Module, port_name: &str) -> Result<&'a ast::Handle, GraphBuilderError> {
+  module.handle_for_field(port_name).ok_or_else(|| GraphBuilderError::PortNotFound(module.name.clone(), port_name.to_string()))
+}
+
 pub fn find_module_by_name<'a>(modules: &Vec<&'a ast::Module>, name: &str) -> Option<&'a ast::Module> {
   modules.iter().find(|&&module| module.name == name).map(|module| *module)
 }
@@ -112,10 +519,14 @@ fn matching_connections<'a, 'b>(from_module: &'a ast::Module, to_module: &'b ast
   }).flatten().collect()
 }
 
-fn only_matching_connection<'a, 'b>(from_module: &'a ast::Module, to_module: &'b ast::Module) -> (&'a str, &'b str, ast::Type) {
+fn only_matching_connection<'a, 'b>(from_module: &'a ast::Module, to_module: &'b ast::Module) -> Result<(&'a str, &'b str, ast::Type), GraphBuilderError> {
   let matches = matching_connections(from_module, to_module);
-  assert!(matches.len() == 1);
-  matches[0].clone()
+  match matches.len() {
+    1 => Ok(matches[0].clone()),
+    0 => Err(GraphBuilderError::NoMatchingConnection(from_module.name.clone(), to_module.name.clone())),
+    _ => Err(GraphBuilderError::AmbiguousConnection(from_module.name.clone(), to_module.name.clone(), 
+      matches.iter().map(|(from_name, to_name, _)| format!("{}.{} -> {}.{}", from_module.name, from_name, to_module.name, to_name)).collect()))
+  }
 }
 
 fn connections_matching_type<'a>(from_module: &'a ast::Module, to_type: &ast::Type) -> Vec<(&'a str, ast::Type)> {
@@ -130,10 +541,38 @@ fn connections_matching_type<'a>(from_module: &'a ast::Module, to_type: &ast::Ty
   }).collect()
 }
 
-fn only_connection_matching_type<'a>(from_module: &'a ast::Module, to_type: &ast::Type) -> (&'a str, ast::Type) {
+// to_name describes what's being connected to, for error reporting.
+fn only_connection_matching_type<'a>(from_module: &'a ast::Module, to_type: &ast::Type, to_name: &str) -> Result<(&'a str, ast::Type), GraphBuilderError> {
   let matches = connections_matching_type(from_module, to_type);
-  assert!(matches.len() == 1);
-  matches[0].clone()
+  match matches.len() {
+    1 => Ok(matches[0].clone()),
+    0 => Err(GraphBuilderError::NoMatchingConnection(from_module.name.clone(), to_name.to_string())),
+    _ => Err(GraphBuilderError::AmbiguousConnection(from_module.name.clone(), to_name.to_string(),
+      matches.iter().map(|(from_name, _)| format!("{}.{} -> {}", from_module.name, from_name, to_name)).collect()))
+  }
+}
+
+fn only_tuple_output<'a>(from_module: &'a ast::Module, size: usize) -> Result<(&'a str, ast::Type), GraphBuilderError> {
+  let matches: Vec<&ast::Handle> = from_module.outputs().into_iter().filter(|from_con| {
+    if let ast::Type::Tuple(members) = &from_con.h_type { members.len() == size } else { false }
+  }).collect();
+  let to_name = format!("a tuple of {}", size);
+  match matches.len() {
+    1 => Ok((matches[0].name.as_str(), matches[0].h_type.clone())),
+    0 => Err(GraphBuilderError::NoMatchingConnection(from_module.name.clone(), to_name)),
+    _ => Err(GraphBuilderError::AmbiguousConnection(from_module.name.clone(), to_name,
+      matches.iter().map(|from_con| format!("{}.{} -> (..)", from_module.name, from_con.name)).collect()))
+  }
+}
+
+fn only_input_matching_type<'a>(to_module: &'a ast::Module, from_type: &ast::Type, from_name: &str) -> Result<&'a str, GraphBuilderError> {
+  let matches: Vec<&str> = to_module.inputs().iter().filter(|to_con| to_con.h_type == *from_type).map(|to_con| to_con.name.as_str()).collect();
+  match matches.len() {
+    1 => Ok(matches[0]),
+    0 => Err(GraphBuilderError::NoMatchingConnection(from_name.to_string(), to_module.name.clone())),
+    _ => Err(GraphBuilderError::AmbiguousConnection(from_name.to_string(), to_module.name.clone(),
+      matches.iter().map(|to_name| format!("{} -> {}.{}", from_name, to_module.name, to_name)).collect()))
+  }
 }
 
 fn expand_to_full_connection(modules: &Vec<&ast::Module>, graph: &mut graph::Graph, connection: &graph::Arrow, uid: &mut usize) -> Result<(), GraphBuilderError> {
@@ -149,7 +588,7 @@ fn expand_to_full_connection(modules: &Vec<&ast::Module>, graph: &mut graph::Gra
         let from_module_idx = connections[idx].module_idx().ok_or(GraphBuilderError::NotModuleEndpoint(conn_from.clone()))?;
         let from_module = find_module_by_name(modules, &graph.modules[from_module_idx].name).ok_or(
           GraphBuilderError::ModuleNotFound(graph.modules[from_module_idx].name.clone()))?;
-        let (from_name, _compatible_type) = only_connection_matching_type(from_module, &sub_types[idx]);
+        let (from_name, _compatible_type) = only_connection_matching_type(from_module, &sub_types[idx], &format!("{}.{}", graph.handles[to_handle_idx].name, idx))?;
         let from_connection = graph.add_connection(from_name);
         let conn_from = graph::Endpoint::Simple(connections[idx]);
         graph.connect(&conn_from, &from_connection);
@@ -162,19 +601,394 @@ fn expand_to_full_connection(modules: &Vec<&ast::Module>, graph: &mut graph::Gra
     }
   }
 
+  if let Some(from_handle_idx) = conn_from.handle_idx() {
+    // handle -> module: connect the handle to the module's input of the handle's type.
+    let to_module_idx = conn_to.module_idx().ok_or(GraphBuilderError::NotModuleEndpoint(conn_to.clone()))?;
+    let to_module = graph_module(modules, graph, to_module_idx)?;
+    let from_type = graph.handles[from_handle_idx].h_type.clone();
+    let to_name = only_input_matching_type(to_module, &from_type, &graph.handles[from_handle_idx].name)?;
+    let to_connection = graph.add_connection(to_name);
+    graph.connect(conn_from, &to_connection);
+    graph.connect(&to_connection, conn_to);
+    return Ok(());
+  }
+
+  if let Some(to_handle_idx) = conn_to.handle_idx() {
+    // module -> handle: connect the module's output of the handle's type. Several modules can do this for
+    // the same handle; graph_to_module merges their writes.
+    let from_module_idx = conn_from.module_idx().ok_or(GraphBuilderError::NotModuleEndpoint(conn_from.clone()))?;
+    let from_module = find_module_by_name(modules, &graph.modules[from_module_idx].name).ok_or(
+      GraphBuilderError::ModuleNotFound(graph.modules[from_module_idx].name.clone()))?;
+    let to_type = graph.handles[to_handle_idx].h_type.clone();
+    let (from_name, _compatible_type) = only_connection_matching_type(from_module, &to_type, &graph.handles[to_handle_idx].name)?;
+    let from_connection = graph.add_connection(from_name);
+    graph.connect(conn_from, &from_connection);
+    graph.connect(&from_connection, conn_to);
+    return Ok(());
+  }
+
   let from_module_idx = conn_from.module_idx().ok_or(GraphBuilderError::NotModuleEndpoint(conn_from.clone()))?;
   let to_module_idx = conn_to.module_idx().ok_or(GraphBuilderError::NotModuleEndpoint(conn_to.clone()))?;
   let from_module = find_module_by_name(modules, &graph.modules[from_module_idx].name).ok_or(
     GraphBuilderError::ModuleNotFound(graph.modules[from_module_idx].name.clone()))?;
   let to_module = find_module_by_name(modules, &graph.modules[to_module_idx].name).ok_or(
     GraphBuilderError::ModuleNotFound(graph.modules[to_module_idx].name.clone()))?;
-  let (from_name, to_name, compatible_type) = only_matching_connection(from_module, to_module);
-  let from_connection = graph.add_connection(from_name);
-  graph.connect(conn_from, &from_connection);
-  let handle = graph.add_handle(&(from_module.name.to_string() + "-" + &from_name + "-" + &to_name + "-" + &to_module.name), compatible_type);
-  graph.connect(&from_connection, &handle);
+  let (from_name, to_name, compatible_type) = only_matching_connection(from_module, to_module)?;
+  // If this output already feeds another module then the new reader shares the existing handle.
+  let existing_handle = graph.handle_for_module_output(graph::SimpleEndpoint::Module(from_module_idx), from_name);
+  let handle = match existing_handle {
+    Some(handle) => handle,
+    None => {
+      let from_connection = graph.add_connection(from_name);
+      graph.connect(conn_from, &from_connection);
+      let handle = graph.add_handle(&(from_module.name.to_string() + "-" + &from_name + "-" + &to_name + "-" + &to_module.name), compatible_type);
+      graph.connect(&from_connection, &handle);
+      handle
+    }
+  };
   let to_connection = graph.add_connection(to_name);
   graph.connect(&handle, &to_connection);
   graph.connect(&to_connection, conn_to);
   Ok(())
 }
+
+// The module at one end of a port arrow, and the port's name if one was given.
+fn port_arrow_end(graph: &graph::Graph, endpoint: &graph::Endpoint) -> Result<(usize, Option<String>), GraphBuilderError> {
+  if let Some(connection_idx) = endpoint.connection_idx() {
+    let module_idx = graph.port_module(connection_idx).ok_or(GraphBuilderError::NotModuleEndpoint(endpoint.clone()))?;
+    Ok((module_idx, Some(graph.connections[connection_idx].clone())))
+  } else {
+    let module_idx = endpoint.module_idx().ok_or(GraphBuilderError::NotModuleEndpoint(endpoint.clone()))?;
+    Ok((module_idx, None))
+  }
+}
+
+// Like expand_to_full_connection, but at least one end of the arrow is a named port. A named port is already
+// connected to its module, so only an unnamed end needs a connection chosen by type.
+fn expand_port_connection(modules: &Vec<&ast::Module>, graph: &mut graph::Graph, connection: &graph::Arrow) -> Result<(), GraphBuilderError> {
+  let (from_module_idx, from_port) = port_arrow_end(graph, &connection.from)?;
+  let (to_module_idx, to_port) = port_arrow_end(graph, &connection.to)?;
+  let from_module = graph_module(modules, graph, from_module_idx)?;
+  let to_module = graph_module(modules, graph, to_module_idx)?;
+
+  let from_handle = match &from_port {
+    Some(port) => {
+      let handle = port_handle(from_module, port)?;
+      if !handle.is_output() {
+        return Err(GraphBuilderError::WrongPortDirection(from_module.name.clone(), port.clone()));
+      }
+      Some(handle)
+    }
+    None => None
+  };
+  let to_handle = match &to_port {
+    Some(port) => {
+      let handle = port_handle(to_module, port)?;
+      if !handle.is_input() {
+        return Err(GraphBuilderError::WrongPortDirection(to_module.name.clone(), port.clone()));
+      }
+      Some(handle)
+    }
+    None => None
+  };
+
+  let (from_name, to_name, compatible_type) = match (from_handle, to_handle) {
+    (Some(from_handle), Some(to_handle)) => {
+      if !types_match(from_handle, to_handle) {
+        return Err(GraphBuilderError::MismatchedTypes);
+      }
+      (from_handle.name.as_str(), to_handle.name.as_str(), from_handle.h_type.clone())
+    }
+    (Some(from_handle), None) => {
+      let from_description = format!("{}.{}", from_module.name, from_handle.name);
+      let to_name = only_input_matching_type(to_module, &from_handle.h_type, &from_description)?;
+      (from_handle.name.as_str(), to_name, from_handle.h_type.clone())
+    }
+    (None, Some(to_handle)) => {
+      let to_description = format!("{}.{}", to_module.name, to_handle.name);
+      let (from_name, compatible_type) = only_connection_matching_type(from_module, &to_handle.h_type, &to_description)?;
+      (from_name, to_handle.name.as_str(), compatible_type)
+    }
+    (None, None) => return Err(GraphBuilderError::NotModuleEndpoint(connection.from.clone()))
+  };
+
+  // As with implicit connections, an output that already feeds a handle shares it with the new reader.
+  let existing_handle = match connection.from.simple_endpoint() {
+    Some(port @ graph::SimpleEndpoint::Connection(_)) => graph.handle_for_connection(port),
+    _ => graph.handle_for_module_output(graph::SimpleEndpoint::Module(from_module_idx), from_name)
+  };
+  let handle = match existing_handle {
+    Some(handle) => handle,
+    None => {
+      let from_connection = if from_port.is_some() {
+        connection.from.clone()
+      } else {
+        let from_connection = graph.add_connection(from_name);
+        graph.connect(&connection.from, &from_connection);
+        from_connection
+      };
+      let handle = graph.add_handle(&(from_module.name.to_string() + "-" + &from_name + "-" + &to_name + "-" + &to_module.name), compatible_type);
+      graph.connect(&from_connection, &handle);
+      handle
+    }
+  };
+  let to_connection = if to_port.is_some() {
+    connection.to.clone()
+  } else {
+    let to_connection = graph.add_connection(to_name);
+    graph.connect(&to_connection, &connection.to);
+    to_connection
+  };
+  graph.connect(&handle, &to_connection);
+  Ok(())
+}
+
+// X -> (A, B): X is a tuple-typed handle, output or port, and each reader gets the matching component of it.
+fn expand_tuple_split(modules: &Vec<&ast::Module>, graph: &mut graph::Graph, connection: &graph::Arrow) -> Result<(), GraphBuilderError> {
+  let readers = match &connection.to {
+    graph::Endpoint::Tuple(readers) => readers.clone(),
+    _ => return Err(GraphBuilderError::NotModuleEndpoint(connection.to.clone()))
+  };
+
+  let tuple_handle = if connection.from.handle_idx().is_some() {
+    connection.from.clone()
+  } else {
+    let (from_module_idx, from_port) = port_arrow_end(graph, &connection.from)?;
+    let from_module = graph_module(modules, graph, from_module_idx)?;
+    let (from_name, from_type) = match &from_port {
+      Some(port) => {
+        let from_handle = port_handle(from_module, port)?;
+        if !from_handle.is_output() {
+          return Err(GraphBuilderError::WrongPortDirection(from_module.name.clone(), port.clone()));
+        }
+        (from_handle.name.as_str(), from_handle.h_type.clone())
+      }
+      None => only_tuple_output(from_module, readers.len())?
+    };
+    let existing_handle = match connection.from.simple_endpoint() {
+      Some(port @ graph::SimpleEndpoint::Connection(_)) => graph.handle_for_connection(port),
+      _ => graph.handle_for_module_output(graph::SimpleEndpoint::Module(from_module_idx), from_name)
+    };
+    match existing_handle {
+      Some(handle) => handle,
+      None => {
+        let from_connection = if from_port.is_some() {
+          connection.from.clone()
+        } else {
+          let from_connection = graph.add_connection(from_name);
+          graph.connect(&connection.from, &from_connection);
+          from_connection
+        };
+        let handle = graph.add_handle(&format!("{}-{}-split", from_module.name, from_name), from_type);
+        graph.connect(&from_connection, &handle);
+        handle
+      }
+    }
+  };
+
+  let tuple_handle_idx = tuple_handle.handle_idx().unwrap();
+  let handle_name = graph.handles[tuple_handle_idx].name.clone();
+  let member_types = match &graph.handles[tuple_handle_idx].h_type {
+    ast::Type::Tuple(members) if members.len() == readers.len() => members.clone(),
+    _ => return Err(GraphBuilderError::MismatchedTypes)
+  };
+
+  for (idx, reader) in readers.iter().enumerate() {
+    let reader = graph::Endpoint::Simple(*reader);
+    let (to_module_idx, to_port) = port_arrow_end(graph, &reader)?;
+    let to_module = graph_module(modules, graph, to_module_idx)?;
+    let to_connection = match to_port {
+      Some(port) => {
+        let to_handle = port_handle(to_module, &port)?;
+        if !to_handle.is_input() {
+          return Err(GraphBuilderError::WrongPortDirection(to_module.name.clone(), port));
+        }
+        if to_handle.h_type != member_types[idx] {
+          return Err(GraphBuilderError::MismatchedTypes);
+        }
+        reader
+      }
+      None => {
+        let to_name = only_input_matching_type(to_module, &member_types[idx], &format!("{}.{}", handle_name, idx))?;
+        let to_connection = graph.add_connection(to_name);
+        graph.connect(&to_connection, &reader);
+        to_connection
+      }
+    };
+    graph.connect_tuple_destructor(&tuple_handle, &to_connection, idx);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::parser;
+
+  static TWO_OUTPUTS_STRING: &str = "
+module Split {
+  input: reads Int;
+  low: writes Int;
+  high: writes Int;
+}
+
+module Sink {
+  input: reads Int;
+}
+";
+
+  fn resolve(graph_string: &str) -> Result<graph::Graph, GraphBuilderError> {
+    let source = TWO_OUTPUTS_STRING.to_string() + graph_string;
+    let (_, ast) = parser::parse(&source).unwrap();
+    let fragments = ast::fragments(&ast).iter().map(|f| (*f).clone()).collect();
+    let mut graph = make_graph(ast::graphs(&ast), &fragments)?;
+    let main = ast::Module::create("Main", Vec::new(), Vec::new(), Vec::new(), ast::Examples { examples: Vec::new() }, Vec::new(), Vec::new());
+    resolve_graph(&main, &ast::modules(&ast), &mut graph)?;
+    Ok(graph)
+  }
+
+  #[test]
+  fn ambiguous_connections_list_ports() {
+    if let Err(GraphBuilderError::AmbiguousConnection(from, to, candidates)) = resolve("Split -> Sink;") {
+      assert_eq!(from, "Split");
+      assert_eq!(to, "Sink");
+      assert_eq!(candidates, vec!("Split.low -> Sink.input", "Split.high -> Sink.input"));
+    } else {
+      panic!("expected an ambiguous connection");
+    }
+  }
+
+  #[test]
+  fn ports_disambiguate_connections() {
+    let graph = resolve("Split.high -> Sink;").unwrap();
+    assert_eq!(graph.handles.len(), 1);
+    assert_eq!(graph.handles[0].name, "Split-high-input-Sink");
+  }
+
+  #[test]
+  fn ports_must_exist_and_face_the_right_way() {
+    if let Err(GraphBuilderError::PortNotFound(module, port)) = resolve("Split.middle -> Sink;") {
+      assert_eq!((module.as_str(), port.as_str()), ("Split", "middle"));
+    } else {
+      panic!("expected a missing port");
+    }
+    if let Err(GraphBuilderError::WrongPortDirection(module, port)) = resolve("Split.input -> Sink;") {
+      assert_eq!((module.as_str(), port.as_str()), ("Split", "input"));
+    } else {
+      panic!("expected a port facing the wrong way");
+    }
+  }
+
+  #[test]
+  fn tuple_splits_need_a_tuple_output() {
+    if let Err(GraphBuilderError::NoMatchingConnection(from, to)) = resolve("Split -> (Sink, Sink);") {
+      assert_eq!((from.as_str(), to.as_str()), ("Split", "a tuple of 2"));
+    } else {
+      panic!("expected no tuple output");
+    }
+  }
+
+  #[test]
+  fn transforms_take_types_from_neighbours() {
+    let graph = resolve("Split.low -> |x| x * 2 -> Sink;").unwrap();
+    assert_eq!(graph.handles[0].name, "lambda0");
+    assert_eq!(graph.handles[0].h_type, ast::Type::Int);
+    assert_eq!(graph.handles[1].name, "lambda0.in");
+    if let Err(GraphBuilderError::AmbiguousConnection(from, to, _)) = resolve("Split -> |x| x * 2 -> Sink;") {
+      assert_eq!((from.as_str(), to.as_str()), ("Split", "lambda0"));
+    } else {
+      panic!("expected an ambiguous source");
+    }
+  }
+
+  #[test]
+  fn transform_bodies_are_checked_and_chained() {
+    let graph = resolve("Split.low -> |x| (x, x) -> |p| p.0 + p.1 -> Sink;").unwrap();
+    let type_of = |name: &str| graph.handles.iter().find(|handle| handle.name == name).unwrap().h_type.clone();
+    assert_eq!(type_of("lambda0"), ast::Type::Tuple(vec!(ast::Type::Int, ast::Type::Int)));
+    assert_eq!(type_of("lambda1"), ast::Type::Int);
+    if let Err(GraphBuilderError::TransformTypeMismatch(name, body_type, input_type)) = resolve("Split.low -> |x| (x, 1) -> Sink;") {
+      assert_eq!(name, "lambda0");
+      assert_eq!(body_type, ast::Type::Tuple(vec!(ast::Type::Int, ast::Type::Int)));
+      assert_eq!(input_type, ast::Type::Int);
+    } else {
+      panic!("expected a transform body of the wrong type");
+    }
+  }
+
+  #[test]
+  fn switches_route_outputs_and_predicates() {
+    let graph = resolve("switch Split { low -> Sink; high -> $s:Sink; }").unwrap();
+    let names: Vec<&str> = graph.handles.iter().map(|handle| handle.name.as_str()).collect();
+    assert_eq!(names, vec!("Split-low-input-Sink", "Split-high-input-Sink"));
+
+    let graph = resolve("switch Split.low { |x| x < 10 -> Sink; _ -> $s:Sink; }").unwrap();
+    let names: Vec<&str> = graph.handles.iter().map(|handle| handle.name.as_str()).collect();
+    assert_eq!(names, vec!("switch0.case0", "switch0.case1", "switch0"));
+    assert!(graph.handles.iter().all(|handle| handle.h_type == ast::Type::Int));
+  }
+
+  #[test]
+  fn otherwise_takes_the_outputs_no_case_names() {
+    let graph = resolve("switch Split { low -> Sink; _ -> $s:Sink; }").unwrap();
+    let names: Vec<&str> = graph.handles.iter().map(|handle| handle.name.as_str()).collect();
+    assert_eq!(names, vec!("Split-low-input-Sink", "Split-high-input-Sink"));
+    assert!(graph.switches.is_empty());
+
+    let graph = resolve("$s:Sink; switch Split { high -> Sink; _ -> $s; }").unwrap();
+    let names: Vec<&str> = graph.handles.iter().map(|handle| handle.name.as_str()).collect();
+    assert_eq!(names, vec!("Split-high-input-Sink", "Split-low-input-Sink"));
+
+    if let Err(GraphBuilderError::NoMatchingConnection(from, to)) = resolve("switch Split { low -> Sink; high -> $a:Sink; _ -> $b:Sink; }") {
+      assert_eq!((from.as_str(), to.as_str()), ("Split", "_"));
+    } else {
+      panic!("expected no outputs left for _");
+    }
+  }
+
+  #[test]
+  fn cycles_need_an_iteration_bound() {
+    let feedback = "$a:Split.low -> $b:Split.input; $b.low -> $a.input;";
+    if let Err(GraphBuilderError::Cycle(names)) = resolve(feedback) {
+      // both modules and the two handles between them, but not their connections
+      assert_eq!(names.len(), 4);
+      assert_eq!(names.iter().filter(|name| *name == "Split").count(), 2);
+    } else {
+      panic!("expected a cycle");
+    }
+    assert!(resolve(&(feedback.to_string() + " iterate 10;")).is_ok());
+  }
+
+  #[test]
+  fn fragments_are_spliced_into_chains() {
+    let graph_string = "graph Low<n> { $s:Split<n>; $s.low; } Low<3> -> Sink; Low<4> -> $s:Sink;";
+    let graph = resolve(graph_string).unwrap();
+    assert_eq!(graph.modules.len(), 4);
+    // the argument keeps its position in the source, not the fragment's
+    let source = TWO_OUTPUTS_STRING.to_string() + graph_string;
+    let offset = source.find("Low<3>").unwrap() + "Low<".len();
+    let line = source[..offset].matches('\n').count() as u32 + 1;
+    assert_eq!(graph.modules[0].params.params, vec!(ast::Expression::int_literal(ast::SafeSpan { offset, line }, 3)));
+    // each use gets its own $s, and neither is the $s outside the fragment
+    let mut names: Vec<&String> = graph.names.keys().collect();
+    names.sort();
+    assert_eq!(names, vec!("Low0.s", "Low1.s", "s"));
+    assert_eq!(graph.handles.len(), 2);
+  }
+
+  #[test]
+  fn fragment_uses_are_checked() {
+    assert!(matches!(resolve("graph Low<n> { $s:Split<n>; $s.low; } Low -> Sink;"), Err(GraphBuilderError::FragmentParamCount(_, 1, 0))));
+    assert!(matches!(resolve("graph Loop { Loop; } Loop;"), Err(GraphBuilderError::RecursiveFragment(_))));
+    assert!(matches!(resolve("graph Merged { merge input: first; } Split.low -> Merged;"), Err(GraphBuilderError::FragmentNotAChain(_))));
+  }
+
+  #[test]
+  fn literals_become_constant_handles() {
+    let graph = resolve("7 -> Sink;").unwrap();
+    assert_eq!(graph.handles.len(), 1);
+    assert_eq!(graph.handles[0].name, "const0");
+    assert_eq!(graph.handles[0].h_type, ast::Type::Int);
+    assert_eq!(graph.constants.len(), 1);
+  }
+}
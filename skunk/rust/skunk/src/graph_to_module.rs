@@ -8,6 +8,8 @@ pub enum GraphToModuleError {
   NameNotInHandleList(ast::Module, String),
   MultipleModulesForConnection(String),
   InvalidHandleType(String),
+  // a merge policy on a handle that doesn't have several submodules writing to it
+  NothingToMerge(String),
 }
 
 #[derive(Debug)]
@@ -35,9 +37,13 @@ pub struct HandleMappingInfo {
 
 #[derive(Debug)]
 pub enum WriteBehaviour {
-  WritesToSubmodule(usize, String),
+  // (submodule index, submodule handle) for every submodule that reads this handle, and the writes to handles
+  // computed from this one (tuple components, |x| nodes, switch cases)
+  WritesToSubmodules(Vec<(usize, String)>, Vec<ast::Expression>),
   // (handle to write to, handles to read from, uid for this constructor, index into read handles)
   WritesToTupleHandle(String, Vec<String>, usize, usize),
+  // (handle to merge into, policy for combining with other writers of that handle)
+  MergesInto(String, ast::MergePolicy),
   None
 }
 
@@ -50,6 +56,8 @@ pub struct HandleInfo {
 }
 
 pub fn graph_to_module(module: &mut ast::Module, graph: graph::Graph, modules: Vec<&ast::Module>) -> Result<(), GraphToModuleError> {
+  let mut initial_values = graph.constants.iter().map(|(idx, value)| (graph.handles[*idx].name.clone(), value.clone())).collect();
+  module.iteration_bound = graph.iteration_bound;
   let module_context = ModuleContext::new(graph, modules)?;
   let mut handle_infos = module_context.generate_handles(&mut module.tuples)?;
   let mut submodules: Vec<ast::ModuleInfo> = 
@@ -70,6 +78,7 @@ pub fn graph_to_module(module: &mut ast::Module, graph: graph::Graph, modules: V
         error = Some(GraphToModuleError::InvalidHandleType(info.handle.name.clone()))
       }
     } else {
+      module.synthetic_handles.push(info.handle.name.clone());
       module.handles.push(info.handle);
     }
   });
@@ -78,6 +87,7 @@ pub fn graph_to_module(module: &mut ast::Module, graph: graph::Graph, modules: V
   }
   module.listeners.append(&mut listeners);
   module.submodules.append(&mut submodules);
+  module.initial_values.append(&mut initial_values);
   Ok(())
 }
 
@@ -106,7 +116,7 @@ impl <'a> ModuleContext<'a> {
       let handle = info.module.handle_for_field(&name).ok_or_else(|| GraphToModuleError::NameNotInHandleList(info.module.clone(), name.clone()))?;
       let mapping_info = HandleMappingInfo { submodule_idx: info.index, submodule_handle: name.clone() };
       let write_behaviour = if handle.is_input() {
-        WriteBehaviour::WritesToSubmodule(info.index, name.clone())
+        WriteBehaviour::WritesToSubmodules(vec!((info.index, name.clone())), Vec::new())
       } else {
         WriteBehaviour::None
       };
@@ -118,11 +128,11 @@ impl <'a> ModuleContext<'a> {
     let mut index = 0;
     for handle in &self.graph.handles {
       let candidate_connections = self.graph.endpoints_associated_with_endpoint(graph::SimpleEndpoint::Handle(index), graph::EndpointSpec::AnyConnection);
-      let mut candidate_found = false;
 
       let mut mapped_for_submodules = Vec::new();
-      let mut writes_to_submodule = 0;
-      let mut submodule_handle = &"".to_string();
+      let mut readers = Vec::new();
+      let mut writers = Vec::new();
+      let mut components: Vec<(usize, HandleInfo)> = Vec::new();
       for connection in candidate_connections {
         // We have a connection that connects to the constructed handle. From that we need to fetch the connected module..
         let connection_idx = connection.0.connection_idx().unwrap();
@@ -162,31 +172,129 @@ impl <'a> ModuleContext<'a> {
           continue;
         }
 
-        // all modules connected in this way participate in the map, collect them here.
-        mapped_for_submodules.push(HandleMappingInfo { submodule_idx: candidate_writes_to_submodule, submodule_handle: connection_name.clone() });
+        if let graph::ArrowInfo::TupleDestructor(n) = connection.1.info {
+          // if the arrow splits this handle, then component n gets its own handle, which this handle's listener
+          // writes to and which in turn copies into the reader.
+          let component_name = format!("{}.split{}", handle.name, n);
+          let reader = (candidate_writes_to_submodule, connection_name.clone());
+          let mapping_info = HandleMappingInfo { submodule_idx: candidate_writes_to_submodule, submodule_handle: connection_name.clone() };
+          match components.iter_mut().find(|(idx, _)| *idx == n) {
+            Some((_, component)) => {
+              component.mapped_for_submodules.push(mapping_info);
+              if let WriteBehaviour::WritesToSubmodules(targets, _) = &mut component.write_behaviour {
+                targets.push(reader);
+              }
+            }
+            None => {
+              let new_handle = ast::Handle {
+                position: ast::SafeSpan { offset: 0, line: 1 },
+                name: component_name,
+                h_type: submodule_connection.h_type.clone(),
+                usages: vec!(ast::Usage::Read, ast::Usage::Write)
+              };
+              components.push((n, HandleInfo {
+                handle: new_handle,
+                mapped_for_submodules: vec!(mapping_info),
+                write_behaviour: WriteBehaviour::WritesToSubmodules(vec!(reader), Vec::new())
+              }));
+            }
+          }
+          continue;
+        }
+
+        let mapping_info = HandleMappingInfo { submodule_idx: candidate_writes_to_submodule, submodule_handle: connection_name.clone() };
+
+        // if this connection inputs into the module then it's going to be triggered by a listener associated with
+        // this handle. A handle may have any number of readers; the listener broadcasts to each of them in turn.
+        if submodule_connection.is_input() {
+          mapped_for_submodules.push(mapping_info);
+          readers.push((candidate_writes_to_submodule, connection_name.clone()));
+        } else {
+          writers.push(mapping_info);
+        }
+      }
 
-        // make sure that this connection inputs into the module. If so, then the connection we found is going to be
-        // triggered by a listener associated with this handle.
-        if !submodule_connection.is_input() {
+      if writers.len() > 1 {
+        // Each writer gets its own handle, with a listener that merges writes into this handle.
+        let policy = self.graph.merge_policies.get(&handle.name).cloned().unwrap_or(ast::MergePolicy::LastWriteWins);
+        for (writer_idx, writer) in writers.drain(..).enumerate() {
+          let writer_handle = ast::Handle {
+            position: ast::SafeSpan { offset: 0, line: 1 },
+            name: format!("{}.writer{}", handle.name, writer_idx),
+            h_type: handle.h_type.clone(),
+            usages: vec!(ast::Usage::Read, ast::Usage::Write)
+          };
+          result.push(HandleInfo {
+            handle: writer_handle,
+            mapped_for_submodules: vec!(writer),
+            write_behaviour: WriteBehaviour::MergesInto(handle.name.clone(), policy.clone())
+          });
+        }
+      } else {
+        if self.graph.merge_policies.contains_key(&handle.name) {
+          return Err(GraphToModuleError::NothingToMerge(handle.name.clone()));
+        }
+        mapped_for_submodules.append(&mut writers);
+      }
+
+      let mut derived = Vec::new();
+      for (n, component) in components {
+        let value = ast::Expression::tuple_lookup(ast::SafeSpan { offset: 0, line: 1 },
+          ast::Expression::state_reference(ast::SafeSpan { offset: 0, line: 1 }, &handle.name), n as i64);
+        derived.push(ast::Expression::output(ast::SafeSpan { offset: 0, line: 1 }, &component.handle.name, value, false));
+        result.push(component);
+      }
+
+      // Handles written from this one: plain copies (input -> output), |x| nodes and switch cases.
+      let mut switch_cases = Vec::new();
+      for (target, arrow) in self.graph.endpoints_associated_with_endpoint(graph::SimpleEndpoint::Handle(index), graph::EndpointSpec::AnyHandle) {
+        if arrow.from != graph::Endpoint::Simple(graph::SimpleEndpoint::Handle(index)) {
           continue;
         }
-        if candidate_found {
-          // TODO: we don't currently deal with a handle having multiple readers
-          panic!("Handle has multiple readers, can't cope");
+        let target_name = &self.graph.handles[target.handle_idx().unwrap()].name;
+        let value = match arrow.info {
+          graph::ArrowInfo::Transform(t) => {
+            let transform = &self.graph.transforms[t];
+            self.bind_handle(&handle.name, &transform.param, transform.body.clone())
+          }
+          graph::ArrowInfo::SwitchCase(s, c) => {
+            switch_cases.push((s, c, target_name.clone()));
+            continue;
+          }
+          _ => ast::Expression::state_reference(ast::SafeSpan { offset: 0, line: 1 }, &handle.name)
+        };
+        derived.push(ast::Expression::output(ast::SafeSpan { offset: 0, line: 1 }, target_name, value, false));
+      }
+
+      // Each switch on this handle writes it to the first case whose predicate holds.
+      switch_cases.sort();
+      let mut switches: Vec<usize> = switch_cases.iter().map(|(s, _, _)| *s).collect();
+      switches.dedup();
+      for s in switches {
+        let mut routing = ast::Expression::empty(ast::SafeSpan { offset: 0, line: 1 });
+        for (_, c, case_handle) in switch_cases.iter().filter(|(case_switch, _, _)| *case_switch == s).rev() {
+          let write = ast::Expression::block(ast::SafeSpan { offset: 0, line: 1 }, vec!(
+            ast::Expression::output(ast::SafeSpan { offset: 0, line: 1 }, case_handle,
+              ast::Expression::state_reference(ast::SafeSpan { offset: 0, line: 1 }, &handle.name), false),
+            ast::Expression::empty(ast::SafeSpan { offset: 0, line: 1 })
+          ));
+          routing = match &self.graph.switches[s].cases[*c].1 {
+            ast::SwitchCase::When(param, predicate) =>
+              ast::Expression::if_expression(ast::SafeSpan { offset: 0, line: 1 }, self.bind_handle(&handle.name, param, predicate.clone()), write, routing),
+            _ => write
+          };
         }
-        writes_to_submodule = candidate_writes_to_submodule;
-        submodule_handle = connection_name;
-        candidate_found = true;
+        derived.push(routing);
       }
 
-      if !candidate_found {
+      if readers.is_empty() && derived.is_empty() {
         // There's no read candidate for this handle
         let new_handle = ast::Handle { position: ast::SafeSpan { offset: 0, line: 1 }, name: handle.name.clone(), h_type: handle.h_type.clone(), usages: vec!(ast::Usage::Write) };
         result.push(HandleInfo { handle: new_handle, write_behaviour: WriteBehaviour::None, mapped_for_submodules });
       } else {
         // TODO: What's the right place to position these synthetic handles?
         let new_handle = ast::Handle { position: ast::SafeSpan { offset: 0, line: 1 }, name: handle.name.clone(), h_type: handle.h_type.clone(), usages: vec!(ast::Usage::Read, ast::Usage::Write) };
-        let write_behaviour = WriteBehaviour::WritesToSubmodule(writes_to_submodule, submodule_handle.clone());
+        let write_behaviour = WriteBehaviour::WritesToSubmodules(readers, derived);
         result.push(HandleInfo { handle: new_handle, write_behaviour, mapped_for_submodules });
       }
       index += 1;
@@ -213,6 +321,15 @@ impl <'a> ModuleContext<'a> {
     Ok(output)
   }
 
+  // { let param = handle; body }
+  fn bind_handle(&self, handle_name: &str, param: &str, body: ast::Expression) -> ast::Expression {
+    ast::Expression::block(ast::SafeSpan { offset: 0, line: 1 }, vec!(
+      ast::Expression::let_expression(ast::SafeSpan { offset: 0, line: 1 }, param,
+        ast::Expression::state_reference(ast::SafeSpan { offset: 0, line: 1 }, handle_name), false),
+      body
+    ))
+  }
+
   fn find_module_by_name(&self, name: &str) -> Option<&ast::Module> {
     graph_builder::find_module_by_name(&self.modules, name)
   }
@@ -250,14 +367,19 @@ impl <'a> ModuleContext<'a> {
     handle_infos.iter().filter(|handle_info| handle_info.handle.is_input())
                        .map(|handle_info| {
                           match &handle_info.write_behaviour {
-                            WriteBehaviour::WritesToSubmodule(submodule, sub_handle_name) =>
+                            WriteBehaviour::WritesToSubmodules(targets, derived) => {
+                              let mut copies: Vec<ast::Expression> = targets.iter().map(|(submodule, sub_handle_name)|
+                                ast::Expression::copy_to_submodule(ast::SafeSpan { offset: 0, line: 1 }, &handle_info.handle.name, *submodule, sub_handle_name)
+                              ).collect();
+                              copies.extend(derived.iter().cloned());
                               ast::Listener {
                                 trigger: handle_info.handle.name.clone(),
                                 kind: ast::ListenerKind::OnWrite,
                                 implementation: ast::Expression::output(ast::SafeSpan { offset: 0, line: 1 }, "", 
-                                  ast::Expression::copy_to_submodule(ast::SafeSpan { offset: 0, line: 1 }, &handle_info.handle.name, *submodule, sub_handle_name), 
+                                  ast::Expression::block(ast::SafeSpan { offset: 0, line: 1 }, copies), 
                                   false).value
-                              },
+                              }
+                            },
                             WriteBehaviour::WritesToTupleHandle(name, handles, uid, idx) =>
                               ast::Listener {
                                 trigger: handle_info.handle.name.clone(),
@@ -265,6 +387,13 @@ impl <'a> ModuleContext<'a> {
                                 implementation: ast::Expression::output(ast::SafeSpan { offset: 0, line: 1}, "",
                                   ast::Expression::write_to_tuple(ast::SafeSpan { offset: 0, line: 1}, name, handles, *uid, *idx), false).value
                               },
+                            WriteBehaviour::MergesInto(target, policy) =>
+                              ast::Listener {
+                                trigger: handle_info.handle.name.clone(),
+                                kind: ast::ListenerKind::OnWrite,
+                                implementation: ast::Expression::output(ast::SafeSpan { offset: 0, line: 1}, "",
+                                  ast::Expression::merge_into(ast::SafeSpan { offset: 0, line: 1}, &handle_info.handle.name, target, policy), false).value
+                              },
                             WriteBehaviour::None => panic!("Shouldn't be possible")
                           }
                         }).collect()
@@ -274,4 +403,66 @@ impl <'a> ModuleContext<'a> {
     // clone modules so the final data structure doesn't refer into the provided module list.
     self.module_infos.iter().map(|info| (info.module.clone(), info.params.clone())).collect()
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static WRITERS_STRING: &str = "
+module AddOne {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+}
+
+module Double {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input * 2;
+}
+";
+
+  // Builds the last module in WRITERS_STRING + module_string from its graph.
+  fn build(module_string: &str) -> Result<(), GraphToModuleError> {
+    let source = WRITERS_STRING.to_string() + module_string;
+    let (_, mut ast) = parser::parse(&source).unwrap();
+    let modules: Vec<ast::Module> = ast::modules(&ast).iter().map(|module| (*module).clone()).collect();
+    let module = ast::modules_mut(&mut ast).pop().unwrap();
+    let submodules: Vec<&ast::Module> = modules.iter().collect();
+    let mut graph = graph_builder::make_graph(module.graph.iter().collect(), &Vec::new()).unwrap();
+    graph_builder::resolve_graph(module, &submodules, &mut graph).unwrap();
+    graph_to_module(module, graph, submodules)
+  }
+
+  #[test]
+  fn merge_policies_need_several_writers() {
+    let merged = "
+module Merged {
+  input: reads Int;
+  output: writes Int;
+
+  input -> AddOne -> output;
+  input -> Double -> output;
+  merge output: first;
+}
+";
+    assert!(build(merged).is_ok());
+    let single = "
+module Single {
+  input: reads Int;
+  output: writes Int;
+
+  input -> AddOne -> output;
+  merge output: first;
+}
+";
+    if let Err(GraphToModuleError::NothingToMerge(name)) = build(single) {
+      assert_eq!(name, "output");
+    } else {
+      panic!("expected a merge policy with nothing to merge");
+    }
+  }
+}
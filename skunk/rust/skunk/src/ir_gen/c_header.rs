@@ -0,0 +1,269 @@
+// C headers for compiled modules: the state struct that <Module>_init returns, laid out the way
+// `Typeable for ast::Module` lays it out, and prototypes for the functions every module exports.
+//
+// For a module Echo the header declares
+//   typedef struct Echo_state { ... } Echo_state;
+//   Echo_state *Echo_init(void);
+//   void Echo_deinit(Echo_state *state);
+//   void Echo_update(Echo_state *state);
+//   int64_t Echo_run(Echo_state *state, uint64_t max_steps);
+//   void Echo__dump(Echo_state *state);
+//   uint64_t Echo_run_examples(void);
+// and the accessors from accessor_codegen:
+//   int64_t Echo_read_input(Echo_state *state);
+//   void Echo_write_input(Echo_state *state, int64_t value);
+//   bool Echo_has_pending(Echo_state *state);
+// and, on the top-level module only, callback registration for each output:
+//   void Echo_on_output(Echo_state *state, Echo_on_output_fn fn, void *userdata);
+// Host code that only uses the functions can treat Echo_state as opaque.
+//
+// Handles that aren't plain Int/Char/Bool get a typedef (Echo_<handle>_t) so that the value and update
+// fields share a type. Submodule state structs are defined in the same header, so each header stands alone;
+// guard macros stop them being defined twice when several headers are included.
+
+use super::ast;
+use super::state_values::{TypePrimitive, type_primitive_for_type};
+use super::codegen::{compiled_modules, is_by_value, RUN_NOT_CONVERGED};
+use super::codegen_state::{CodegenError, CodegenStatus};
+
+use std::collections::{HashMap, HashSet};
+
+// C keywords, and the macros stdbool.h defines, which can't be used as field names.
+static KEYWORDS: &[&str] = &[
+  "auto", "bool", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern",
+  "false", "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return", "short", "signed",
+  "sizeof", "static", "struct", "switch", "true", "typedef", "union", "unsigned", "void", "volatile", "while",
+];
+
+// Handle names made up by graph_to_module contain '.', as do names from fragments, which are prefixed with
+// <Fragment><n>.
+pub fn c_identifier(name: &str) -> String {
+  name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+// A state struct field's name: c_identifier, with a _ after C keywords (so char becomes char_).
+fn c_field_name(name: &str) -> String {
+  let name = c_identifier(name);
+  if KEYWORDS.contains(&name.as_str()) { format!("{}_", name) } else { name }
+}
+
+// c_identifier maps a.b and a_b to the same name, and c_field_name maps char and char_ to the same field,
+// so a module using both can't have a header or accessors.
+pub fn check_c_identifiers(module: &ast::Module) -> CodegenStatus {
+  let mut seen: HashMap<String, &str> = HashMap::new();
+  let names = module.handles.iter().map(|handle| handle.name.as_str()).chain(module.value_params.iter().map(|param| param.name.as_str()));
+  for name in names {
+    match seen.insert(c_field_name(name), name) {
+      Some(other) if other != name => return Err(CodegenError::CIdentifierClash(other.to_string(), name.to_string())),
+      _ => ()
+    }
+  }
+  Ok(())
+}
+
+fn state_type_name(module: &ast::Module) -> String {
+  format!("{}_state", c_identifier(&module.name))
+}
+
+fn c_type_for_primitive(primitive_type: &Vec<TypePrimitive>) -> String {
+  if primitive_type.len() != 1 {
+    return struct_for_members(primitive_type);
+  }
+  match &primitive_type[0] {
+    TypePrimitive::Int => "int64_t".to_string(),
+    TypePrimitive::Char => "char".to_string(),
+    TypePrimitive::Bool => "bool".to_string(),
+    TypePrimitive::MemRegion => "struct { char *data; int64_t size; }".to_string(),
+    TypePrimitive::DynamicArrayOf(x) => format!("struct {{ {} *data; int64_t size; }}", c_type_for_primitive(x)),
+    TypePrimitive::PointerTo(x) => format!("{} *", struct_for_members(x)),
+    TypePrimitive::FixedArrayOf(x, _s) => format!("{} *", c_type_for_primitive(x)),
+    TypePrimitive::TupleOf(x) => struct_for_members(x),
+  }
+}
+
+// Tuple members are named _0, _1, ... after their position.
+fn struct_for_members(members: &Vec<TypePrimitive>) -> String {
+  let fields: Vec<String> = members.iter().enumerate().map(|(idx, member)| format!("{} _{};", c_type_for_primitive(&vec!(member.clone())), idx)).collect();
+  format!("struct {{ {} }}", fields.join(" "))
+}
+
+// The name of the type to declare a field with, and the typedef for it if it needs one.
+fn named_type(module: &ast::Module, name: &str, field_type: &ast::Type) -> (String, Option<String>) {
+  let primitive_type = type_primitive_for_type(field_type);
+  let c_type = c_type_for_primitive(&primitive_type);
+  if is_by_value(&primitive_type) {
+    (c_type, None)
+  } else {
+    let type_name = format!("{}_{}_t", c_identifier(&module.name), c_identifier(name));
+    (type_name.clone(), Some(format!("typedef {} {};", c_type, type_name)))
+  }
+}
+
+fn field_type(lines: &mut Vec<String>, module: &ast::Module, name: &str, field_type: &ast::Type) -> String {
+  let (type_name, typedef) = named_type(module, name, field_type);
+  lines.extend(typedef);
+  type_name
+}
+
+// The type of the callbacks registered with <Module>_on_<handle>.
+fn callback_type_name(module: &ast::Module, handle: &ast::Handle) -> String {
+  format!("{}_on_{}_fn", c_identifier(&module.name), c_identifier(&handle.name))
+}
+
+// Only the top-level module (root) has callbacks.
+fn state_struct(lines: &mut Vec<String>, defined: &mut HashSet<String>, module: &ast::Module, root: bool) {
+  if defined.contains(&module.name) {
+    return;
+  }
+  defined.insert(module.name.clone());
+  for submodule in &module.submodules {
+    state_struct(lines, defined, &submodule.module, false);
+  }
+
+  let state_type = state_type_name(module);
+  let guard = format!("SKUNK_{}_DEFINED", state_type.to_uppercase());
+  lines.push(format!("#ifndef {}", guard));
+  lines.push(format!("#define {}", guard));
+
+  let mut fields = Vec::new();
+  for handle in &module.handles {
+    let c_type = field_type(lines, module, &handle.name, &handle.h_type);
+    fields.push(format!("  {} {};", c_type, c_field_name(&handle.name)));
+    fields.push(format!("  {} {}_upd;", c_type, c_identifier(&handle.name)));
+  }
+  fields.push("  // bit n is set when handle n has a pending update".to_string());
+  fields.push("  uint64_t bitfield;".to_string());
+  if module.tuples.len() > 0 {
+    fields.push("  uint64_t tuple_bitfield;".to_string());
+  }
+  for param in &module.value_params {
+    let c_type = field_type(lines, module, &param.name, &param.vp_type);
+    fields.push(format!("  {} {};", c_type, c_field_name(&param.name)));
+  }
+  for (idx, submodule) in module.submodules.iter().enumerate() {
+    fields.push(format!("  {} sub{};", state_type_name(&submodule.module), idx));
+  }
+  if root {
+    for handle in module.outputs() {
+      let (type_name, _) = named_type(module, &handle.name, &handle.h_type);
+      let value = if is_by_value(&type_primitive_for_type(&handle.h_type)) { format!("{} value", type_name) } else { format!("const {} *value", type_name) };
+      let callback_type = callback_type_name(module, handle);
+      lines.push(format!("typedef void (*{})(void *userdata, {});", callback_type, value));
+      let name = c_identifier(&handle.name);
+      fields.push(format!("  {} on_{};", callback_type, name));
+      fields.push(format!("  void *on_{}_userdata;", name));
+    }
+  }
+
+  lines.push(format!("typedef struct {} {{", state_type));
+  lines.append(&mut fields);
+  lines.push(format!("}} {};", state_type));
+  lines.push(format!("#endif /* {} */", guard));
+  lines.push(String::new());
+}
+
+pub fn c_header(module: &ast::Module, root: bool) -> String {
+  let name = c_identifier(&module.name);
+  let state_type = state_type_name(module);
+  let guard = format!("SKUNK_{}_H", name.to_uppercase());
+  let mut lines = vec!(
+    format!("/* Generated by skunk for module {}. Do not edit. */", module.name),
+    format!("#ifndef {}", guard),
+    format!("#define {}", guard),
+    String::new(),
+    "#include <stdbool.h>".to_string(),
+    "#include <stdint.h>".to_string(),
+    String::new(),
+  );
+  state_struct(&mut lines, &mut HashSet::new(), module, root);
+  lines.push(format!("{} *{}_init(void);", state_type, name));
+  lines.push(format!("void {}_deinit({} *state);", name, state_type));
+  lines.push(format!("void {}_update({} *state);", name, state_type));
+  lines.push(format!("/* The number of updates run, or {} if updates are still pending after max_steps (0 for the module's limit) or a submodule gave up. */", RUN_NOT_CONVERGED));
+  lines.push(format!("int64_t {}_run({} *state, uint64_t max_steps);", name, state_type));
+  lines.push(format!("void {}__dump({} *state);", name, state_type));
+  lines.push("/* Runs and reports every example, and returns how many failed. */".to_string());
+  lines.push(format!("uint64_t {}_run_examples(void);", name));
+  lines.push(String::new());
+  for handle in &module.handles {
+    let (type_name, _) = named_type(module, &handle.name, &handle.h_type);
+    let handle_name = c_identifier(&handle.name);
+    if is_by_value(&type_primitive_for_type(&handle.h_type)) {
+      lines.push(format!("{} {}_read_{}({} *state);", type_name, name, handle_name, state_type));
+      lines.push(format!("void {}_write_{}({} *state, {} value);", name, handle_name, state_type, type_name));
+    } else {
+      lines.push(format!("void {}_read_{}({} *state, {} *value);", name, handle_name, state_type, type_name));
+      lines.push(format!("void {}_write_{}({} *state, const {} *value);", name, handle_name, state_type, type_name));
+    }
+  }
+  lines.push(format!("bool {}_has_pending({} *state);", name, state_type));
+  if root {
+    lines.push(String::new());
+    lines.push("/* fn is called with the new value whenever an update makes one current; a null fn removes it. */".to_string());
+    for handle in module.outputs() {
+      lines.push(format!("void {}_on_{}({} *state, {} fn, void *userdata);", name, c_identifier(&handle.name), state_type, callback_type_name(module, handle)));
+    }
+  }
+  lines.push(String::new());
+  lines.push(format!("#endif /* {} */", guard));
+  lines.join("\n") + "\n"
+}
+
+// One (file name, contents) pair per module that codegen produces an object for.
+pub fn c_headers(module: &ast::Module) -> Vec<(String, String)> {
+  compiled_modules(module).iter().enumerate().map(|(idx, module)| (format!("{}.h", module.name), c_header(module, idx == 0))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::super::parser;
+
+  #[test]
+  fn headers_follow_the_state_layout() {
+    let (_, ast) = parser::parse("module Pairer {\n  input: reads Int;\n  pair: writes (Int, Char);\n  done: writes Bool;\n}").unwrap();
+    let header = c_header(&ast::modules(&ast)[0], true);
+    assert!(header.contains("#include <stdint.h>"));
+    assert!(header.contains("typedef struct { int64_t _0; char _1; } Pairer_pair_t;"));
+    assert!(header.contains(
+      "typedef struct Pairer_state {\n  int64_t input;\n  int64_t input_upd;\n  Pairer_pair_t pair;\n  Pairer_pair_t pair_upd;\n  bool done;\n  bool done_upd;\n"
+    ));
+    assert!(header.contains("  uint64_t bitfield;\n  Pairer_on_pair_fn on_pair;"));
+    assert!(header.contains("Pairer_state *Pairer_init(void);"));
+    assert!(header.contains("void Pairer_deinit(Pairer_state *state);"));
+    assert!(header.contains("void Pairer_update(Pairer_state *state);"));
+    assert!(header.contains("int64_t Pairer_run(Pairer_state *state, uint64_t max_steps);"));
+    assert!(header.contains("void Pairer__dump(Pairer_state *state);"));
+    assert!(header.contains("uint64_t Pairer_run_examples(void);"));
+    assert!(header.contains("int64_t Pairer_read_input(Pairer_state *state);"));
+    assert!(header.contains("void Pairer_write_pair(Pairer_state *state, const Pairer_pair_t *value);"));
+    assert!(header.contains("bool Pairer_read_done(Pairer_state *state);"));
+    assert!(header.contains("bool Pairer_has_pending(Pairer_state *state);"));
+    assert!(header.contains("typedef void (*Pairer_on_pair_fn)(void *userdata, const Pairer_pair_t *value);"));
+    assert!(header.contains("  Pairer_on_done_fn on_done;\n  void *on_done_userdata;\n} Pairer_state;"));
+    assert!(header.contains("void Pairer_on_pair(Pairer_state *state, Pairer_on_pair_fn fn, void *userdata);"));
+    assert!(!header.contains("Pairer_on_input"));
+  }
+
+  #[test]
+  fn clashing_c_identifiers_are_rejected() {
+    let (_, ast) = parser::parse("module Clash {\n  a_b: reads Int;\n  c: writes Int;\n}").unwrap();
+    let mut module = ast::modules(&ast)[0].clone();
+    assert_eq!(check_c_identifiers(&module), Ok(()));
+    let mut synthetic = module.handles[1].clone();
+    synthetic.name = "a.b".to_string();
+    module.handles.push(synthetic);
+    assert_eq!(check_c_identifiers(&module), Err(CodegenError::CIdentifierClash("a_b".to_string(), "a.b".to_string())));
+
+    let (_, ast) = parser::parse("module Keywords {\n  char: reads Int;\n  default: writes Int;\n}").unwrap();
+    let mut module = ast::modules(&ast)[0].clone();
+    assert_eq!(check_c_identifiers(&module), Ok(()));
+    let header = c_header(&module, true);
+    assert!(header.contains("  int64_t char_;\n  int64_t char_upd;\n  int64_t default_;\n"));
+    assert!(header.contains("int64_t Keywords_read_char(Keywords_state *state);"));
+    let mut synthetic = module.handles[1].clone();
+    synthetic.name = "char_".to_string();
+    module.handles.push(synthetic);
+    assert_eq!(check_c_identifiers(&module), Err(CodegenError::CIdentifierClash("char".to_string(), "char_".to_string())));
+  }
+}
@@ -0,0 +1,182 @@
+use super::*;
+
+use super::super::c_header::c_identifier;
+
+use inkwell::types::PointerType;
+use inkwell::values::CallableValue;
+
+use std::convert::TryFrom;
+
+// Accessors let host code treat a module's state as opaque:
+//   <Module>_write_<handle>(state, value)  writes a pending update and sets its bit
+//   <Module>_read_<handle>(state)          reads the current value
+//   <Module>_has_pending(state)            whether any updates are pending
+// and on the top-level module, for each output
+//   <Module>_on_<handle>(state, fn, userdata)
+// which registers fn(userdata, value) to be called whenever <Module>_update makes a new value current.
+// Passing a null fn removes the callback.
+// Int, Char and Bool values are passed by value (Bool as a C bool, i.e. a byte). Other values are passed
+// through pointers to the handle's type: write takes a pointer to the new value, and read takes a pointer
+// to store the value into and returns nothing.
+
+pub fn is_by_value(primitive_type: &Vec<TypePrimitive>) -> bool {
+  primitive_type.len() == 1 && matches!(primitive_type[0], TypePrimitive::Int | TypePrimitive::Char | TypePrimitive::Bool)
+}
+
+fn is_bool(primitive_type: &Vec<TypePrimitive>) -> bool {
+  primitive_type.len() == 1 && primitive_type[0] == TypePrimitive::Bool
+}
+
+// The type accessors pass values as.
+fn abi_type<'ctx>(cg: &CodegenState<'ctx>, primitive_type: &Vec<TypePrimitive>) -> BasicTypeEnum<'ctx> {
+  if is_bool(primitive_type) {
+    cg.context.i8_type().into()
+  } else if is_by_value(primitive_type) {
+    llvm_type_for_primitive(cg, primitive_type)
+  } else {
+    llvm_type_for_primitive(cg, primitive_type).ptr_type(AddressSpace::Generic).into()
+  }
+}
+
+pub fn accessors_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module) -> CodegenStatus {
+  for handle in &module.handles {
+    write_accessor_codegen(cg, module, handle)?;
+    read_accessor_codegen(cg, module, handle)?;
+  }
+  if cg.has_host_callbacks(module) {
+    for handle in module.outputs() {
+      register_callback_codegen(cg, module, handle)?;
+    }
+  }
+  has_pending_codegen(cg, module)
+}
+
+pub fn callback_type<'ctx>(cg: &CodegenState<'ctx>, primitive_type: &Vec<TypePrimitive>) -> PointerType<'ctx> {
+  cg.context.void_type().fn_type(&[cg.char_ptr_type().into(), abi_type(cg, primitive_type).into()], false).ptr_type(AddressSpace::Generic)
+}
+
+fn callback_ptrs<'ctx>(cg: &CodegenState<'ctx>, module: &ast::Module, state_ptr: PointerValue<'ctx>, handle: &ast::Handle) -> CodegenResult<(PointerValue<'ctx>, PointerValue<'ctx>)> {
+  let idx = module.idx_for_callback(&handle.name).ok_or(CodegenError::BadReadFieldName(handle.name.clone()))? as u32;
+  let fn_ptr = cg.builder.build_struct_gep(state_ptr, idx, "callback_ptr").or(Err(CodegenError::InvalidStructPointer("callback_ptrs given bad state pointer".to_string())))?;
+  let userdata_ptr = cg.builder.build_struct_gep(state_ptr, idx + 1, "userdata_ptr").or(Err(CodegenError::InvalidStructPointer("callback_ptrs given bad state pointer".to_string())))?;
+  Ok((fn_ptr, userdata_ptr))
+}
+
+fn register_callback_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module, handle: &ast::Handle) -> CodegenStatus {
+  let module_ptr_type = module.ir_type(cg).into_struct_type().ptr_type(AddressSpace::Generic);
+  let callback_type = callback_type(cg, &type_primitive_for_type(&handle.h_type));
+  let function_type = cg.context.void_type().fn_type(&[module_ptr_type.into(), callback_type.into(), cg.char_ptr_type().into()], false);
+  let name = format!("{}_on_{}", c_identifier(&module.name), c_identifier(&handle.name));
+  let function = cg.module.add_function(&name, function_type, None);
+  let entry_block = cg.context.append_basic_block(function, "entry");
+  cg.builder.position_at_end(entry_block);
+
+  let state_ptr = function.get_first_param().unwrap().into_pointer_value();
+  let (fn_ptr, userdata_ptr) = callback_ptrs(cg, module, state_ptr, handle)?;
+  let callback = cg.builder.build_bitcast(function.get_nth_param(1).unwrap(), cg.char_ptr_type(), "callback");
+  cg.builder.build_store(fn_ptr, callback);
+  cg.builder.build_store(userdata_ptr, function.get_nth_param(2).unwrap());
+  cg.builder.build_return(None);
+  Ok(())
+}
+
+// Calls the callback registered for handle, if there is one, with the handle's current value.
+pub fn callback_codegen<'ctx>(cg: &CodegenState<'ctx>, module: &ast::Module, state_ptr: PointerValue<'ctx>, handle: &ast::Handle) -> CodegenStatus {
+  let primitive_type = type_primitive_for_type(&handle.h_type);
+  let (fn_ptr, userdata_ptr) = callback_ptrs(cg, module, state_ptr, handle)?;
+  let callback = cg.builder.build_load(fn_ptr, "callback").into_pointer_value();
+
+  let function = cg.builder.get_insert_block().unwrap().get_parent().unwrap();
+  let call_block = cg.context.append_basic_block(function, &format!("call_on_{}", handle.name));
+  let after_block = cg.context.append_basic_block(function, &format!("after_on_{}", handle.name));
+  let registered = cg.builder.build_is_not_null(callback, "registered");
+  cg.builder.build_conditional_branch(registered, call_block, after_block);
+
+  cg.builder.position_at_end(call_block);
+  let read_ptr = cg.read_ptr_for_field(module, state_ptr, &handle.name)?.pointer.into_pointer_value();
+  let value: BasicValueEnum = if is_bool(&primitive_type) {
+    let value = cg.builder.build_load(read_ptr, "value").into_int_value();
+    cg.builder.build_int_z_extend(value, cg.context.i8_type(), "value").into()
+  } else if is_by_value(&primitive_type) {
+    cg.builder.build_load(read_ptr, "value")
+  } else {
+    read_ptr.into()
+  };
+  let userdata = cg.builder.build_load(userdata_ptr, "userdata");
+  let callback = cg.builder.build_bitcast(callback, callback_type(cg, &primitive_type), "callback").into_pointer_value();
+  let callable = CallableValue::try_from(callback).unwrap();
+  cg.builder.build_call(callable, &[userdata.into(), value.into()], "_");
+  cg.builder.build_unconditional_branch(after_block);
+
+  cg.builder.position_at_end(after_block);
+  Ok(())
+}
+
+fn write_accessor_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module, handle: &ast::Handle) -> CodegenStatus {
+  let module_ptr_type = module.ir_type(cg).into_struct_type().ptr_type(AddressSpace::Generic);
+  let primitive_type = type_primitive_for_type(&handle.h_type);
+  let function_type = cg.context.void_type().fn_type(&[module_ptr_type.into(), abi_type(cg, &primitive_type).into()], false);
+  let name = format!("{}_write_{}", c_identifier(&module.name), c_identifier(&handle.name));
+  let function = cg.module.add_function(&name, function_type, None);
+  let entry_block = cg.context.append_basic_block(function, "entry");
+  cg.builder.position_at_end(entry_block);
+
+  let state_ptr = function.get_first_param().unwrap().into_pointer_value();
+  let value = function.get_nth_param(1).unwrap();
+  let value = if is_bool(&primitive_type) {
+    cg.builder.build_int_truncate(value.into_int_value(), cg.context.bool_type(), "value").into()
+  } else if is_by_value(&primitive_type) {
+    value
+  } else {
+    cg.builder.build_load(value.into_pointer_value(), "value")
+  };
+  let update_ptr = cg.update_ptr_for_field(module, state_ptr, &handle.name, UpdatePtrPurpose::WriteAndSet)?;
+  cg.builder.build_store(update_ptr.pointer.into_pointer_value(), value);
+  cg.builder.build_return(None);
+  Ok(())
+}
+
+fn read_accessor_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module, handle: &ast::Handle) -> CodegenStatus {
+  let module_ptr_type = module.ir_type(cg).into_struct_type().ptr_type(AddressSpace::Generic);
+  let primitive_type = type_primitive_for_type(&handle.h_type);
+  let function_type = if is_by_value(&primitive_type) {
+    abi_type(cg, &primitive_type).fn_type(&[module_ptr_type.into()], false)
+  } else {
+    cg.context.void_type().fn_type(&[module_ptr_type.into(), abi_type(cg, &primitive_type).into()], false)
+  };
+  let name = format!("{}_read_{}", c_identifier(&module.name), c_identifier(&handle.name));
+  let function = cg.module.add_function(&name, function_type, None);
+  let entry_block = cg.context.append_basic_block(function, "entry");
+  cg.builder.position_at_end(entry_block);
+
+  let state_ptr = function.get_first_param().unwrap().into_pointer_value();
+  let read_ptr = cg.read_ptr_for_field(module, state_ptr, &handle.name)?;
+  let value = cg.builder.build_load(read_ptr.pointer.into_pointer_value(), "value");
+  if is_bool(&primitive_type) {
+    let value = cg.builder.build_int_z_extend(value.into_int_value(), cg.context.i8_type(), "value");
+    cg.builder.build_return(Some(&value));
+  } else if is_by_value(&primitive_type) {
+    cg.builder.build_return(Some(&value));
+  } else {
+    let out_ptr = function.get_nth_param(1).unwrap().into_pointer_value();
+    cg.builder.build_store(out_ptr, value);
+    cg.builder.build_return(None);
+  }
+  Ok(())
+}
+
+fn has_pending_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module) -> CodegenStatus {
+  let module_ptr_type = module.ir_type(cg).into_struct_type().ptr_type(AddressSpace::Generic);
+  let function_type = cg.context.i8_type().fn_type(&[module_ptr_type.into()], false);
+  let function = cg.module.add_function(&format!("{}_has_pending", c_identifier(&module.name)), function_type, None);
+  let entry_block = cg.context.append_basic_block(function, "entry");
+  cg.builder.position_at_end(entry_block);
+
+  let state_ptr = function.get_first_param().unwrap().into_pointer_value();
+  let bitfield_ptr = cg.module_bitfield_ptr(module, state_ptr)?;
+  let bitfield = cg.builder.build_load(bitfield_ptr, "bitfield").into_int_value();
+  let pending = cg.builder.build_int_compare(IntPredicate::NE, bitfield, cg.uint_const(0), "pending");
+  let pending = cg.builder.build_int_z_extend(pending, cg.context.i8_type(), "pending");
+  cg.builder.build_return(Some(&pending));
+  Ok(())
+}
@@ -1,16 +1,18 @@
 use super::*;
 
+// Sizes can be given as any integer type; they're converted to the target's size_t.
+fn as_size<'ctx>(cg: &CodegenState<'ctx>, size: IntValue<'ctx>) -> IntValue<'ctx> {
+  cg.builder.build_int_cast(size, cg.size_type, "size")
+}
+
 pub fn malloc<'ctx>(cg: &CodegenState<'ctx>, size: IntValue<'ctx>, name: &str) -> BasicValueEnum<'ctx> {
   let malloc = get_malloc(cg);
-  if size.get_type().get_bit_width() != 32 {
-    panic!("malloc called with non-32 bit size");
-  }
-  cg.builder.build_call(malloc, &[size.into()], name).try_as_basic_value().left().unwrap()
+  cg.builder.build_call(malloc, &[as_size(cg, size).into()], name).try_as_basic_value().left().unwrap()
 }
 
 fn get_malloc<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
   cg.module.get_function("malloc").or_else(|| {
-    let function_type = cg.char_ptr_type().fn_type(&[cg.context.i32_type().into()], false);
+    let function_type = cg.char_ptr_type().fn_type(&[cg.size_type.into()], false);
     Some(cg.module.add_function("malloc", function_type, None))
   }).unwrap()
 }
@@ -29,15 +31,16 @@ fn get_free<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
 
 pub fn memcmp<'ctx>(cg: &CodegenState<'ctx>, lhs: PointerValue<'ctx>, rhs: PointerValue<'ctx>, size: IntValue<'ctx>) -> IntValue<'ctx> {
   let memcmp = get_memcmp(cg);
-  cg.builder.build_call(memcmp, &[lhs.into(), rhs.into(), size.into()], "memcmp_result").try_as_basic_value().left().unwrap().into_int_value()
+  let result = cg.builder.build_call(memcmp, &[lhs.into(), rhs.into(), as_size(cg, size).into()], "memcmp_result").try_as_basic_value().left().unwrap().into_int_value();
+  cg.builder.build_int_s_extend(result, cg.context.i64_type(), "memcmp_result")
 }
 
 fn get_memcmp<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
   cg.module.get_function("memcmp").or_else(|| {
-    let function_type = cg.context.i64_type().fn_type(&[
+    let function_type = cg.context.i32_type().fn_type(&[
       cg.char_ptr_type().into(), 
       cg.char_ptr_type().into(), 
-      cg.context.i64_type().into()
+      cg.size_type.into()
     ], false);
     Some(cg.module.add_function("memcmp", function_type, None))
   }).unwrap()
@@ -45,19 +48,43 @@ fn get_memcmp<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
 
 pub fn get_snprintf<'ctx>(cg: &mut CodegenState<'ctx>) -> FunctionValue<'ctx> {
   cg.module.get_function("snprintf").or_else(|| {
-    let function_type = cg.context.i32_type().fn_type(&[cg.char_ptr_type().into(), cg.context.i32_type().into(), cg.char_ptr_type().into()], true);
+    let function_type = cg.context.i32_type().fn_type(&[cg.char_ptr_type().into(), cg.size_type.into(), cg.char_ptr_type().into()], true);
     Some(cg.module.add_function("snprintf", function_type, None))
   }).unwrap()
 }
 
-pub fn va_list_type<'ctx>(cg: &CodegenState<'ctx>) -> StructType<'ctx> {
-  // NOTE: THis is the correct va_list struct type for X86 64-bit linux platforms. YMMV for other platforms.
-  cg.context.struct_type(&[cg.context.i32_type().into(), cg.context.i32_type().into(), cg.char_ptr_type().into(), cg.char_ptr_type().into()], false)
+// The C library's va_list. On x86-64 (other than Windows) and on AArch64 (other than Apple's) it's a
+// struct that's handed to functions like vsnprintf by pointer; elsewhere (32-bit ARM and x86, Apple,
+// Windows) it's a plain pointer passed by value.
+pub fn va_list_type<'ctx>(cg: &CodegenState<'ctx>) -> BasicTypeEnum<'ctx> {
+  let triple = cg.module.get_triple();
+  let triple = triple.as_str().to_string_lossy();
+  let arch = triple.split('-').next().unwrap_or("");
+  let i32_type = cg.context.i32_type().into();
+  let char_ptr_type = cg.char_ptr_type().into();
+  if arch == "x86_64" && !triple.contains("windows") {
+    cg.context.struct_type(&[i32_type, i32_type, char_ptr_type, char_ptr_type], false).into()
+  } else if (arch == "aarch64" || arch == "arm64") && !triple.contains("apple") && !triple.contains("windows") {
+    cg.context.struct_type(&[char_ptr_type, char_ptr_type, char_ptr_type, i32_type, i32_type], false).into()
+  } else {
+    char_ptr_type
+  }
+}
+
+// What to pass as the va_list argument, given the memory that llvm.va_start initialised.
+pub fn va_list_argument<'ctx>(cg: &CodegenState<'ctx>, va_list_alloca: PointerValue<'ctx>) -> BasicValueEnum<'ctx> {
+  if va_list_type(cg).is_struct_type() {
+    va_list_alloca.into()
+  } else {
+    cg.builder.build_load(va_list_alloca, "va_list")
+  }
 }
 
 pub fn get_vsnprintf<'ctx>(cg: &mut CodegenState<'ctx>) -> FunctionValue<'ctx> {
   cg.module.get_function("vsnprintf").or_else(|| {
-    let function_type = cg.context.i32_type().fn_type(&[cg.char_ptr_type().into(), cg.context.i32_type().into(), cg.char_ptr_type().into(), va_list_type(cg).ptr_type(AddressSpace::Generic).into()], false);
+    let va_list_type = va_list_type(cg);
+    let va_list_parameter = if va_list_type.is_struct_type() { va_list_type.ptr_type(AddressSpace::Generic).into() } else { va_list_type };
+    let function_type = cg.context.i32_type().fn_type(&[cg.char_ptr_type().into(), cg.size_type.into(), cg.char_ptr_type().into(), va_list_parameter.into()], false);
     Some(cg.module.add_function("vsnprintf", function_type, None))
   }).unwrap()
 }
@@ -71,7 +98,7 @@ pub fn get_printf<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
 
 pub fn get_realloc<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
   cg.module.get_function("realloc").or_else(|| {
-    let function_type = cg.char_ptr_type().fn_type(&[cg.char_ptr_type().into(), cg.context.i32_type().into()], false);
+    let function_type = cg.char_ptr_type().fn_type(&[cg.char_ptr_type().into(), cg.size_type.into()], false);
     Some(cg.module.add_function("realloc", function_type, None))
   }).unwrap()
 }
@@ -86,12 +113,65 @@ pub fn get_va_end<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
 
 pub fn memset<'ctx>(cg: &CodegenState<'ctx>, ptr: PointerValue<'ctx>, c: IntValue<'ctx>, n: IntValue<'ctx>) -> PointerValue<'ctx> {
   let memset = get_memset(cg);
-  cg.builder.build_call(memset, &[ptr.into(), c.into(), n.into()], "memset_result").try_as_basic_value().left().unwrap().into_pointer_value()
+  let c = cg.builder.build_int_cast(c, cg.context.i32_type(), "c");
+  cg.builder.build_call(memset, &[ptr.into(), c.into(), as_size(cg, n).into()], "memset_result").try_as_basic_value().left().unwrap().into_pointer_value()
 }
 
 pub fn get_memset<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
   cg.module.get_function("memset").or_else(|| {
-    let function_type = cg.char_ptr_type().fn_type(&[cg.char_ptr_type().into(), cg.context.i8_type().into(), cg.context.i32_type().into()], false);
+    let function_type = cg.char_ptr_type().fn_type(&[cg.char_ptr_type().into(), cg.context.i32_type().into(), cg.size_type.into()], false);
     Some(cg.module.add_function("memset", function_type, None))
   }).unwrap()
-}
\ No newline at end of file
+}
+// FILE* is only ever handed back to the C library, so it's treated as a char*.
+pub fn get_fdopen<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
+  cg.module.get_function("fdopen").or_else(|| {
+    let function_type = cg.char_ptr_type().fn_type(&[cg.context.i32_type().into(), cg.char_ptr_type().into()], false);
+    Some(cg.module.add_function("fdopen", function_type, None))
+  }).unwrap()
+}
+
+pub fn get_fopen<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
+  cg.module.get_function("fopen").or_else(|| {
+    let function_type = cg.char_ptr_type().fn_type(&[cg.char_ptr_type().into(), cg.char_ptr_type().into()], false);
+    Some(cg.module.add_function("fopen", function_type, None))
+  }).unwrap()
+}
+
+pub fn get_fclose<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
+  cg.module.get_function("fclose").or_else(|| {
+    let function_type = cg.context.i32_type().fn_type(&[cg.char_ptr_type().into()], false);
+    Some(cg.module.add_function("fclose", function_type, None))
+  }).unwrap()
+}
+
+// getdelim(&buffer, &allocated, delimiter, file) returns an ssize_t, which is the same width as size_t.
+pub fn get_getdelim<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
+  cg.module.get_function("getdelim").or_else(|| {
+    let function_type = cg.size_type.fn_type(&[
+      cg.char_ptr_type().ptr_type(AddressSpace::Generic).into(),
+      cg.size_type.ptr_type(AddressSpace::Generic).into(),
+      cg.context.i32_type().into(),
+      cg.char_ptr_type().into()
+    ], false);
+    Some(cg.module.add_function("getdelim", function_type, None))
+  }).unwrap()
+}
+
+pub fn get_strlen<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
+  cg.module.get_function("strlen").or_else(|| {
+    let function_type = cg.size_type.fn_type(&[cg.char_ptr_type().into()], false);
+    Some(cg.module.add_function("strlen", function_type, None))
+  }).unwrap()
+}
+
+pub fn get_strtoll<'ctx>(cg: &CodegenState<'ctx>) -> FunctionValue<'ctx> {
+  cg.module.get_function("strtoll").or_else(|| {
+    let function_type = cg.context.i64_type().fn_type(&[
+      cg.char_ptr_type().into(),
+      cg.char_ptr_type().ptr_type(AddressSpace::Generic).into(),
+      cg.context.i32_type().into()
+    ], false);
+    Some(cg.module.add_function("strtoll", function_type, None))
+  }).unwrap()
+}
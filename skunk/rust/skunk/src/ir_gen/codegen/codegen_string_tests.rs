@@ -5,11 +5,28 @@ use super::tests::test_module;
 use inkwell::execution_engine::{JitFunction, ExecutionEngine};
 use super::super::super::graph_builder;
 use super::super::super::graph_to_module;
-use super::super::codegen_state::tests::*;
+use super::super::codegen_state::JitInfo;
 use paste::paste;
 use std::ptr;
 use std::io;
 use std::io::Write;
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+// Modules with their own graph are wired up against the modules declared before them.
+fn resolve_module_graphs(ast: &mut Vec<ast::TopLevel>) {
+  let fragments = ast::fragments(ast).iter().map(|f| (*f).clone()).collect();
+  let mut processed_modules: Vec<ast::Module> = Vec::new();
+  for module in ast::modules_mut(ast) {
+    if module.graph.len() > 0 {
+      let mut graph = graph_builder::make_graph(module.graph.iter().collect(), &fragments).unwrap();
+      let processed_refs: Vec<&ast::Module> = processed_modules.iter().collect();
+      graph_builder::resolve_graph(module, &processed_refs, &mut graph).unwrap();
+      graph_to_module::graph_to_module(module, graph, processed_refs).unwrap();
+    }
+    processed_modules.push(module.clone());
+  }
+}
 
 fn ee_for_string<F>(module: &str, func: F) -> CodegenStatus
     where F: FnOnce(ExecutionEngine, &Module) -> () {
@@ -22,7 +39,8 @@ fn ee_for_string<F>(module: &str, func: F) -> CodegenStatus
     let ee = jit_info.execution_engine.unwrap();
     func(ee, &modules[0]);
   } else {
-    let mut graph = graph_builder::make_graph(ast::graphs(&ast));
+    let fragments = ast::fragments(&ast).iter().map(|f| (*f).clone()).collect();
+    let mut graph = graph_builder::make_graph(ast::graphs(&ast), &fragments).unwrap();
     let modules = ast::modules(&ast);
     let mut main = ast::Module::create("Main", Vec::new(), Vec::new(), Vec::new(), ast::Examples { examples: Vec::new() }, Vec::new(), Vec::new());
     
@@ -36,6 +54,21 @@ fn ee_for_string<F>(module: &str, func: F) -> CodegenStatus
   Ok(())
 }
 
+// For sources whose last module is built from the ones before it with its own graph.
+fn ee_for_nested_string<F>(module: &str, func: F) -> CodegenStatus
+    where F: FnOnce(ExecutionEngine, &Module) -> () {
+  let context = Context::create();
+  let (_, mut ast) = parser::parse(module).unwrap();
+  resolve_module_graphs(&mut ast);
+
+  let mut jit_info = JitInfo::new();
+  let modules = ast::modules(&ast);
+  let cg_modules = codegen(&context, &mut jit_info, modules[modules.len() - 1])?;
+  let ee = jit_info.execution_engine.unwrap();
+  func(ee, &cg_modules[0]);
+  Ok(())
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct MemRegion {
@@ -85,10 +118,13 @@ type u64Func = unsafe extern "C" fn() -> u64;
 #[macro_export]
 macro_rules! check_examples {
   ($name:ident, $defn:ident) => {
+    check_examples!($name, $defn, ee_for_string);
+  };
+  ($name:ident, $defn:ident, $harness:ident) => {
     paste! {
       #[test]
       fn [<check_examples_for_ $name>]() -> CodegenStatus {
-        ee_for_string($defn, |ee: ExecutionEngine, _m| {
+        $harness($defn, |ee: ExecutionEngine, _m| {
           unsafe {
             //_m.print_to_stderr();
             println!("running examples\n\n\n");
@@ -188,6 +224,784 @@ fn jit_multi_module_codegen_runs() -> CodegenStatus {
   })
 }
 
+static FAN_OUT_STRING: &str = "
+module Tokenizer {
+  text: reads Int;
+  token: writes Int;
+
+  text.onChange: token <- text + 1;
+}
+
+module Validator {
+  input: reads Int;
+  valid: writes Int;
+
+  input.onChange: valid <- input * 2;
+}
+
+module Logger {
+  input: reads Int;
+  logged: writes Int;
+
+  input.onChange: logged <- input + 100;
+}
+
+$t:Tokenizer -> Validator;
+$t -> Logger;
+";
+
+state_struct!(FanOutTokenizer, text: u64, token: u64);
+state_struct!(FanOutValidator, input: u64, valid: u64);
+state_struct!(FanOutLogger, input: u64, logged: u64);
+state_struct!(FanOut, text: u64, valid: u64, logged: u64, h0: u64 | tokenizer: FanOutTokenizerState, validator: FanOutValidatorState, logger: FanOutLoggerState);
+
+#[test]
+fn jit_fan_out_codegen_runs() -> CodegenStatus {
+  ee_for_string(FAN_OUT_STRING, |ee: ExecutionEngine, _| {
+    unsafe {
+      let function: JitFunction<FanOutFunc> = ee.get_function("Main_update").unwrap();
+      let mut state = FanOutState { text: 0, text_upd: 5, valid: 0, valid_upd: 0, logged: 0, logged_upd: 0, h0: 0, h0_upd: 0, bitfield: 0x1,
+                        tokenizer: FanOutTokenizerState { text: 0, text_upd: 0, token: 0, token_upd: 0, bitfield: 0 },
+                        validator: FanOutValidatorState { input: 0, input_upd: 0, valid: 0, valid_upd: 0, bitfield: 0 },
+                        logger: FanOutLoggerState { input: 0, input_upd: 0, logged: 0, logged_upd: 0, bitfield: 0 } };
+
+      // The first update runs Tokenizer, writing the shared handle h0.
+      function.call(&mut state);
+      assert_eq!(state.bitfield, 0x8);
+      assert_eq!(state.h0_upd, 6);
+      assert_eq!(state.tokenizer.text, 5);
+      assert_eq!(state.tokenizer.token, 6);
+
+      // The second update broadcasts h0 to both Validator and Logger, so both outputs are written.
+      function.call(&mut state);
+      assert_eq!(state.bitfield, 0x6);
+      assert_eq!(state.h0, 6);
+      assert_eq!(state.validator.input, 6);
+      assert_eq!(state.logger.input, 6);
+      assert_eq!(state.validator.valid, 12);
+      assert_eq!(state.logger.logged, 106);
+      assert_eq!(state.valid_upd, 12);
+      assert_eq!(state.logged_upd, 106);
+    }
+  })
+}
+
+static PORT_TEST_STRING: &str = "
+module Split {
+  input: reads Int;
+  low: writes Int;
+  high: writes Int;
+
+  input.onChange: {
+    low <- input;
+    high <- input + 1;
+  }
+}
+
+module Sink {
+  input: reads Int;
+  out: writes Int;
+
+  input.onChange: out <- input * 10;
+}
+
+$s:Split.high -> Sink;
+";
+
+state_struct!(PortSplit, input: u64, low: u64, high: u64);
+state_struct!(PortSink, input: u64, out: u64);
+state_struct!(PortMain, input: u64, low: u64, out: u64, h0: u64 | split: PortSplitState, sink: PortSinkState);
+
+#[test]
+fn jit_port_codegen_runs() -> CodegenStatus {
+  ee_for_string(PORT_TEST_STRING, |ee: ExecutionEngine, _| {
+    unsafe {
+      let function: JitFunction<PortMainFunc> = ee.get_function("Main_update").unwrap();
+      let mut state = PortMainState { input: 0, input_upd: 5, low: 0, low_upd: 0, out: 0, out_upd: 0, h0: 0, h0_upd: 0, bitfield: 0x1,
+                        split: PortSplitState { input: 0, input_upd: 0, low: 0, low_upd: 0, high: 0, high_upd: 0, bitfield: 0 },
+                        sink: PortSinkState { input: 0, input_upd: 0, out: 0, out_upd: 0, bitfield: 0 } };
+
+      // Only the named port (high) feeds Sink; low is left free and becomes part of Main's interface.
+      function.call(&mut state);
+      assert_eq!(state.bitfield, 0xa);
+      assert_eq!(state.low_upd, 5);
+      assert_eq!(state.h0_upd, 6);
+
+      function.call(&mut state);
+      assert_eq!(state.bitfield, 0x4);
+      assert_eq!(state.out_upd, 60);
+    }
+  })
+}
+
+// AddOne and Double both write output; writes arrive in that order.
+static MERGE_LAST_TEST_STRING: &str = "
+module Source {
+  input: reads Int;
+  value: writes Int;
+
+  input.onChange: value <- input;
+}
+
+module AddOne {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+}
+
+module Double {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input * 2;
+}
+
+module MergeLast {
+  input: reads Int;
+  output: writes Int;
+
+  $s:Source -> AddOne -> output;
+  $s -> Double -> output;
+
+  examples {
+    !input: 5 -> output: 10;
+  }
+}
+";
+
+state_struct!(MergeLast, input: u64, output: u64);
+
+check_examples!(MergeLast, MERGE_LAST_TEST_STRING, ee_for_nested_string);
+
+static MERGE_FIRST_TEST_STRING: &str = "
+module Source {
+  input: reads Int;
+  value: writes Int;
+
+  input.onChange: value <- input;
+}
+
+module AddOne {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+}
+
+module Double {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input * 2;
+}
+
+module MergeFirst {
+  input: reads Int;
+  output: writes Int;
+
+  $s:Source -> AddOne -> output;
+  $s -> Double -> output;
+  merge output: first;
+
+  examples {
+    !input: 5 -> output: 6;
+  }
+}
+";
+
+state_struct!(MergeFirst, input: u64, output: u64);
+
+check_examples!(MergeFirst, MERGE_FIRST_TEST_STRING, ee_for_nested_string);
+
+static MERGE_COMBINE_TEST_STRING: &str = "
+module Source {
+  input: reads Int;
+  value: writes Int;
+
+  input.onChange: value <- input;
+}
+
+module AddOne {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+}
+
+module Double {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input * 2;
+}
+
+module MergeCombine {
+  input: reads Int;
+  output: writes Int;
+
+  $s:Source -> AddOne -> output;
+  $s -> Double -> output;
+  merge output: |pending, incoming| pending + incoming;
+
+  examples {
+    !input: 5 -> output: 16;
+  }
+}
+";
+
+state_struct!(MergeCombine, input: u64, output: u64);
+
+check_examples!(MergeCombine, MERGE_COMBINE_TEST_STRING, ee_for_nested_string);
+
+static SPLIT_OUTPUT_TEST_STRING: &str = "
+module Pairer {
+  input: reads Int;
+  pair: writes (Int, Int);
+
+  input.onChange: pair <- (input, input + 1);
+}
+
+module Tens {
+  input: reads Int;
+  tens: writes Int;
+
+  input.onChange: tens <- input * 10;
+}
+
+module Hundreds {
+  input: reads Int;
+  hundreds: writes Int;
+
+  input.onChange: hundreds <- input + 100;
+}
+
+module SplitOutput {
+  input: reads Int;
+  tens: writes Int;
+  hundreds: writes Int;
+
+  Pairer -> (Tens, Hundreds);
+
+  examples {
+    !input: 5 -> tens: 50, hundreds: 106;
+  }
+}
+";
+
+state_struct!(SplitOutput, input: u64, tens: u64, hundreds: u64);
+
+check_examples!(SplitOutput, SPLIT_OUTPUT_TEST_STRING, ee_for_nested_string);
+
+static SPLIT_HANDLE_TEST_STRING: &str = "
+module Tens {
+  input: reads Int;
+  tens: writes Int;
+
+  input.onChange: tens <- input * 10;
+}
+
+module Hundreds {
+  input: reads Int;
+  hundreds: writes Int;
+
+  input.onChange: hundreds <- input + 100;
+}
+
+module SplitHandle {
+  input: reads (Int, Int);
+  tens: writes Int;
+  hundreds: writes Int;
+
+  input -> (Tens, Hundreds);
+
+  examples {
+    !input: (3, 4) -> tens: 30, hundreds: 104;
+  }
+}
+";
+
+state_struct!(SplitHandle, input: (u64, u64), tens: u64, hundreds: u64);
+
+check_examples!(SplitHandle, SPLIT_HANDLE_TEST_STRING, ee_for_nested_string);
+
+static CONSTANT_SOURCE_TEST_STRING: &str = "
+module Scale {
+  factor: reads Int;
+  input: reads Int;
+  output: writes Int;
+
+  factor.onChange: output <- input * factor;
+  input.onChange: output <- input * factor;
+}
+
+module ConstantSource {
+  input: reads Int;
+  output: writes Int;
+
+  3 -> $s:Scale.factor;
+  input -> $s.input;
+
+  examples {
+    !input: 5 -> output: 15;
+    !input: 7 -> output: 21;
+  }
+}
+";
+
+state_struct!(ConstantSource, input: u64, output: u64);
+
+check_examples!(ConstantSource, CONSTANT_SOURCE_TEST_STRING, ee_for_nested_string);
+
+static TRANSFORM_TEST_STRING: &str = "
+module Pairer {
+  input: reads Int;
+  pair: writes (Int, Int);
+
+  input.onChange: pair <- (input, input + 1);
+}
+
+module Subtract {
+  input: reads (Int, Int);
+  difference: writes Int;
+
+  input.onChange: difference <- input.0 - input.1;
+}
+
+module TransformChain {
+  input: reads Int;
+  output: writes Int;
+
+  input -> |n| n + 1 -> |n| n * 2 -> Pairer -> |x| (x.1 * 2, x.0) -> Subtract -> |d| d * 10 -> output;
+
+  examples {
+    !input: 5 -> output: 140;
+  }
+}
+";
+
+state_struct!(TransformChain, input: u64, output: u64);
+
+check_examples!(TransformChain, TRANSFORM_TEST_STRING, ee_for_nested_string);
+
+static SWITCH_TEST_STRING: &str = "
+module AddOne {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+}
+
+module Double {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input * 2;
+}
+
+module Classify {
+  input: reads Int;
+  small: writes Int;
+  large: writes Int;
+
+  switch input {
+    |x| x < 10 -> AddOne -> small;
+    _ -> Double -> large;
+  }
+
+  examples {
+    !input: 5 -> small: 6;
+    !input: 50 -> large: 100;
+  }
+}
+";
+
+state_struct!(Classify, input: u64, small: u64, large: u64);
+
+check_examples!(Classify, SWITCH_TEST_STRING, ee_for_nested_string);
+
+static OTHER_OUTPUTS_TEST_STRING: &str = "
+module Checked {
+  input: reads Int;
+  result: writes Int;
+  error: writes Int;
+
+  input.onChange: {
+    if input > 10 {
+      error <- input;
+    } else {
+      result <- input;
+    }
+  }
+}
+
+module AddOne {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+}
+
+module Double {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input * 2;
+}
+
+module Route {
+  input: reads Int;
+  small: writes Int;
+  large: writes Int;
+
+  input -> $c:Checked;
+  switch $c {
+    error -> Double -> large;
+    _ -> AddOne -> small;
+  }
+
+  examples {
+    !input: 5 -> small: 6;
+    !input: 50 -> large: 100;
+  }
+}
+";
+
+state_struct!(Route, input: u64, small: u64, large: u64);
+
+check_examples!(Route, OTHER_OUTPUTS_TEST_STRING, ee_for_nested_string);
+
+static FEEDBACK_TEST_STRING: &str = "
+module Loop {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+}
+
+module Spin {
+  input: reads Int;
+
+  $l:Loop -> $l;
+  input -> $l;
+  iterate 5;
+
+  examples {
+    !input: 1 -> input: 1;
+  }
+}
+";
+
+state_struct!(Spin, input: u64);
+
+#[test]
+fn feedback_loops_report_not_converging() -> CodegenStatus {
+  ee_for_nested_string(FEEDBACK_TEST_STRING, |ee: ExecutionEngine, _| {
+    unsafe {
+      let run_function: JitFunction<SpinRunFunc> = ee.get_function("Spin_run_examples").unwrap();
+      assert_eq!(run_function.call(), 1);
+    }
+  })
+}
+
+static REPORT_TEST_STRING: &str = "
+module Report {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+
+  examples {
+    !input: 1 -> output: 5;
+    !input: 2 -> output: 3;
+    !input: 3 -> output: 0;
+  }
+}
+";
+
+state_struct!(Report, input: u64, output: u64);
+
+#[test]
+fn every_example_runs_after_a_failure() -> CodegenStatus {
+  ee_for_string(REPORT_TEST_STRING, |ee: ExecutionEngine, _| {
+    unsafe {
+      let run_function: JitFunction<ReportRunFunc> = ee.get_function("Report_run_examples").unwrap();
+      assert_eq!(run_function.call(), 2);
+    }
+  })
+}
+
+static ACCESSOR_TEST_STRING: &str = "
+module Accessors {
+  input: reads Int;
+  output: writes Int;
+  pair: writes (Int, Int);
+
+  input.onChange: {
+    output <- input + 1;
+    pair <- (input, input + 1);
+  }
+}
+";
+
+state_struct!(Accessors, input: u64, output: u64, pair: (u64, u64));
+
+#[derive(Debug, PartialEq)]
+#[repr(C)]
+pub struct AccessorsPair(u64, u64);
+
+type AccessorsWriteFunc = unsafe extern "C" fn(*mut AccessorsState, u64);
+type AccessorsReadFunc = unsafe extern "C" fn(*mut AccessorsState) -> u64;
+type AccessorsReadPairFunc = unsafe extern "C" fn(*mut AccessorsState, *mut AccessorsPair);
+type AccessorsPendingFunc = unsafe extern "C" fn(*mut AccessorsState) -> bool;
+
+#[test]
+fn accessors_hide_the_state_layout() -> CodegenStatus {
+  ee_for_string(ACCESSOR_TEST_STRING, |ee: ExecutionEngine, _| {
+    unsafe {
+      let init: JitFunction<AccessorsPrepFunc> = ee.get_function("Accessors_init").unwrap();
+      let update: JitFunction<AccessorsFunc> = ee.get_function("Accessors_update").unwrap();
+      let deinit: JitFunction<AccessorsFunc> = ee.get_function("Accessors_deinit").unwrap();
+      let write_input: JitFunction<AccessorsWriteFunc> = ee.get_function("Accessors_write_input").unwrap();
+      let read_input: JitFunction<AccessorsReadFunc> = ee.get_function("Accessors_read_input").unwrap();
+      let read_output: JitFunction<AccessorsReadFunc> = ee.get_function("Accessors_read_output").unwrap();
+      let read_pair: JitFunction<AccessorsReadPairFunc> = ee.get_function("Accessors_read_pair").unwrap();
+      let has_pending: JitFunction<AccessorsPendingFunc> = ee.get_function("Accessors_has_pending").unwrap();
+
+      let state = init.call();
+      assert!(!has_pending.call(state));
+      write_input.call(state, 7);
+      assert!(has_pending.call(state));
+      // the write is pending, not current
+      assert_eq!(read_input.call(state), 0);
+      while has_pending.call(state) {
+        update.call(state);
+      }
+      assert_eq!(read_input.call(state), 7);
+      assert_eq!(read_output.call(state), 8);
+      let mut pair = AccessorsPair(0, 0);
+      read_pair.call(state, &mut pair);
+      assert_eq!(pair, AccessorsPair(7, 8));
+      deinit.call(state);
+    }
+  })
+}
+
+type RunFunc<S> = unsafe extern "C" fn(*mut S, u64) -> i64;
+
+#[test]
+fn run_drives_updates_to_a_fixed_point() -> CodegenStatus {
+  ee_for_string(ACCESSOR_TEST_STRING, |ee: ExecutionEngine, _| {
+    unsafe {
+      let init: JitFunction<AccessorsPrepFunc> = ee.get_function("Accessors_init").unwrap();
+      let write_input: JitFunction<AccessorsWriteFunc> = ee.get_function("Accessors_write_input").unwrap();
+      let run: JitFunction<RunFunc<AccessorsState>> = ee.get_function("Accessors_run").unwrap();
+      let state = init.call();
+      assert_eq!(run.call(state, 0), 0);
+      write_input.call(state, 7);
+      assert_eq!(run.call(state, 0), 2);
+      assert_eq!((*state).output, 8);
+    }
+  })?;
+  ee_for_nested_string(FEEDBACK_TEST_STRING, |ee: ExecutionEngine, _| {
+    unsafe {
+      let init: JitFunction<SpinPrepFunc> = ee.get_function("Spin_init").unwrap();
+      let write_input: JitFunction<unsafe extern "C" fn(*mut SpinState, u64)> = ee.get_function("Spin_write_input").unwrap();
+      let run: JitFunction<RunFunc<SpinState>> = ee.get_function("Spin_run").unwrap();
+      let state = init.call();
+      write_input.call(state, 1);
+      let has_pending: JitFunction<unsafe extern "C" fn(*mut SpinState) -> bool> = ee.get_function("Spin_has_pending").unwrap();
+      assert_eq!(run.call(state, 3), RUN_NOT_CONVERGED);
+      assert!(has_pending.call(state));
+    }
+  })
+}
+
+static NESTED_FEEDBACK_TEST_STRING: &str = "
+module Loop {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+}
+
+module Spin {
+  input: reads Int;
+
+  $l:Loop -> $l;
+  input -> $l;
+  iterate 5;
+}
+
+module Outer {
+  input: reads Int;
+
+  input -> Spin;
+}
+";
+
+#[test]
+fn submodules_that_do_not_settle_leave_their_parent_pending_until_its_next_update() -> CodegenStatus {
+  let context = Context::create();
+  let (_, mut ast) = parser::parse(NESTED_FEEDBACK_TEST_STRING).unwrap();
+  resolve_module_graphs(&mut ast);
+  let modules = ast::modules(&ast);
+  let mut jit_info = JitInfo::new();
+  // Outer only brings in its direct submodules, so Spin's Loop is built separately.
+  codegen(&context, &mut jit_info, modules[0])?;
+  codegen(&context, &mut jit_info, modules[2])?;
+  let ee = jit_info.execution_engine.unwrap();
+  unsafe {
+    let init: JitFunction<unsafe extern "C" fn() -> *mut u8> = ee.get_function("Outer_init").unwrap();
+    let write_input: JitFunction<unsafe extern "C" fn(*mut u8, u64)> = ee.get_function("Outer_write_input").unwrap();
+    let run: JitFunction<RunFunc<u8>> = ee.get_function("Outer_run").unwrap();
+    let update: JitFunction<unsafe extern "C" fn(*mut u8)> = ee.get_function("Outer_update").unwrap();
+    let has_pending: JitFunction<unsafe extern "C" fn(*mut u8) -> bool> = ee.get_function("Outer_has_pending").unwrap();
+    let state = init.call();
+    write_input.call(state, 1);
+    // Outer_run gives up as soon as Spin does, rather than going round again
+    assert_eq!(run.call(state, 0), RUN_NOT_CONVERGED);
+    assert!(has_pending.call(state));
+    // but the next round starts afresh
+    update.call(state);
+    assert!(!has_pending.call(state));
+    assert_eq!(run.call(state, 0), 0);
+    write_input.call(state, 2);
+    assert_eq!(run.call(state, 0), RUN_NOT_CONVERGED);
+  }
+  Ok(())
+}
+
+extern "C" fn record_output(userdata: *mut u8, value: u64) {
+  unsafe { (*(userdata as *mut Vec<u64>)).push(value) }
+}
+
+type AccessorsOnOutputFunc = unsafe extern "C" fn(*mut AccessorsState, extern "C" fn(*mut u8, u64), *mut u8);
+
+#[test]
+fn callbacks_see_each_new_output() -> CodegenStatus {
+  ee_for_string(ACCESSOR_TEST_STRING, |ee: ExecutionEngine, _| {
+    unsafe {
+      let init: JitFunction<AccessorsPrepFunc> = ee.get_function("Accessors_init").unwrap();
+      let write_input: JitFunction<AccessorsWriteFunc> = ee.get_function("Accessors_write_input").unwrap();
+      let run: JitFunction<RunFunc<AccessorsState>> = ee.get_function("Accessors_run").unwrap();
+      let on_output: JitFunction<AccessorsOnOutputFunc> = ee.get_function("Accessors_on_output").unwrap();
+      assert!(ee.get_function::<AccessorsOnOutputFunc>("Accessors_on_input").is_err());
+
+      let mut outputs: Vec<u64> = Vec::new();
+      let state = init.call();
+      on_output.call(state, record_output, &mut outputs as *mut Vec<u64> as *mut u8);
+      write_input.call(state, 7);
+      run.call(state, 0);
+      write_input.call(state, 9);
+      run.call(state, 0);
+      assert_eq!(outputs, vec!(8, 10));
+    }
+  })
+}
+
+static RUN_TEST_STRING: &str = "
+module Limit {
+  input: reads Int;
+  output: writes Int;
+  error: writes Int;
+
+  input.onChange: {
+    if input > 10 {
+      error <- input;
+    }
+    output <- input;
+  }
+}
+";
+
+type MainFunc = unsafe extern "C" fn(i32, *const *const c_char) -> i32;
+
+// Builds the last module in defn with a main for bindings, and calls that main with args.
+fn run_main_for_string(defn: &str, bindings: &[&str], args: &[&str]) -> CodegenResult<i32> {
+  let context = Context::create();
+  let (_, ast) = parser::parse(defn).unwrap();
+  let modules = ast::modules(&ast);
+  let module = modules[modules.len() - 1];
+  let mut jit_info = JitInfo::new();
+  let _cg_modules = codegen(&context, &mut jit_info, module)?;
+  let bindings = bindings.iter().map(|binding| Binding::parse(binding).unwrap()).collect();
+  let _run_main = main_for_run(&context, &mut jit_info, module, &bindings)?;
+  let ee = jit_info.execution_engine.unwrap();
+
+  let args: Vec<CString> = args.iter().map(|arg| CString::new(*arg).unwrap()).collect();
+  let argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+  unsafe {
+    let main: JitFunction<MainFunc> = ee.get_function("main").unwrap();
+    Ok(main.call(argv.len() as i32, argv.as_ptr()))
+  }
+}
+
+#[test]
+fn run_main_exits_non_zero_when_error_is_written() -> CodegenStatus {
+  let bindings = ["input=arg:1", "output=stdout"];
+  assert_eq!(run_main_for_string(RUN_TEST_STRING, &bindings, &["Limit_run", "7"])?, 0);
+  assert_eq!(run_main_for_string(RUN_TEST_STRING, &bindings, &["Limit_run", "42"])?, 1);
+  assert_eq!(run_main_for_string(RUN_TEST_STRING, &bindings, &["Limit_run"])?, 2);
+  assert_eq!(run_main_for_string(RUN_TEST_STRING, &["input=arg:1", "error=stdout"], &["Limit_run", "42"])?, 0);
+  Ok(())
+}
+
+// A file in the temp directory with contents, named after the test so parallel tests don't share one.
+fn temp_file(name: &str, contents: &str) -> String {
+  let path = std::env::temp_dir().join(format!("skunk_{}_{}", std::process::id(), name));
+  std::fs::write(&path, contents).unwrap();
+  path.to_str().unwrap().to_string()
+}
+
+extern "C" {
+  fn dup(fd: i32) -> i32;
+  fn dup2(old_fd: i32, new_fd: i32) -> i32;
+}
+
+// Runs func with stdin reading contents; main reads stdin-lines from file descriptor 0.
+fn with_stdin<T>(name: &str, contents: &str, func: impl FnOnce() -> T) -> T {
+  use std::os::unix::io::AsRawFd;
+  let path = temp_file(name, contents);
+  let file = std::fs::File::open(&path).unwrap();
+  let result = unsafe {
+    let saved = dup(0);
+    dup2(file.as_raw_fd(), 0);
+    let result = func();
+    dup2(saved, 0);
+    result
+  };
+  std::fs::remove_file(&path).unwrap();
+  result
+}
+
+#[test]
+fn run_main_reads_stdin_lines() -> CodegenStatus {
+  let bindings = ["input=stdin-lines", "output=stdout"];
+  assert_eq!(with_stdin("stdin_settles", "3\n5\n7", || run_main_for_string(RUN_TEST_STRING, &bindings, &["Limit_run"]))?, 0);
+  assert_eq!(with_stdin("stdin_error", "3\n42\n5\n", || run_main_for_string(RUN_TEST_STRING, &bindings, &["Limit_run"]))?, 1);
+  assert_eq!(with_stdin("stdin_not_a_number", "3\n4x\n", || run_main_for_string(RUN_TEST_STRING, &bindings, &["Limit_run"]))?, 2);
+  assert_eq!(with_stdin("stdin_empty", "", || run_main_for_string(RUN_TEST_STRING, &bindings, &["Limit_run"]))?, 0);
+  Ok(())
+}
+
+#[test]
+fn run_main_reads_files() -> CodegenStatus {
+  for (name, contents, expected) in &[("file_settles", "7\n", 0), ("file_error", "42", 1), ("file_not_a_number", "seven\n", 2), ("file_trailing_text", "7\n8\n", 2)] {
+    let path = temp_file(name, contents);
+    let bindings = [format!("input=file:{}", path), "output=stdout".to_string()];
+    let bindings: Vec<&str> = bindings.iter().map(|binding| binding.as_str()).collect();
+    let result = run_main_for_string(RUN_TEST_STRING, &bindings, &["Limit_run"]);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(result?, *expected, "{}", name);
+  }
+  let missing = std::env::temp_dir().join(format!("skunk_{}_missing", std::process::id()));
+  let bindings = [format!("input=file:{}", missing.to_str().unwrap()), "output=stdout".to_string()];
+  let bindings: Vec<&str> = bindings.iter().map(|binding| binding.as_str()).collect();
+  assert_eq!(run_main_for_string(RUN_TEST_STRING, &bindings, &["Limit_run"])?, 2);
+  Ok(())
+}
+
 static NEW_MEMREGION_TEST_STRING: &str = "
 module ModuleWithNew {
   bar: writes MemRegion;
@@ -413,6 +1227,34 @@ fn jit_syntax_test_codegen_runs() -> CodegenStatus {
   }) 
 }
 
+static NESTED_TUPLE_TEST_STRING: &str = "
+module NestedTuple {
+  input: reads ((String, Int), Int);
+  small: reads ((Char, Char), Int);
+  output: writes ((String, Int), Int);
+  inner: writes (String, Int);
+  offset: writes Int;
+  second: writes Char;
+
+  input.onChange: {
+    inner <- input.0;
+    offset <- input.0.1 + input.1;
+    output <- ((input.0.0, input.1), input.0.1);
+  }
+
+  small.onChange: second <- small.0.1;
+
+  examples {
+    !input: ((\"abc\", 1), 2) -> inner: (\"abc\", 1), offset: 3, output: ((\"abc\", 2), 1);
+    !small: (('a', 'b'), 7) -> second: 'b';
+  }
+}
+";
+
+state_struct!(NestedTuple, input: (*const (MemRegion, u64), u64), small: ((u8, u8), u64), output: (*const (MemRegion, u64), u64), inner: *const (MemRegion, u64), offset: u64, second: u8);
+
+check_examples!(NestedTuple, NESTED_TUPLE_TEST_STRING);
+
 static WHILE_TEST_STRING: &str = "
 module WhileTest {
   len: reads Int;
@@ -449,6 +1291,70 @@ fn jit_while_test_codegen_runs() -> CodegenStatus {
   })
 }
 
+static SCOPED_LOCALS_TEST_STRING: &str = "
+module ScopedLocals {
+  inp: reads Int;
+  out: writes Int;
+
+  inp.onChange: {
+    let x = inp;
+    if inp > 5 {
+      let x = 100;
+      x = x + 1;
+    }
+    while x < 3 {
+      x = x + 1;
+    }
+    out <- x;
+  }
+
+  examples {
+    !inp: 1 -> out: 3;
+    !inp: 7 -> out: 7;
+  }
+}
+";
+
+state_struct!(ScopedLocals, inp: u64, out: u64);
+
+check_examples!(ScopedLocals, SCOPED_LOCALS_TEST_STRING);
+
+static LEAKED_LOCAL_TEST_STRING: &str = "
+module LeakedLocal {
+  inp: reads Int;
+  out: writes Int;
+
+  inp.onChange: {
+    if inp > 5 {
+      let x = 1;
+    }
+    out <- x;
+  }
+}
+";
+
+#[test]
+fn block_locals_do_not_leak() {
+  assert_eq!(ee_for_string(LEAKED_LOCAL_TEST_STRING, |_, _| ()), Err(CodegenError::BadReadFieldName("x".to_string())));
+}
+
+static UNDEFINED_LOCAL_TEST_STRING: &str = "
+module UndefinedLocal {
+  inp: reads Int;
+  out: writes Int;
+
+  inp.onChange: {
+    x = inp;
+    out <- inp;
+  }
+}
+";
+
+#[test]
+fn assignment_requires_let() {
+  assert_eq!(ee_for_string(UNDEFINED_LOCAL_TEST_STRING, |_, _| ()), Err(CodegenError::UndefinedLocal("x".to_string())));
+}
+
 static EXAMPLES_TEST_STRING: &str = "
 module ExampleTest {
   a: reads Int;
@@ -16,24 +16,22 @@ pub struct DebugState<'ctx> {
 // }
 impl <'ctx> DebugState<'ctx> {
   fn llvm_type(cg: &CodegenState<'ctx>) -> PointerType<'ctx> {
-    let struct_type = cg.context.struct_type(&[cg.context.i8_type().ptr_type(AddressSpace::Generic).into(), cg.context.i32_type().into(), cg.context.i32_type().into()], false);
+    let struct_type = cg.context.struct_type(&[cg.char_ptr_type().into(), cg.size_type.into(), cg.size_type.into()], false);
     struct_type.ptr_type(AddressSpace::Generic)
   }
   pub fn new(cg: &mut CodegenState<'ctx>, explode: bool) -> Self {
     // Allocate struct in memory & bitcast to appropriate type
     let ds_type = DebugState::llvm_type(cg);
-    // let debug_size = ds_type.get_element_type().size_of().unwrap();
-    // let debug_size32 = cg.builder.build_int_cast(debug_size, cg.context.i32_type(), "debug_size32");
-    let debug_size32 = cg.uint32_const(24);
-    let raw_mem = malloc(cg, debug_size32.into(), "raw_alloc");
+    let debug_size = ds_type.get_element_type().into_struct_type().size_of().unwrap();
+    let raw_mem = malloc(cg, debug_size, "raw_alloc");
     let ptr = cg.builder.build_bitcast(raw_mem, ds_type, "state_ptr").into_pointer_value();
     
     // Allocate buffer in memory (500 bytes to start with) and set struct values appropriately
-    let buffer = malloc(cg, cg.uint32_const(500).into(), "buffer").into_pointer_value();
+    let buffer = malloc(cg, cg.size_const(500), "buffer").into_pointer_value();
     let me = Self { state_ptr: ptr, explode, current_indent: 0, at_line_start: true };
     DebugState::set_buffer(&me, cg, buffer);
-    DebugState::set_allocated(&me, cg, cg.uint32_const(500));
-    DebugState::set_position(&me, cg, cg.uint32_const(0));
+    DebugState::set_allocated(&me, cg, cg.size_const(500));
+    DebugState::set_position(&me, cg, cg.size_const(0));
     me
   }
 
@@ -113,16 +111,15 @@ impl <'ctx> DebugState<'ctx> {
     let me = DebugState { state_ptr: ptr, explode: self.explode, current_indent: self.current_indent, at_line_start: self.at_line_start };
     let allocated = DebugState::get_allocated(&me, cg);
     let position = DebugState::get_position(&me, cg);
-    let position64 = cg.builder.build_int_cast(position, cg.context.i64_type(), "position64");
     let size = cg.builder.build_int_sub(allocated, position, "size");
     let buffer = DebugState::get_buffer(&me, cg);
-    let buffer_as_int = cg.builder.build_ptr_to_int(buffer, cg.context.i64_type(), "buffer_as_int");
-    let write_pos_as_int = cg.builder.build_int_add(buffer_as_int, position64, "write_pos_as_int");
-    let write_pos = cg.builder.build_int_to_ptr(write_pos_as_int, cg.char_ptr_type(), "write_pos");
+    let write_pos = unsafe { cg.builder.build_in_bounds_gep(buffer, &[position], "write_pos") };
     let format_str = function.get_nth_param(1).unwrap();
 
     cg.builder.build_call(get_va_start(cg), &[va_list_as_char_ptr.into()], "va_start");
-    let potentially_written = cg.builder.build_call(vsnprintf, &[write_pos.into(), size.into(), format_str.into(), va_list_alloca.into()], "potentially_written").try_as_basic_value().left().unwrap().into_int_value();
+    let va_list = va_list_argument(cg, va_list_alloca);
+    let potentially_written = cg.builder.build_call(vsnprintf, &[write_pos.into(), size.into(), format_str.into(), va_list.into()], "potentially_written").try_as_basic_value().left().unwrap().into_int_value();
+    let potentially_written = cg.builder.build_int_z_extend(potentially_written, cg.size_type, "potentially_written");
     cg.builder.build_call(get_va_end(cg), &[va_list_as_char_ptr.into()], "va_end");
 
     let test = cg.builder.build_int_compare(IntPredicate::UGT, potentially_written, size, "test");
@@ -131,25 +128,25 @@ impl <'ctx> DebugState<'ctx> {
     cg.builder.build_conditional_branch(test, too_big, ok);
     
     cg.builder.position_at_end(too_big);
-    let double_alloc = cg.builder.build_int_mul(allocated, cg.uint32_const(2), "double_alloc");
+    let double_alloc = cg.builder.build_int_mul(allocated, cg.size_const(2), "double_alloc");
     let new_allocated = cg.builder.build_int_add(double_alloc, potentially_written, "new_allocated");
     let realloc = get_realloc(cg);
     let new_buffer = cg.builder.build_call(realloc, &[buffer.into(), new_allocated.into()], "new_buffer").try_as_basic_value().left().unwrap().into_pointer_value();
     DebugState::set_allocated(&me, cg, new_allocated);
     DebugState::set_buffer(&me, cg, new_buffer);
-    let new_buffer_as_int = cg.builder.build_ptr_to_int(new_buffer, cg.context.i64_type(), "new_buffer_as_int");
-    let new_write_pos_as_int = cg.builder.build_int_add(new_buffer_as_int, position64, "new_write_pos_as_int");
-    let new_write_pos = cg.builder.build_int_to_ptr(new_write_pos_as_int, cg.char_ptr_type(), "new_write_pos");
+    let new_write_pos = unsafe { cg.builder.build_in_bounds_gep(new_buffer, &[position], "new_write_pos") };
     let new_size = cg.builder.build_int_sub(new_allocated, position, "new_size");
 
     cg.builder.build_call(get_va_start(cg), &[va_list_as_char_ptr.into()], "va_start");
-    let written = cg.builder.build_call(vsnprintf, &[new_write_pos.into(), new_size.into(), format_str.into(), va_list_alloca.into()], "written").try_as_basic_value().left().unwrap().into_int_value();
+    let va_list = va_list_argument(cg, va_list_alloca);
+    let written = cg.builder.build_call(vsnprintf, &[new_write_pos.into(), new_size.into(), format_str.into(), va_list.into()], "written").try_as_basic_value().left().unwrap().into_int_value();
+    let written = cg.builder.build_int_z_extend(written, cg.size_type, "written");
     cg.builder.build_call(get_va_end(cg), &[va_list_as_char_ptr.into()], "va_end");
 
     cg.builder.build_unconditional_branch(ok);
 
     cg.builder.position_at_end(ok);
-    let written_phi = cg.builder.build_phi(cg.context.i32_type(), "actual_written");
+    let written_phi = cg.builder.build_phi(cg.size_type, "actual_written");
     written_phi.add_incoming(&[(&potentially_written, block), (&written, too_big)]);
     let new_position = cg.builder.build_int_add(position, written_phi.as_basic_value().into_int_value(), "new_position");
     DebugState::set_position(&me, cg, new_position);
@@ -216,11 +213,10 @@ impl <'ctx> Printer<'ctx> for DebugState<'ctx> {
 }
 
 pub fn print_if_not_null<'ctx>(cg: &mut CodegenState<'ctx>, printer: &mut dyn Printer<'ctx>, value: PointerValue<'ctx>, null_action: &dyn Fn(&mut CodegenState<'ctx>, &mut dyn Printer<'ctx>) -> CodegenStatus, action: &dyn Fn(&mut CodegenState<'ctx>, &mut dyn Printer<'ctx>) -> CodegenStatus) -> CodegenStatus {
-  let value_as_int = cg.builder.build_ptr_to_int(value, cg.context.i64_type(), "value_as_int");
   let is_null = append_new_block(cg, "is_null")?;
   let not_null = append_new_block(cg, "not_null")?;
   let finally = append_new_block(cg, "finally")?;
-  let test = cg.builder.build_int_compare(IntPredicate::EQ, value_as_int, cg.uint_const(0), "test");
+  let test = cg.builder.build_is_null(value, "test");
   cg.builder.build_conditional_branch(test, is_null, not_null);
   cg.builder.position_at_end(is_null);
   null_action(cg, printer)?;
@@ -80,65 +80,79 @@ pub fn examples_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast::Mo
   let return_val = cg.builder.build_call(callable, &[example_check_fn.get_nth_param(1).unwrap()], "return_val").try_as_basic_value().left().unwrap();
   cg.builder.build_return(Some(&return_val));
 
+  // The line each example is on, for reports.
+  let lines: Vec<IntValue> = module.examples.examples.iter().map(|example| cg.uint_const(example.position.line as u64)).collect();
+  let example_lines_global = cg.module.add_global(cg.context.i64_type().array_type(num_examples as u32), None, &(module.name.clone() + "__example_lines"));
+  example_lines_global.set_initializer(&cg.context.i64_type().const_array(&lines));
+
   // create a function to run a single example end-to-end ("<module>__run_example").
   let example_run_fn = cg.module.add_function(&(module.name.clone() + "__run_example"), cg.context.i64_type().fn_type(&[cg.context.i64_type().into()], false), None);
   let entry_block = cg.context.append_basic_block(example_run_fn, "entry");
   cg.builder.position_at_end(entry_block);
   let state_ptr = cg.builder.build_call(example_prep_fn, &[example_run_fn.get_first_param().unwrap()], "state_ptr").try_as_basic_value().left().unwrap().into_pointer_value();
 
-  let run_update = cg.context.append_basic_block(example_run_fn, "run_update");
+  let not_converged = cg.context.append_basic_block(example_run_fn, "not_converged");
   let after_update = cg.context.append_basic_block(example_run_fn, "after_update");
 
-  let bitfield_ptr = cg.module_bitfield_ptr(module, state_ptr)?;
-  let bitfield = cg.builder.build_load(bitfield_ptr, "bitfield").into_int_value();
-  let test = cg.builder.build_int_compare(IntPredicate::NE, bitfield, cg.uint_const(0), "test");
-  cg.builder.build_conditional_branch(test, run_update, after_update);
+  let limit = update_limit(module);
+  let run_fn = cg.module.get_function(&(module.name.clone() + "_run")).unwrap();
+  let steps = cg.builder.build_call(run_fn, &[state_ptr.into(), cg.uint_const(limit).into()], "steps").try_as_basic_value().left().unwrap().into_int_value();
+  let converged = cg.builder.build_int_compare(IntPredicate::SGE, steps, cg.uint_const(0), "converged");
+  cg.builder.build_conditional_branch(converged, after_update, not_converged);
 
-  cg.builder.position_at_end(run_update);
-  let update_fn = cg.module.get_function(&(module.name.clone() + "_update")).unwrap();
-  cg.builder.build_call(update_fn, &[state_ptr.into()], "_"); 
+  // Report the example as failing rather than spinning forever; the status is whatever is still pending.
+  cg.builder.position_at_end(not_converged);
+  let bitfield_ptr = cg.module_bitfield_ptr(module, state_ptr)?;
   let bitfield = cg.builder.build_load(bitfield_ptr, "bitfield").into_int_value();
-  let test = cg.builder.build_int_compare(IntPredicate::NE, bitfield, cg.uint_const(0), "test");
-  cg.builder.build_conditional_branch(test, run_update, after_update);
+  let example_idx = example_run_fn.get_first_param().unwrap().into_int_value();
+  let line_ptr = unsafe { cg.builder.build_gep(example_lines_global.as_pointer_value(), &[cg.uint_const(0), example_idx], "line_ptr") };
+  let line = cg.builder.build_load(line_ptr, "line");
+  let printf = get_printf(cg);
+  let format = cg.global_string(&format!("{} example %lld (line %lld): FAILED\n    updates still pending after %lld steps\n", module.name));
+  cg.builder.build_call(printf, &[format.into(), example_idx.into(), line.into(), cg.uint_const(limit).into()], "_");
+  let state_ptr_as_char_ptr = cg.builder.build_bitcast(state_ptr, cg.context.i8_type().ptr_type(AddressSpace::Generic), "state_ptr_as_char_ptr").into_pointer_value();
+  free(cg, state_ptr_as_char_ptr);
+  cg.builder.build_return(Some(&bitfield));
 
   cg.builder.position_at_end(after_update);
   let status_code = cg.builder.build_call(example_check_fn, &[example_run_fn.get_first_param().unwrap(), state_ptr.into()], "status_code").try_as_basic_value().left().unwrap();
   cg.builder.build_return(Some(&status_code));
 
   // create a function to run all examples in a module ("<module>_run_examples").
-  // This returns 1 on error, or 0 on success.
+  // Every example is run and reported, and this returns how many failed.
   let examples_fn = cg.module.add_function(&(module.name.clone() + "_run_examples"), cg.context.i64_type().fn_type(&[], false), None);
   let entry_block = cg.context.append_basic_block(examples_fn, "entry");
   cg.builder.position_at_end(entry_block);
   let count = cg.builder.build_call(example_count_fn, &[], "count").try_as_basic_value().left().unwrap().into_int_value();
-  let count_alloca = cg.builder.build_alloca(cg.context.i64_type(), "count_alloca");
-  cg.builder.build_store(count_alloca, count);
+  let idx_alloca = cg.builder.build_alloca(cg.context.i64_type(), "idx_alloca");
+  cg.builder.build_store(idx_alloca, cg.uint_const(0));
+  let failures_alloca = cg.builder.build_alloca(cg.context.i64_type(), "failures_alloca");
+  cg.builder.build_store(failures_alloca, cg.uint_const(0));
 
+  let test_block = cg.context.append_basic_block(examples_fn, "test");
   let run_example = cg.context.append_basic_block(examples_fn, "run_example");
-  let run_example_2 = cg.context.append_basic_block(examples_fn, "run_example_2");
   let return_block = cg.context.append_basic_block(examples_fn, "return_block");
+  cg.builder.build_unconditional_branch(test_block);
 
-  let test = cg.builder.build_int_compare(IntPredicate::EQ, count, cg.uint_const(0), "test");
-  cg.builder.build_conditional_branch(test, return_block, run_example);
+  cg.builder.position_at_end(test_block);
+  let idx = cg.builder.build_load(idx_alloca, "idx").into_int_value();
+  let more = cg.builder.build_int_compare(IntPredicate::ULT, idx, count, "more");
+  cg.builder.build_conditional_branch(more, run_example, return_block);
 
   cg.builder.position_at_end(run_example);
-  let count = cg.builder.build_load(count_alloca, "count").into_int_value();
-  let new_count = cg.builder.build_int_sub(count, cg.uint_const(1), "new_count");
-  cg.builder.build_store(count_alloca, new_count);
-  let result = cg.builder.build_call(example_run_fn, &[new_count.into()], "result").try_as_basic_value().left().unwrap().into_int_value();
-  let result_test = cg.builder.build_int_compare(IntPredicate::EQ, result, cg.uint_const(0), "result_test");
-  cg.builder.build_conditional_branch(result_test, run_example_2, return_block);
-  
-  cg.builder.position_at_end(run_example_2);
-  let test = cg.builder.build_int_compare(IntPredicate::EQ, new_count, cg.uint_const(0), "test");
-  cg.builder.build_conditional_branch(test, return_block, run_example);
+  let result = cg.builder.build_call(example_run_fn, &[idx.into()], "result").try_as_basic_value().left().unwrap().into_int_value();
+  let failed = cg.builder.build_int_compare(IntPredicate::NE, result, cg.uint_const(0), "failed");
+  let failed = cg.builder.build_int_z_extend(failed, cg.context.i64_type(), "failed");
+  let failures = cg.builder.build_load(failures_alloca, "failures").into_int_value();
+  let failures = cg.builder.build_int_add(failures, failed, "failures");
+  cg.builder.build_store(failures_alloca, failures);
+  let next_idx = cg.builder.build_int_add(idx, cg.uint_const(1), "next_idx");
+  cg.builder.build_store(idx_alloca, next_idx);
+  cg.builder.build_unconditional_branch(test_block);
 
   cg.builder.position_at_end(return_block);
-  let return_value = cg.builder.build_phi(cg.context.i64_type(), "return");
-  let success = cg.uint_const(0);
-  let failure = cg.uint_const(1);
-  return_value.add_incoming(&[(&success, entry_block), (&failure, run_example), (&success, run_example_2)]);
-  cg.builder.build_return(Some(&return_value.as_basic_value()));
+  let failures = cg.builder.build_load(failures_alloca, "failures");
+  cg.builder.build_return(Some(&failures));
   Ok(())
 }
 
@@ -156,9 +170,8 @@ pub fn example_prep_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast
 
   /*
   let module_size = module_type.size_of().unwrap();
-  let module_size32 = cg.builder.build_int_cast(module_size, cg.context.i32_type(), "module_size32");
-  let state_ptr_as_char_ptr = malloc(cg, module_size32.into(), "malloced-state").into_pointer_value();
-  memset(cg, state_ptr_as_char_ptr, cg.context.i8_type().const_zero(), module_size32);
+  let state_ptr_as_char_ptr = malloc(cg, module_size, "malloced-state").into_pointer_value();
+  memset(cg, state_ptr_as_char_ptr, cg.context.i8_type().const_zero(), module_size);
   let state_ptr = cg.builder.build_bitcast(state_ptr_as_char_ptr, module_ptr_type, "state_ptr").into_pointer_value();
   */
   let init_fn = cg.module.get_function(&format!("{}_init", &module.name)).unwrap();
@@ -166,19 +179,14 @@ pub fn example_prep_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast
   let state_alloca = cg.builder.build_alloca(module_ptr_type, "state_alloca");
   cg.builder.build_store(state_alloca, state_ptr);
 
+  // Only the updates from values the graph supplies at init are left pending.
+  let initial_bits = module.initial_values.iter().filter_map(|(name, _)| module.idx_for_field(name)).fold(0, |bits, idx| bits | (1 << idx));
   let bitfield_ptr = cg.module_bitfield_ptr(module, state_ptr)?;
-  cg.builder.build_store(bitfield_ptr, cg.uint_const(0));
+  cg.builder.build_store(bitfield_ptr, cg.uint_const(initial_bits));
 
 
   for (field, value_expression) in &example.inputs {
-    let value = expression_codegen(cg, module, state_alloca, &value_expression.value.value)?;
-    if value_expression.is_update {
-      let ptr = cg.update_ptr_for_field(module, state_ptr, field, UpdatePtrPurpose::WriteAndSet)?;
-      value.store(cg, &ptr)?;
-    } else {
-      let ptr = cg.read_ptr_for_field(module, state_ptr, field)?;
-      value.store(cg, &ptr)?;
-    }
+    example_input_codegen(cg, module, state_ptr, state_alloca, field, value_expression)?;
   }
   /*
   let dump = cg.module.get_function(&(module.name.clone() + "__dump")).unwrap();
@@ -189,10 +197,37 @@ pub fn example_prep_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast
   Ok(function)
 }
 
+// Sets field to the value of the expression, as a pending update if it's marked with !.
+fn example_input_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module, state_ptr: PointerValue<'ctx>, state_alloca: PointerValue<'ctx>, field: &str, value_expression: &ast::ExampleInfo) -> CodegenStatus {
+  let value = expression_codegen(cg, module, state_alloca, &value_expression.value.value)?;
+  if value_expression.is_update {
+    let ptr = cg.update_ptr_for_field(module, state_ptr, field, UpdatePtrPurpose::WriteAndSet)?;
+    value.store(cg, &ptr)
+  } else {
+    let ptr = cg.read_ptr_for_field(module, state_ptr, field)?;
+    value.store(cg, &ptr)
+  }
+}
+
+// A function that sets one field of an existing state the way an example input would, for the repl.
+pub fn write_function_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module, field: &str, value_expression: &ast::ExampleInfo, name: &str) -> CodegenResult<FunctionValue<'ctx>> {
+  let module_ptr_type = module.ir_type(cg).into_struct_type().ptr_type(AddressSpace::Generic);
+  let function = cg.module.add_function(name, cg.context.void_type().fn_type(&[module_ptr_type.into()], false), None);
+  let entry_block = cg.context.append_basic_block(function, "entry");
+  cg.builder.position_at_end(entry_block);
+
+  let state_ptr = function.get_first_param().unwrap().into_pointer_value();
+  let state_alloca = cg.builder.build_alloca(module_ptr_type, "state_alloca");
+  cg.builder.build_store(state_alloca, state_ptr);
+  example_input_codegen(cg, module, state_ptr, state_alloca, field, value_expression)?;
+  cg.builder.build_return(None);
+  Ok(function)
+}
+
 // Check functions:
 // (1) compare the members of the provided state struct to the expressions stored in the example description
-//    (1a) print an error message if the comparison fails
-// (2) free the provided state struct
+// (2) report the example as ok, or as failed along with the expected and actual value of each field that didn't match
+// (3) free the provided state struct
 pub fn example_check_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast::Module, example: &'ctx ast::Example, idx: usize) -> CodegenResult<FunctionValue<'ctx>> {
   let module_type = module.ir_type(cg).into_struct_type();
   let module_ptr_type = module_type.ptr_type(AddressSpace::Generic);
@@ -211,11 +246,15 @@ pub fn example_check_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx as
 
   // if a field doesn't match the result of an output expression for that field,
   // store this in the status_code against the field's bit offset.
-  for (field, value_expression) in &example.expected {
+  let mut expected: Vec<(&String, &ast::ExampleInfo)> = example.expected.iter().collect();
+  expected.sort_by_key(|(field, _)| *field);
+  let mut comparisons = Vec::new();
+  for (field, value_expression) in expected {
     let ptr = cg.read_ptr_for_field(module, state_ptr, field)?;
     let value = ptr.load(cg, "value_to_check")?;
     let test_value = expression_codegen(cg, module, state_alloca, &value_expression.value.value)?;
     let cmp = value.equals(cg, &test_value)?.into_int_value()?;
+    comparisons.push((field, value, test_value, cmp));
     let record_problem_block = cg.context.append_basic_block(function, "record_problem");
     let next_block = cg.context.append_basic_block(function, "next");
     cg.builder.build_conditional_branch(cmp, next_block, record_problem_block);
@@ -235,24 +274,39 @@ pub fn example_check_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx as
   let status_code = cg.builder.build_load(status_code_alloca, "status_code").into_int_value();
 
   let test = cg.builder.build_int_compare(IntPredicate::EQ, status_code, cg.uint_const(0), "test");
+  let passed = append_new_block(cg, "passed")?;
   let non_zero_status = append_new_block(cg, "non_zero_status")?;
   let finally = append_new_block(cg, "finally")?;
 
-  cg.builder.build_conditional_branch(test, finally, non_zero_status);
-  
-  cg.builder.position_at_end(non_zero_status);
+  cg.builder.build_conditional_branch(test, passed, non_zero_status);
 
   let printf = get_printf(cg);
-  let format = cg.global_string("\nUnexpected result for example %d. Output of this example:\n\n");
-  cg.builder.build_call(printf, &[format.into(), cg.uint32_const(idx as u32).into()], "_");
-
-
-  let dump = cg.module.get_function(&(module.name.clone() + "__dump")).unwrap();
-  cg.builder.build_call(dump, &[state_ptr.into()], "_");
-
-  let format = cg.global_string("\nerror bitfield: %ld\n\n");
-  cg.builder.build_call(printf, &[format.into(), status_code.into()], "_");
+  let report = format!("{} example {} (line {})", module.name, idx, example.position.line);
+  cg.builder.position_at_end(passed);
+  let format = cg.global_string(&format!("{}: ok\n", report));
+  cg.builder.build_call(printf, &[format.into()], "_");
+  cg.builder.build_unconditional_branch(finally);
 
+  cg.builder.position_at_end(non_zero_status);
+  let format = cg.global_string(&format!("{}: FAILED\n", report));
+  cg.builder.build_call(printf, &[format.into()], "_");
+  for (field, value, test_value, cmp) in comparisons {
+    let mismatch = append_new_block(cg, "mismatch")?;
+    let next = append_new_block(cg, "next")?;
+    cg.builder.build_conditional_branch(cmp, next, mismatch);
+
+    cg.builder.position_at_end(mismatch);
+    let mut printer = DebugState::new(cg, false);
+    printer.printf(cg, &format!("    {}: expected ", field), &[])?;
+    test_value.debug(cg, &mut printer)?;
+    printer.printf(cg, " but got ", &[])?;
+    value.debug(cg, &mut printer)?;
+    printer.printf(cg, "\n", &[])?;
+    printer.print_to_console(cg);
+    cg.builder.build_unconditional_branch(next);
+
+    cg.builder.position_at_end(next);
+  }
   cg.builder.build_unconditional_branch(finally);
 
   cg.builder.position_at_end(finally);
@@ -263,33 +317,42 @@ pub fn example_check_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx as
   Ok(function)
 }
 
-pub fn main_for_examples<'ctx>(context: &'ctx Context, target_machine: &TargetMachine, target_triple: &TargetTriple, modules: &Vec<Module<'ctx>>) -> CodegenResult<Module<'ctx>> {
+// A main that runs the examples of each module, prints how many failed, and exits with that count (up to 255).
+pub fn main_for_examples<'ctx>(context: &'ctx Context, target_machine: &TargetMachine, target_triple: &TargetTriple, opt_level: OptLevel, modules: &Vec<Module<'ctx>>) -> CodegenResult<Module<'ctx>> {
 
-  let cg = CodegenState::new(context, target_machine, target_triple, "main");
+  let mut cg = CodegenState::new(context, target_machine, target_triple, opt_level, "main");
   let function = cg.module.add_function("main", context.i32_type().fn_type(&[], false), None);
 
   let entry = cg.context.append_basic_block(function, "entry");
   cg.builder.position_at_end(entry);
 
+  let mut count = context.i64_type().const_zero();
+  let mut failures = context.i64_type().const_zero();
   for submodule in modules {
-    let fn_name = submodule.get_name().to_str().unwrap().to_string() + "_run_examples";
+    let name = submodule.get_name().to_str().unwrap().to_string();
+    let fn_name = name.clone() + "_run_examples";
     if let Some(sub_fn) = submodule.get_function(&fn_name) {
       let sub_fn = cg.module.add_function(&fn_name, sub_fn.get_type(), None);
       let result = cg.builder.build_call(sub_fn, &[], "result").try_as_basic_value().left().unwrap().into_int_value();
-      let bad_result = context.append_basic_block(function, &(fn_name.clone() + "_bad"));
-      let next = context.append_basic_block(function, &(fn_name + "_next"));
-
-      let test = cg.builder.build_int_compare(IntPredicate::EQ, result, context.i64_type().const_zero(), "test");
-      cg.builder.build_conditional_branch(test, next, bad_result);
+      failures = cg.builder.build_int_add(failures, result, "failures");
 
-      cg.builder.position_at_end(bad_result);
-      cg.builder.build_return(Some(&context.i32_type().const_int(-(1 as i32) as u64, true)));
-      
-      cg.builder.position_at_end(next);
+      let count_fn_name = name + "__get_example_count";
+      let count_fn = submodule.get_function(&count_fn_name).unwrap();
+      let count_fn = cg.module.add_function(&count_fn_name, count_fn.get_type(), None);
+      let module_count = cg.builder.build_call(count_fn, &[], "module_count").try_as_basic_value().left().unwrap().into_int_value();
+      count = cg.builder.build_int_add(count, module_count, "count");
     }
   }
 
-  cg.builder.build_return(Some(&context.i32_type().const_zero()));
+  let printf = get_printf(&cg);
+  let format = cg.global_string("\n%lld examples, %lld failed\n");
+  cg.builder.build_call(printf, &[format.into(), count.into(), failures.into()], "_");
+
+  let max_status = context.i64_type().const_int(255, false);
+  let too_many = cg.builder.build_int_compare(IntPredicate::UGT, failures, max_status, "too_many");
+  let status = cg.builder.build_select(too_many, max_status, failures, "status").into_int_value();
+  let status = cg.builder.build_int_truncate(status, context.i32_type(), "status");
+  cg.builder.build_return(Some(&status));
 
   Ok(cg.module)
-}
\ No newline at end of file
+}
@@ -1,9 +1,7 @@
 use super::*;
 
-pub fn expression_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module, state_alloca: PointerValue<'ctx>, expression: &'ctx ast::ExpressionValue) -> CodegenResult<StateValue<'ctx>> {
-  let old_considering = cg.considering;
-  cg.considering = Some(expression);
-  let result = match &expression.info {
+pub fn expression_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module, state_alloca: PointerValue<'ctx>, expression: &ast::ExpressionValue) -> CodegenResult<StateValue<'ctx>> {
+  match &expression.info {
     ast::ExpressionValueEnum::Output(output_expression) => {
       let return_value = expression_codegen(cg, module, state_alloca, &output_expression.expression)?;
       // expression.output == "" is a workaround for an effectful subexpression (e.g. a CopyToSubModule). This is an 'orrible 'ack and should
@@ -21,15 +19,21 @@ pub fn expression_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Modul
       Ok(return_value)
     }
     ast::ExpressionValueEnum::Block(expressions) => {
+      cg.push_scope();
       let mut final_result = StateValue::new_none();
       for expression in expressions {
         final_result = expression_codegen(cg, module, state_alloca, &expression)?;
       }
+      cg.pop_scope();
       Ok(final_result)
     }
     ast::ExpressionValueEnum::Let(let_expression) => {
       let value = expression_codegen(cg, module, state_alloca, &let_expression.expression)?;
-      cg.add_local(&let_expression.var_name, value.clone())?;
+      if let_expression.is_update {
+        cg.update_local(&let_expression.var_name, value.clone())?;
+      } else {
+        cg.add_local(&let_expression.var_name, value.clone())?;
+      }
       Ok(value)
     }
 
@@ -90,6 +94,10 @@ pub fn expression_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Modul
       let value = from_value_ptr.load(cg, "value")?;
       value.store(cg, &to_update_ptr)?;
 
+      let limit = update_limit(&submodule_info.module);
+      let steps_alloca = cg.entry_block_alloca(cg.context.i64_type().into(), "steps")?;
+      cg.builder.build_store(steps_alloca, cg.uint_const(0));
+
       let invoke_loop_start = flow_to_new_block(cg, "invoke_loop_start")?;
 
       invoke_submodule(cg, &submodule_info.module, submodule_state_ptr)?;
@@ -97,6 +105,13 @@ pub fn expression_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Modul
       let bitfield_ptr = cg.module_bitfield_ptr(&submodule_info.module, submodule_state_ptr)?;
       let bitfield = cg.builder.build_load(bitfield_ptr, "bitfield").into_int_value();
       let has_update = cg.builder.build_int_compare(IntPredicate::NE, bitfield, cg.uint_const(0), "has_update");
+      // a submodule with submodules of its own can give up too, which this one has to pass on
+      let gave_up = if submodule_info.module.submodules.is_empty() {
+        None
+      } else {
+        let gave_up = cg.builder.build_and(bitfield, cg.uint_const(not_converged_mask(&submodule_info.module)?), "gave_up");
+        Some(cg.builder.build_int_compare(IntPredicate::NE, gave_up, cg.uint_const(0), "gave_up"))
+      };
       let copy_back = append_new_block(cg, "copy_back")?;
       // (1) The code below goes here (at the end of invoke_loop_start, before copy_back)
       cg.builder.position_at_end(copy_back);
@@ -107,10 +122,29 @@ pub fn expression_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Modul
         }
       }
 
-      cg.builder.build_unconditional_branch(invoke_loop_start);
+      // a feedback loop inside the submodule might never settle, so give up once it has had its bound
+      let steps = cg.builder.build_load(steps_alloca, "steps").into_int_value();
+      let steps = cg.builder.build_int_add(steps, cg.uint_const(1), "steps");
+      cg.builder.build_store(steps_alloca, steps);
+      let mut keep_going = cg.builder.build_int_compare(IntPredicate::ULT, steps, cg.uint_const(limit), "within_limit");
+      if let Some(gave_up) = gave_up {
+        let carried_on = cg.builder.build_not(gave_up, "carried_on");
+        keep_going = cg.builder.build_and(keep_going, carried_on, "keep_going");
+      }
+      let not_converged = append_new_block(cg, "not_converged")?;
+      cg.builder.build_conditional_branch(keep_going, invoke_loop_start, not_converged);
+
+      // Hosts find out through <Module>_run (or the bit, if they call <Module>_update themselves) and report it.
+      cg.builder.position_at_end(not_converged);
+      let mask = not_converged_mask(module)?;
+      let bitfield_ptr = cg.module_bitfield_ptr(module, state_ptr)?;
+      let bitfield = cg.builder.build_load(bitfield_ptr, "bitfield").into_int_value();
+      let bitfield = cg.builder.build_or(bitfield, cg.uint_const(mask), "not_converged_bitfield");
+      cg.builder.build_store(bitfield_ptr, bitfield);
 
       // this needs to be at the end..
       let updates_complete = append_new_block(cg, "updates_complete")?;
+      cg.builder.build_unconditional_branch(updates_complete);
       // ..which means we can't do this until now, even though it belongs at (1)
       cg.builder.position_at_end(invoke_loop_start);
       cg.builder.build_conditional_branch(has_update, copy_back, updates_complete);
@@ -123,8 +157,7 @@ pub fn expression_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Modul
       match name.as_str() {
         "new" => {
           let size = value.into_int_value()?;
-          let size32 = cg.builder.build_int_cast(size, cg.context.i32_type(), "size32");
-          let raw_location = malloc(cg, size32.into(), "mem_region_location").into_pointer_value();
+          let raw_location = malloc(cg, size, "mem_region_location").into_pointer_value();
           Ok(StateValue::new_dynamic_mem_region_of_type(raw_location, size, vec!(TypePrimitive::MemRegion)))
         }
         "size" => {
@@ -158,7 +191,6 @@ pub fn expression_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Modul
       arr_ptr.array_lookup(cg, idx)
     }
     ast::ExpressionValueEnum::Tuple(entries) => {
-      // TODO: this won't deal with inlined tuples; will need to create a new StateValue type for those.
       let tuple_type = expression_type(cg, module, expression)?;
       let mut tuple_ptr = StateValue::new_tuple(cg, tuple_type)?;
       for (idx, entry) in entries.iter().enumerate() {
@@ -228,22 +260,58 @@ pub fn expression_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Modul
       let test = cg.builder.build_int_compare(IntPredicate::EQ, masked_value, mask, "test");
 
       conditional_expression(cg, test, |cg| {
-        for idx in 0..write_to_tuple.tuple_fields.len() {
-          let tuple_field = &write_to_tuple.tuple_fields[idx];
+        // Build a fresh tuple rather than writing into the update slot in place; this works the same way
+        // whether the tuple is stored inline or behind a pointer.
+        let to_value_ptr = cg.update_ptr_for_field(module, state_ptr, &write_to_tuple.state, UpdatePtrPurpose::WriteAndSet)?;
+        let mut tuple = StateValue::new_tuple(cg, to_value_ptr.pointer_type.clone())?;
+        for (idx, tuple_field) in write_to_tuple.tuple_fields.iter().enumerate() {
           let from_value_ptr = cg.read_ptr_for_field(module, state_ptr, tuple_field)?;
-          let to_value_ptr = cg.update_ptr_for_field(module, state_ptr, &write_to_tuple.state, UpdatePtrPurpose::WriteAndSet)?;
-          let to_ptr = to_value_ptr.get_element_pointer(cg, idx)?;
-          from_value_ptr.load(cg, "from")?.store(cg, &to_ptr)?;
+          tuple.set_tuple_index(cg, idx as u32, from_value_ptr.load(cg, "from")?)?;
         }
-        Ok(())
+        tuple.store(cg, &to_value_ptr)
       })?;
 
       Ok(StateValue::new_none())
     },
+    ast::ExpressionValueEnum::MergeInto(merge_into) => {
+      let state_ptr = cg.builder.build_load(state_alloca, "state_ptr").into_pointer_value();
+      let incoming = cg.read_ptr_for_field(module, state_ptr, &merge_into.state)?.load(cg, "incoming")?;
+
+      // Writes only need merging if the target already has an update pending.
+      let target_idx = module.idx_for_field(&merge_into.target).ok_or(CodegenError::BadUpdateFieldName)?;
+      let bitfield_ptr = cg.module_bitfield_ptr(module, state_ptr)?;
+      let bitfield = cg.builder.build_load(bitfield_ptr, "bitfield").into_int_value();
+      let pending_bit = cg.builder.build_and(bitfield, cg.uint_const(1 << target_idx), "pending_bit");
+      let is_pending = cg.builder.build_int_compare(IntPredicate::NE, pending_bit, cg.uint_const(0), "is_pending");
+      let is_not_pending = cg.builder.build_int_compare(IntPredicate::EQ, pending_bit, cg.uint_const(0), "is_not_pending");
+
+      let write_incoming = |cg: &mut CodegenState<'ctx>| {
+        let to_update_ptr = cg.update_ptr_for_field(module, state_ptr, &merge_into.target, UpdatePtrPurpose::WriteAndSet)?;
+        incoming.store(cg, &to_update_ptr)
+      };
+
+      match &merge_into.policy {
+        ast::MergePolicy::LastWriteWins => write_incoming(cg)?,
+        ast::MergePolicy::FirstWriteWins => conditional_expression(cg, is_not_pending, write_incoming)?,
+        ast::MergePolicy::Combine(pending_name, incoming_name, combiner) => {
+          conditional_expression(cg, is_not_pending, write_incoming)?;
+          conditional_expression(cg, is_pending, |cg| {
+            let to_update_ptr = cg.update_ptr_for_field(module, state_ptr, &merge_into.target, UpdatePtrPurpose::ReadWithoutClearing)?;
+            let pending = to_update_ptr.load(cg, "pending")?;
+            cg.push_scope();
+            cg.add_local(pending_name, pending)?;
+            cg.add_local(incoming_name, incoming.clone())?;
+            let merged = expression_codegen(cg, module, state_alloca, combiner)?;
+            cg.pop_scope();
+            merged.store(cg, &to_update_ptr)
+          })?;
+        }
+      }
+
+      Ok(StateValue::new_none())
+    },
     _ => todo!("Need to implement support for {:?}", expression.info),
-  };
-  cg.considering = old_considering;
-  result
+  }
 }
 
 pub fn expression_logical_and<'ctx>(
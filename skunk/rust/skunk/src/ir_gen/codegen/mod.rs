@@ -6,6 +6,7 @@ use inkwell::types::{BasicTypeEnum, AnyTypeEnum, StructType, BasicType};
 use inkwell::values::{FunctionValue, PointerValue, BasicValueEnum, IntValue};
 
 use super::ast;
+use super::c_header::check_c_identifiers;
 
 use std::convert::TryInto;
 use std::collections::hash_set::HashSet;
@@ -15,15 +16,40 @@ use super::state_values::*;
 
 mod expression_codegen;
 mod examples_codegen;
-pub use examples_codegen::main_for_examples;
+pub use examples_codegen::{main_for_examples, write_function_codegen};
 mod debug_codegen;
 mod c_functions;
+mod accessor_codegen;
+mod run_codegen;
 
 pub use expression_codegen::{expression_codegen, expression_logical_and, if_else_expression};
 use examples_codegen::examples_codegen;
+use accessor_codegen::{accessors_codegen, callback_codegen};
+pub use accessor_codegen::is_by_value;
+pub use run_codegen::{Binding, BindSource, BindSink, main_for_run};
 pub use debug_codegen::*;
 pub use c_functions::*;
 
+// How many update passes a module without a declared iteration bound gets before it's reported as
+// not converging. Graphs without feedback loops settle long before this.
+pub const DEFAULT_UPDATE_LIMIT: u64 = 10000;
+
+// What <Module>_run returns when updates are still pending after max_steps, or a submodule gave up.
+pub const RUN_NOT_CONVERGED: i64 = -1;
+
+pub fn update_limit(module: &ast::Module) -> u64 {
+  module.iteration_bound.unwrap_or(DEFAULT_UPDATE_LIMIT)
+}
+
+// The bitfield bit that says a submodule gave up without settling during the last update.
+pub fn not_converged_mask(module: &ast::Module) -> CodegenResult<u64> {
+  let bit = module.not_converged_bit();
+  if bit >= 64 {
+    return Err(CodegenError::TooManyHandles(module.name.clone(), module.handles.len()));
+  }
+  Ok(1 << bit)
+}
+
 trait Typeable {
   fn ir_type<'ctx>(&self, cg: &CodegenState<'ctx>) -> AnyTypeEnum<'ctx>;
 }
@@ -58,12 +84,19 @@ pub fn llvm_type_for_primitive<'ctx>(cg: &CodegenState<'ctx>, primitive_type: &V
       TypePrimitive::Bool => cg.context.custom_width_int_type(1).into(),
       TypePrimitive::MemRegion => dptr_ir_type(cg, cg.context.i8_type().into()).into(),
       TypePrimitive::DynamicArrayOf(x) => dptr_ir_type(cg, llvm_type_for_primitive(cg, &x).into()).into(),
-      TypePrimitive::PointerTo(x) => llvm_type_for_primitive(cg, x).ptr_type(AddressSpace::Generic).into(),
+      TypePrimitive::PointerTo(x) => struct_type_for_members(cg, x).ptr_type(AddressSpace::Generic).into(),
       TypePrimitive::FixedArrayOf(x, _s) => llvm_type_for_primitive(cg, x).ptr_type(AddressSpace::Generic).into(),
+      TypePrimitive::TupleOf(x) => struct_type_for_members(cg, x).into(),
     }
   }
 }
 
+// Tuples are always structs, even when they only have a single member.
+fn struct_type_for_members<'ctx>(cg: &CodegenState<'ctx>, members: &Vec<TypePrimitive>) -> StructType<'ctx> {
+  let element_types: Vec<BasicTypeEnum<'ctx>> = members.iter().map(|t| llvm_type_for_primitive(cg, &vec!(t.clone()))).collect();
+  cg.context.struct_type(&element_types, false)
+}
+
 impl <'a> Typeable for ast::Module {
   fn ir_type<'ctx>(&self, cg: &CodegenState<'ctx>) -> AnyTypeEnum<'ctx> {
     let mut sub_types: Vec<BasicTypeEnum> = Vec::new();
@@ -89,6 +122,12 @@ impl <'a> Typeable for ast::Module {
     for submodule_info in &self.submodules {
       sub_types.push(submodule_info.module.ir_type(cg).into_struct_type().into());
     }
+    if cg.has_host_callbacks(self) {
+      for _output in self.outputs() {
+        sub_types.push(cg.char_ptr_type().into());
+        sub_types.push(cg.char_ptr_type().into());
+      }
+    }
     cg.context.struct_type(&sub_types, false).into()
   }
 }
@@ -99,19 +138,30 @@ impl <'a> Nameable for ast::Module {
   }
 }
 
-pub fn codegen<'ctx>(context: &'ctx Context, constructor: &mut dyn CodegenStateConstructor<'ctx>, module: &'ctx ast::Module) -> CodegenResult<Vec<Module<'ctx>>> {
-  let mut result = Vec::new();
-  let mut cg = constructor.construct(context, &module.name);
-  module_codegen(&mut cg, module)?;
-  result.push(cg.module);
+// The modules that codegen produces an LLVM module (and so an object) for: module itself, then each
+// distinct direct submodule.
+pub fn compiled_modules(module: &ast::Module) -> Vec<&ast::Module> {
+  let mut result = vec!(module);
   let mut seen_names = HashSet::<String>::new();
   for submodule in &module.submodules {
     if seen_names.contains(&submodule.module.name) {
       continue;
     }
     seen_names.insert(submodule.module.name.clone());
-    let mut cg = constructor.construct(context, &submodule.module.name);
-    module_codegen(&mut cg, &submodule.module)?;
+    result.push(&submodule.module);
+  }
+  result
+}
+
+pub fn codegen<'ctx>(context: &'ctx Context, constructor: &mut dyn CodegenStateConstructor<'ctx>, module: &'ctx ast::Module) -> CodegenResult<Vec<Module<'ctx>>> {
+  let mut result = Vec::new();
+  let root_module = module.name.clone();
+  for module in compiled_modules(module) {
+    check_c_identifiers(module)?;
+    let mut cg = constructor.construct(context, &module.name);
+    cg.root_module = Some(root_module.clone());
+    module_codegen(&mut cg, module)?;
+    cg.module_pass_manager.run_on(&cg.module);
     result.push(cg.module);
   }
   Ok(result)
@@ -120,7 +170,7 @@ pub fn codegen<'ctx>(context: &'ctx Context, constructor: &mut dyn CodegenStateC
 pub fn module_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast::Module) -> CodegenStatus {
  module_init_codegen(cg, module)?;
 
-  //module_deinit_codegen(cg, module)?;
+  module_deinit_codegen(cg, module)?;
 
   for listener in module.listeners.iter() {
     listener_codegen(cg, module, listener)?;
@@ -128,6 +178,10 @@ pub fn module_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast::Modu
 
   module_update_function(cg, module)?;
 
+  module_run_function(cg, module)?;
+
+  accessors_codegen(cg, module)?;
+
   // TODO: Gate producing this on test or debug modes
   debug_codegen(cg, module)?;
 
@@ -146,9 +200,8 @@ fn module_init_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast::Mod
 
   // allocate & clear space
   let module_size = module_type.size_of().unwrap();
-  let module_size32 = cg.builder.build_int_cast(module_size, cg.context.i32_type(), "module_size32");
-  let state_ptr_as_char_ptr = malloc(cg, module_size32.into(), "malloced-state").into_pointer_value();
-  memset(cg, state_ptr_as_char_ptr, cg.context.i8_type().const_zero(), module_size32);
+  let state_ptr_as_char_ptr = malloc(cg, module_size, "malloced-state").into_pointer_value();
+  memset(cg, state_ptr_as_char_ptr, cg.context.i8_type().const_zero(), module_size);
   let state_ptr = cg.builder.build_bitcast(state_ptr_as_char_ptr, module_ptr_type, "state_ptr").into_pointer_value();
 
   for submodule_idx in 0..module.submodules.len() {
@@ -163,6 +216,8 @@ fn module_init_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast::Mod
     let submodule_struct = cg.builder.build_load(submodule_state, "struct");
     let submodule_state_ptr = cg.submodule_ptr(module, state_ptr, submodule_idx)?;
     cg.builder.build_store(submodule_state_ptr, submodule_struct);
+    let submodule_state_as_char_ptr = cg.builder.build_bitcast(submodule_state, cg.char_ptr_type(), "submodule_state_as_char_ptr").into_pointer_value();
+    free(cg, submodule_state_as_char_ptr);
 
     // set generic params for submodule
     for idx in 0..submodule.params.params.len() {
@@ -178,10 +233,97 @@ fn module_init_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast::Mod
     }
   }
 
+  // Values supplied by the graph are pending updates, so listeners fire on the first update.
+  for (name, value) in &module.initial_values {
+    let state_alloca = cg.builder.build_alloca(module_ptr_type, "state_alloca");
+    cg.builder.build_store(state_alloca, state_ptr);
+    let result = expression_codegen(cg, module, state_alloca, value)?;
+    let update_ptr = cg.update_ptr_for_field(module, state_ptr, name, UpdatePtrPurpose::WriteAndSet)?;
+    result.store(cg, &update_ptr)?;
+  }
+
   cg.builder.build_return(Some(&state_ptr));
   Ok(())  
 }
 
+// <Module>_run(state, max_steps) calls <Module>_update until no updates are pending, and returns how many
+// calls that took, or RUN_NOT_CONVERGED if updates were still pending after max_steps calls or a submodule
+// gave up without settling. A max_steps of 0 means the module's update limit.
+fn module_run_function<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module) -> CodegenStatus {
+  let module_ptr_type = module.ir_type(cg).into_struct_type().ptr_type(AddressSpace::Generic);
+  let function_type = cg.context.i64_type().fn_type(&[module_ptr_type.into(), cg.context.i64_type().into()], false);
+  let function = cg.module.add_function(&format!("{}_run", module.name), function_type, None);
+  let update_fn = cg.module.get_function(&module.fn_name()).ok_or(CodegenError::FunctionMissing)?;
+
+  let entry_block = cg.context.append_basic_block(function, "entry");
+  let loop_block = cg.context.append_basic_block(function, "loop");
+  let run_update = cg.context.append_basic_block(function, "run_update");
+  let exit_block = cg.context.append_basic_block(function, "exit");
+  let converged = cg.context.append_basic_block(function, "converged");
+  let not_converged = cg.context.append_basic_block(function, "not_converged");
+
+  cg.builder.position_at_end(entry_block);
+  let state_ptr = function.get_first_param().unwrap().into_pointer_value();
+  let max_steps = function.get_nth_param(1).unwrap().into_int_value();
+  let use_limit = cg.builder.build_int_compare(IntPredicate::EQ, max_steps, cg.uint_const(0), "use_limit");
+  let max_steps = cg.builder.build_select(use_limit, cg.uint_const(update_limit(module)), max_steps, "max_steps").into_int_value();
+  let bitfield_ptr = cg.module_bitfield_ptr(module, state_ptr)?;
+  cg.builder.build_unconditional_branch(loop_block);
+
+  cg.builder.position_at_end(loop_block);
+  let steps = cg.builder.build_phi(cg.context.i64_type(), "steps");
+  let bitfield = cg.builder.build_load(bitfield_ptr, "bitfield").into_int_value();
+  let pending = cg.builder.build_int_compare(IntPredicate::NE, bitfield, cg.uint_const(0), "pending");
+  let within_limit = cg.builder.build_int_compare(IntPredicate::ULT, steps.as_basic_value().into_int_value(), max_steps, "within_limit");
+  let test = cg.builder.build_and(pending, within_limit, "test");
+  cg.builder.build_conditional_branch(test, run_update, exit_block);
+
+  cg.builder.position_at_end(run_update);
+  cg.builder.build_call(update_fn, &[state_ptr.into()], "_");
+  let next_steps = cg.builder.build_int_add(steps.as_basic_value().into_int_value(), cg.uint_const(1), "next_steps");
+  let next_block = if module.submodules.is_empty() {
+    run_update
+  } else {
+    // no point going round again once a submodule has given up
+    let bitfield = cg.builder.build_load(bitfield_ptr, "bitfield").into_int_value();
+    let gave_up = cg.builder.build_and(bitfield, cg.uint_const(not_converged_mask(module)?), "gave_up");
+    let gave_up = cg.builder.build_int_compare(IntPredicate::NE, gave_up, cg.uint_const(0), "gave_up");
+    let next_block = cg.context.append_basic_block(function, "next");
+    cg.builder.build_conditional_branch(gave_up, not_converged, next_block);
+    cg.builder.position_at_end(next_block);
+    next_block
+  };
+  cg.builder.build_unconditional_branch(loop_block);
+  steps.add_incoming(&[(&cg.uint_const(0), entry_block), (&next_steps, next_block)]);
+
+  cg.builder.position_at_end(exit_block);
+  cg.builder.build_conditional_branch(pending, not_converged, converged);
+
+  cg.builder.position_at_end(converged);
+  cg.builder.build_return(Some(&steps.as_basic_value()));
+
+  cg.builder.position_at_end(not_converged);
+  cg.builder.build_return(Some(&cg.context.i64_type().const_int(RUN_NOT_CONVERGED as u64, true)));
+  cg.function_pass_manager.run_on(&function);
+  Ok(())
+}
+
+// <Module>_deinit frees a state returned by <Module>_init. Submodule states live inside it, so there's
+// nothing else to free; memory regions that handles point at aren't owned by the state.
+fn module_deinit_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &'ctx ast::Module) -> CodegenStatus {
+  let module_ptr_type = module.ir_type(cg).into_struct_type().ptr_type(AddressSpace::Generic);
+  let function_type = cg.context.void_type().fn_type(&[module_ptr_type.into()], false);
+  let function = cg.module.add_function(&format!("{}_deinit", module.name), function_type, None);
+
+  let entry_block = cg.context.append_basic_block(function, "entry");
+  cg.builder.position_at_end(entry_block);
+  let state_ptr = function.get_first_param().unwrap().into_pointer_value();
+  let state_ptr_as_char_ptr = cg.builder.build_bitcast(state_ptr, cg.char_ptr_type(), "state_ptr_as_char_ptr").into_pointer_value();
+  free(cg, state_ptr_as_char_ptr);
+  cg.builder.build_return(None);
+  Ok(())
+}
+
 fn module_update_function<'ctx, 'a>(cg: &CodegenState<'ctx>, module: &ast::Module) -> CodegenStatus {
   // Compute a trigger mask - we only need to trigger when a listener is installed on a handle
   // TODO: we don't actually use this..
@@ -205,6 +347,11 @@ fn module_update_function<'ctx, 'a>(cg: &CodegenState<'ctx>, module: &ast::Modul
 
   let bitfield_ptr = cg.module_bitfield_ptr(module, state_ptr)?;
   let bitfield = cg.builder.build_load(bitfield_ptr, "bitfield").into_int_value();
+  if !module.submodules.is_empty() {
+    // a new round, so forget whether a submodule gave up in the last one
+    let cleared = cg.builder.build_and(bitfield, cg.uint_const(!not_converged_mask(module)?), "cleared");
+    cg.builder.build_store(bitfield_ptr, cleared);
+  }
 
   for (idx, handle) in module.handles.iter().enumerate() {
     let test_value = cg.uint_const(1 << idx);
@@ -223,6 +370,10 @@ fn module_update_function<'ctx, 'a>(cg: &CodegenState<'ctx>, module: &ast::Modul
     value.store(cg, &write_ptr)?;
     update_ptr.clear_update_pointer(cg)?;
 
+    if cg.has_host_callbacks(module) && handle.is_output() {
+      callback_codegen(cg, module, state_ptr, handle)?;
+    }
+
     for listener in module.listeners.iter() {
       if listener.trigger == *handle.name {
         let function_name = (module, listener).fn_name();
@@ -275,6 +426,7 @@ fn state_alloca_for_module_function<'ctx>(cg: &CodegenState<'ctx>, module: &ast:
 fn listener_body_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module, implementation: &'ctx ast::ExpressionValue, function: FunctionValue<'ctx>) -> CodegenStatus {
   let entry_block = cg.context.append_basic_block(function, "entry");
   cg.builder.position_at_end(entry_block);
+  cg.reset_locals();
   let state_alloca = state_alloca_for_module_function(cg, module, function);
   expression_codegen(cg, module, state_alloca, implementation)?;
   cg.builder.build_return(Option::None);
@@ -326,30 +478,19 @@ fn expression_type<'ctx>(cg: &CodegenState<'ctx>, module: &ast::Module, expressi
       if let Some(local) = cg.get_local(&field) {
         Ok(local.value_type.clone())
       } else {
-        let h_type = module.type_for_field(&field).unwrap(); //ok_or(CodegenError::BadReadFieldName(field.clone()))?;
+        let h_type = module.type_for_field(&field).ok_or(CodegenError::BadReadFieldName(field.clone()))?;
         Ok(type_primitive_for_type(&h_type))
       }
     }
     ast::ExpressionValueEnum::Tuple(exprs) => {
-      let tuple_contents = exprs.iter().map(|expr| expression_type(cg, module, expr)).flatten().flatten().collect();
-      let size = type_size(&tuple_contents);
-      if size <= 16 {
-        Ok(tuple_contents)
-      } else {
-        Ok(vec!(TypePrimitive::PointerTo(tuple_contents)))
-      }
+      let tuple_contents = exprs.iter().map(|expr| expression_type(cg, module, expr)).collect::<CodegenResult<Vec<_>>>()?;
+      Ok(tuple_type_primitive(tuple_contents.into_iter().flatten().collect()))
     }
     ast::ExpressionValueEnum::TupleLookup(expr, pos) => {
       let tuple_type = expression_type(cg, module, expr.as_ref())?;
-      if tuple_type.len() == 1 {
-        if let TypePrimitive::PointerTo(tuple_type) = &tuple_type[0] {
-          Ok(vec!(tuple_type[*pos as usize].clone()))
-        } else {
-          Err(CodegenError::TypeMismatch("non-pointer tuple field of size 1".to_string()))
-        }
-      } else {
-        todo!("Implement type lookup by field # for inline tuples")
-      }
+      let members = tuple_members(&tuple_type)?;
+      let member = members.get(*pos as usize).ok_or(CodegenError::TypeMismatch(format!("tuple {:?} has no field {}", tuple_type, pos)))?;
+      Ok(vec!(member.clone()))
     }
     ast::ExpressionValueEnum::BinaryOperator(lhs, op, rhs) => {
       match op {
@@ -537,4 +678,36 @@ module ExpressionTypes {
     dbg!(t, u, v);
     Ok(())
   }
+
+  #[test]
+  fn not_converged_bit_fits_in_the_bitfield() {
+    let module_with = |count: usize| {
+      let handles: Vec<String> = (0..count).map(|idx| format!("  h{}: reads Int;\n", idx)).collect();
+      let (_, ast) = parser::parse(&format!("module Wide {{\n{}}}", handles.concat())).unwrap();
+      ast::modules(&ast)[0].clone()
+    };
+    assert_eq!(not_converged_mask(&module_with(63)), Ok(1 << 63));
+    assert_eq!(not_converged_mask(&module_with(64)), Err(CodegenError::TooManyHandles("Wide".to_string(), 64)));
+  }
+
+  #[test]
+  fn nested_tuple_layout() {
+    let string = TypePrimitive::DynamicArrayOf(vec!(TypePrimitive::Char));
+    let inner = ast::Type::Tuple(vec!(ast::Type::String, ast::Type::Int));
+    assert_eq!(
+      type_primitive_for_type(&ast::Type::Tuple(vec!(inner, ast::Type::Int))),
+      vec!(TypePrimitive::TupleOf(vec!(TypePrimitive::PointerTo(vec!(string.clone(), TypePrimitive::Int)), TypePrimitive::Int)))
+    );
+    let wide = ast::Type::Tuple(vec!(ast::Type::String, ast::Type::String));
+    assert_eq!(
+      type_primitive_for_type(&ast::Type::Tuple(vec!(wide, ast::Type::Int, ast::Type::Int))),
+      vec!(TypePrimitive::PointerTo(vec!(TypePrimitive::PointerTo(vec!(string.clone(), string)), TypePrimitive::Int, TypePrimitive::Int)))
+    );
+    let chars = ast::Type::Tuple(vec!(ast::Type::Char, ast::Type::Char));
+    assert_eq!(
+      type_primitive_for_type(&ast::Type::Tuple(vec!(chars, ast::Type::Int))),
+      vec!(TypePrimitive::TupleOf(vec!(TypePrimitive::TupleOf(vec!(TypePrimitive::Char, TypePrimitive::Char)), TypePrimitive::Int)))
+    );
+    assert_eq!(type_size(&vec!(TypePrimitive::Char, TypePrimitive::Int)), 16);
+  }
 }
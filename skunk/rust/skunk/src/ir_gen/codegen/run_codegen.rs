@@ -0,0 +1,389 @@
+use super::*;
+
+use super::super::c_header::c_identifier;
+use super::accessor_codegen::callback_type;
+
+/*
+
+`skunk run` links a module against a main that connects its handles to the outside world. Each
+--bind <handle>=<target> is one of
+
+  <input>=stdin-lines   each line of stdin (without its newline) is written to the handle in turn
+  <input>=file:<path>   the contents of the file are written to the handle, before stdin is read
+  <input>=arg:<n>       the nth command line argument (from 1) is written to the handle, before stdin is read
+  <output>=stdout       each new value of the handle is printed
+  <output>=error        each new value of the handle is printed, and main returns 1 once updates settle
+
+An output called error that isn't bound to anything else is bound to error. Inputs are String or
+MemRegion, or Int in which case the text is parsed as a decimal number. After each write, updates
+run until the module is quiescent; main returns 2 if they don't settle, an input can't be read, or an Int
+input isn't a number.
+
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BindSource {
+  StdinLines,
+  File(String),
+  Arg(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BindSink {
+  Stdout,
+  Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Binding {
+  Input(String, BindSource),
+  Output(String, BindSink),
+}
+
+impl Binding {
+  pub fn parse(binding: &str) -> Option<Binding> {
+    let mut parts = binding.splitn(2, '=');
+    let handle = parts.next()?.to_string();
+    let target = parts.next()?;
+    match target {
+      "stdin-lines" => Some(Binding::Input(handle, BindSource::StdinLines)),
+      "stdout" => Some(Binding::Output(handle, BindSink::Stdout)),
+      "error" => Some(Binding::Output(handle, BindSink::Error)),
+      _ => {
+        if let Some(path) = target.strip_prefix("file:") {
+          Some(Binding::Input(handle, BindSource::File(path.to_string())))
+        } else {
+          let arg = target.strip_prefix("arg:")?.parse().ok().filter(|&arg| arg > 0)?;
+          Some(Binding::Input(handle, BindSource::Arg(arg)))
+        }
+      }
+    }
+  }
+
+  fn handle(&self) -> &str {
+    match self {
+      Binding::Input(handle, _) | Binding::Output(handle, _) => handle
+    }
+  }
+}
+
+fn is_text(primitive_type: &Vec<TypePrimitive>) -> bool {
+  *primitive_type == vec!(TypePrimitive::MemRegion) || *primitive_type == vec!(TypePrimitive::DynamicArrayOf(vec!(TypePrimitive::Char)))
+}
+
+// Checks bindings against the module, and adds the default binding for error.
+pub fn run_bindings(module: &ast::Module, bindings: &Vec<Binding>) -> CodegenResult<Vec<Binding>> {
+  let mut result = bindings.clone();
+  if let Some(error) = module.outputs().into_iter().find(|handle| handle.name == "error") {
+    if !bindings.iter().any(|binding| binding.handle() == error.name) {
+      result.push(Binding::Output(error.name.clone(), BindSink::Error));
+    }
+  }
+
+  let mut bound = HashSet::new();
+  for binding in &result {
+    let handle = module.handle_for_field(binding.handle()).ok_or_else(|| CodegenError::BadBinding(format!("{} has no handle called {}", module.name, binding.handle())))?;
+    if !bound.insert(handle.name.clone()) {
+      return Err(CodegenError::BadBinding(format!("{} is bound more than once", handle.name)));
+    }
+    match binding {
+      Binding::Input(_, _) => {
+        if !handle.is_input() {
+          return Err(CodegenError::BadBinding(format!("{} isn't read by {}", handle.name, module.name)));
+        }
+        let primitive_type = type_primitive_for_type(&handle.h_type);
+        if !is_text(&primitive_type) && primitive_type != vec!(TypePrimitive::Int) {
+          return Err(CodegenError::BadBinding(format!("{} has type {:?}; inputs can only be bound to text or Int handles", handle.name, handle.h_type)));
+        }
+      }
+      Binding::Output(_, _) => {
+        if !handle.is_output() {
+          return Err(CodegenError::BadBinding(format!("{} isn't written by {}", handle.name, module.name)));
+        }
+      }
+    }
+  }
+  if result.iter().filter(|binding| matches!(binding, Binding::Input(_, BindSource::StdinLines))).count() > 1 {
+    return Err(CodegenError::BadBinding("only one handle can be bound to stdin-lines".to_string()));
+  }
+  Ok(result)
+}
+
+// A main(argc, argv) for module, which must already have been compiled as the top-level module.
+pub fn main_for_run<'ctx>(context: &'ctx Context, constructor: &mut dyn CodegenStateConstructor<'ctx>, module: &ast::Module, bindings: &Vec<Binding>) -> CodegenResult<Module<'ctx>> {
+  let bindings = run_bindings(module, bindings)?;
+  let mut cg = constructor.construct(context, "main");
+  cg.root_module = Some(module.name.clone());
+
+  let module_ptr_type = module.ir_type(&cg).into_struct_type().ptr_type(AddressSpace::Generic);
+  let failed = cg.module.add_global(context.i32_type(), None, "failed");
+  failed.set_initializer(&context.i32_type().const_zero());
+  let failed = failed.as_pointer_value();
+
+  let mut callbacks = Vec::new();
+  for binding in &bindings {
+    if let Binding::Output(name, sink) = binding {
+      let handle = module.handle_for_field(name).unwrap();
+      callbacks.push((handle, print_callback_codegen(&mut cg, module, handle, sink, failed)?));
+    }
+  }
+
+  let i32_type = context.i32_type();
+  let argv_type = cg.char_ptr_type().ptr_type(AddressSpace::Generic);
+  let function = cg.module.add_function("main", i32_type.fn_type(&[i32_type.into(), argv_type.into()], false), None);
+  let entry = context.append_basic_block(function, "entry");
+  cg.builder.position_at_end(entry);
+  let argc = function.get_first_param().unwrap().into_int_value();
+  let argv = function.get_nth_param(1).unwrap().into_pointer_value();
+
+  let init = external_function(&cg, &format!("{}_init", module.name), module_ptr_type.fn_type(&[], false));
+  let run = external_function(&cg, &format!("{}_run", module.name), context.i64_type().fn_type(&[module_ptr_type.into(), context.i64_type().into()], false));
+  let deinit = external_function(&cg, &format!("{}_deinit", module.name), context.void_type().fn_type(&[module_ptr_type.into()], false));
+
+  let state_ptr = cg.builder.build_call(init, &[], "state").try_as_basic_value().left().unwrap().into_pointer_value();
+  let userdata = cg.builder.build_bitcast(state_ptr, cg.char_ptr_type(), "userdata");
+  for (handle, callback) in callbacks {
+    let callback_ptr_type = callback_type(&cg, &type_primitive_for_type(&handle.h_type));
+    let register_type = context.void_type().fn_type(&[module_ptr_type.into(), callback_ptr_type.into(), cg.char_ptr_type().into()], false);
+    let register = external_function(&cg, &format!("{}_on_{}", c_identifier(&module.name), c_identifier(&handle.name)), register_type);
+    cg.builder.build_call(register, &[state_ptr.into(), callback.as_global_value().as_pointer_value().into(), userdata.into()], "_");
+  }
+
+  // getdelim's buffer and its size, for reading input
+  let buffer_ptr = cg.builder.build_alloca(cg.char_ptr_type(), "buffer_ptr");
+  let allocated_ptr = cg.builder.build_alloca(cg.size_type, "allocated_ptr");
+
+  let run_state = RunState { function, run, state_ptr, failed, buffer_ptr, allocated_ptr };
+  for binding in &bindings {
+    if let Binding::Input(name, source) = binding {
+      let handle = module.handle_for_field(name).unwrap();
+      match source {
+        BindSource::File(path) => file_input_codegen(&mut cg, &run_state, module, handle, path)?,
+        BindSource::Arg(arg) => arg_input_codegen(&mut cg, &run_state, module, handle, *arg, argc, argv)?,
+        BindSource::StdinLines => continue,
+      }
+      settle_codegen(&mut cg, &run_state, module)?;
+    }
+  }
+  for binding in &bindings {
+    if let Binding::Input(name, BindSource::StdinLines) = binding {
+      stdin_lines_codegen(&mut cg, &run_state, module, module.handle_for_field(name).unwrap())?;
+    }
+  }
+
+  cg.builder.build_call(deinit, &[state_ptr.into()], "_");
+  let result = cg.builder.build_load(failed, "failed");
+  cg.builder.build_return(Some(&result));
+  Ok(cg.module)
+}
+
+struct RunState<'ctx> {
+  function: FunctionValue<'ctx>,
+  run: FunctionValue<'ctx>,
+  state_ptr: PointerValue<'ctx>,
+  failed: PointerValue<'ctx>,
+  buffer_ptr: PointerValue<'ctx>,
+  allocated_ptr: PointerValue<'ctx>,
+}
+
+fn external_function<'ctx>(cg: &CodegenState<'ctx>, name: &str, function_type: inkwell::types::FunctionType<'ctx>) -> FunctionValue<'ctx> {
+  cg.module.get_function(name).unwrap_or_else(|| cg.module.add_function(name, function_type, None))
+}
+
+// fn(userdata, value) for <Module>_on_<handle>; userdata is the state, and the value is printed from there.
+fn print_callback_codegen<'ctx>(cg: &mut CodegenState<'ctx>, module: &ast::Module, handle: &ast::Handle, sink: &BindSink, failed: PointerValue<'ctx>) -> CodegenResult<FunctionValue<'ctx>> {
+  let function_type = callback_type(cg, &type_primitive_for_type(&handle.h_type)).get_element_type().into_function_type();
+  let function = cg.module.add_function(&format!("__print_{}", c_identifier(&handle.name)), function_type, None);
+  let entry = cg.context.append_basic_block(function, "entry");
+  cg.builder.position_at_end(entry);
+
+  let module_ptr_type = module.ir_type(cg).into_struct_type().ptr_type(AddressSpace::Generic);
+  let state_ptr = cg.builder.build_bitcast(function.get_first_param().unwrap(), module_ptr_type, "state").into_pointer_value();
+  let value = cg.read_ptr_for_field(module, state_ptr, &handle.name)?.load(cg, "value")?;
+  let mut printer = DebugState::new(cg, false);
+  if *sink == BindSink::Error {
+    printer.printf(cg, &format!("{}: ", handle.name), &[])?;
+    cg.builder.build_store(failed, cg.context.i32_type().const_int(1, false));
+  }
+  value.debug(cg, &mut printer)?;
+  printer.printf(cg, "\n", &[])?;
+  printer.print_to_console(cg);
+  cg.builder.build_return(None);
+  Ok(function)
+}
+
+// Prints message and returns 2 from main if condition holds; otherwise carries on.
+fn exit_if_codegen<'ctx>(cg: &mut CodegenState<'ctx>, run_state: &RunState<'ctx>, condition: IntValue<'ctx>, message: &str) {
+  let exit_block = cg.context.append_basic_block(run_state.function, "exit");
+  let next_block = cg.context.append_basic_block(run_state.function, "next");
+  cg.builder.build_conditional_branch(condition, exit_block, next_block);
+
+  cg.builder.position_at_end(exit_block);
+  let format = cg.global_string("%s");
+  let message = cg.global_string(message);
+  cg.builder.build_call(get_printf(cg), &[format.into(), message.into()], "_");
+  cg.builder.build_return(Some(&cg.context.i32_type().const_int(2, false)));
+
+  cg.builder.position_at_end(next_block);
+}
+
+// Runs updates until none are pending, then returns from main if an error was printed.
+fn settle_codegen<'ctx>(cg: &mut CodegenState<'ctx>, run_state: &RunState<'ctx>, module: &ast::Module) -> CodegenStatus {
+  let steps = cg.builder.build_call(run_state.run, &[run_state.state_ptr.into(), cg.context.i64_type().const_zero().into()], "steps")
+    .try_as_basic_value().left().unwrap().into_int_value();
+  let not_converged = cg.builder.build_int_compare(IntPredicate::SLT, steps, cg.context.i64_type().const_zero(), "not_converged");
+  exit_if_codegen(cg, run_state, not_converged, &format!("{} did not settle after {} updates\n", module.name, update_limit(module)));
+
+  let failed_block = cg.context.append_basic_block(run_state.function, "failed");
+  let next_block = cg.context.append_basic_block(run_state.function, "next");
+  let failed = cg.builder.build_load(run_state.failed, "failed").into_int_value();
+  let is_failed = cg.builder.build_int_compare(IntPredicate::NE, failed, cg.context.i32_type().const_zero(), "is_failed");
+  cg.builder.build_conditional_branch(is_failed, failed_block, next_block);
+
+  cg.builder.position_at_end(failed_block);
+  cg.builder.build_return(Some(&failed));
+
+  cg.builder.position_at_end(next_block);
+  Ok(())
+}
+
+// Writes size bytes of text at data to handle, or the number they spell out if handle is an Int.
+fn write_text_codegen<'ctx>(cg: &mut CodegenState<'ctx>, run_state: &RunState<'ctx>, module: &ast::Module, handle: &ast::Handle, data: PointerValue<'ctx>, size: IntValue<'ctx>) -> CodegenStatus {
+  let update_ptr = cg.update_ptr_for_field(module, run_state.state_ptr, &handle.name, UpdatePtrPurpose::WriteAndSet)?.pointer.into_pointer_value();
+  if is_text(&type_primitive_for_type(&handle.h_type)) {
+    let data_ptr = cg.builder.build_struct_gep(update_ptr, 0, "data_ptr").or(Err(CodegenError::InvalidStructPointer("write_text_codegen given bad handle".to_string())))?;
+    let size_ptr = cg.builder.build_struct_gep(update_ptr, 1, "size_ptr").or(Err(CodegenError::InvalidStructPointer("write_text_codegen given bad handle".to_string())))?;
+    let data = cg.builder.build_bitcast(data, data_ptr.get_type().get_element_type().into_pointer_type(), "data");
+    cg.builder.build_store(data_ptr, data);
+    cg.builder.build_store(size_ptr, cg.builder.build_int_cast(size, cg.context.i64_type(), "size"));
+  } else {
+    let end_ptr = cg.entry_block_alloca(cg.char_ptr_type().into(), "end_ptr")?;
+    let value = cg.builder.build_call(get_strtoll(cg), &[data.into(), end_ptr.into(), cg.context.i32_type().const_int(10, false).into()], "value")
+      .try_as_basic_value().left().unwrap();
+    cg.builder.build_store(update_ptr, value);
+
+    // The number has to be all of the text, apart from a trailing newline (as files tend to have).
+    let i64_type = cg.context.i64_type();
+    let end = cg.builder.build_load(end_ptr, "end").into_pointer_value();
+    let consumed = cg.builder.build_ptr_diff(end, data, "consumed");
+    let size = cg.builder.build_int_cast(size, i64_type, "size");
+    let no_digits = cg.builder.build_int_compare(IntPredicate::EQ, consumed, i64_type.const_zero(), "no_digits");
+    let all_consumed = cg.builder.build_int_compare(IntPredicate::EQ, consumed, size, "all_consumed");
+    let one_left = cg.builder.build_int_add(consumed, i64_type.const_int(1, false), "one_left");
+    let one_left = cg.builder.build_int_compare(IntPredicate::EQ, one_left, size, "one_left");
+    let last = cg.builder.build_load(end, "last").into_int_value();
+    let newline_left = cg.builder.build_int_compare(IntPredicate::EQ, last, cg.context.i8_type().const_int(b'\n' as u64, false), "newline_left");
+    let newline_left = cg.builder.build_and(one_left, newline_left, "newline_left");
+    let rest_ok = cg.builder.build_or(all_consumed, newline_left, "rest_ok");
+    let rest_bad = cg.builder.build_not(rest_ok, "rest_bad");
+    let not_a_number = cg.builder.build_or(no_digits, rest_bad, "not_a_number");
+    exit_if_codegen(cg, run_state, not_a_number, &format!("{} expects a decimal number\n", handle.name));
+  }
+  Ok(())
+}
+
+fn arg_input_codegen<'ctx>(cg: &mut CodegenState<'ctx>, run_state: &RunState<'ctx>, module: &ast::Module, handle: &ast::Handle, arg: usize, argc: IntValue<'ctx>, argv: PointerValue<'ctx>) -> CodegenStatus {
+  let arg_idx = cg.context.i32_type().const_int(arg as u64, false);
+  let missing = cg.builder.build_int_compare(IntPredicate::SLE, argc, arg_idx, "missing");
+  exit_if_codegen(cg, run_state, missing, &format!("{} is bound to argument {}, which wasn't given\n", handle.name, arg));
+
+  let arg_ptr = unsafe { cg.builder.build_in_bounds_gep(argv, &[arg_idx], "arg_ptr") };
+  let data = cg.builder.build_load(arg_ptr, "arg").into_pointer_value();
+  let size = cg.builder.build_call(get_strlen(cg), &[data.into()], "size").try_as_basic_value().left().unwrap().into_int_value();
+  write_text_codegen(cg, run_state, module, handle, data, size)
+}
+
+// Reads up to delimiter from file into a new buffer, which the handle keeps. Returns the buffer and the
+// length read, which is negative at the end of the file.
+fn read_until_codegen<'ctx>(cg: &mut CodegenState<'ctx>, run_state: &RunState<'ctx>, file: PointerValue<'ctx>, delimiter: u8) -> (PointerValue<'ctx>, IntValue<'ctx>) {
+  let (buffer_ptr, allocated_ptr) = (run_state.buffer_ptr, run_state.allocated_ptr);
+  cg.builder.build_store(buffer_ptr, cg.char_ptr_type().const_null());
+  cg.builder.build_store(allocated_ptr, cg.size_const(0));
+  let delimiter = cg.context.i32_type().const_int(delimiter as u64, false);
+  let length = cg.builder.build_call(get_getdelim(cg), &[buffer_ptr.into(), allocated_ptr.into(), delimiter.into(), file.into()], "length")
+    .try_as_basic_value().left().unwrap().into_int_value();
+  let buffer = cg.builder.build_load(buffer_ptr, "buffer").into_pointer_value();
+  (buffer, length)
+}
+
+// Files are read up to the first NUL, so this is for text.
+fn file_input_codegen<'ctx>(cg: &mut CodegenState<'ctx>, run_state: &RunState<'ctx>, module: &ast::Module, handle: &ast::Handle, path: &str) -> CodegenStatus {
+  let path_string = cg.global_string(path);
+  let mode = cg.global_string("r");
+  let file = cg.builder.build_call(get_fopen(cg), &[path_string.into(), mode.into()], "file").try_as_basic_value().left().unwrap().into_pointer_value();
+  let unopened = cg.builder.build_is_null(file, "unopened");
+  exit_if_codegen(cg, run_state, unopened, &format!("Can't open {} for {}\n", path, handle.name));
+
+  let (buffer, length) = read_until_codegen(cg, run_state, file, 0);
+  let empty = cg.builder.build_int_compare(IntPredicate::SLT, length, cg.size_const(0), "empty");
+  let size = cg.builder.build_select(empty, cg.size_const(0), length, "size").into_int_value();
+  cg.builder.build_call(get_fclose(cg), &[file.into()], "_");
+  write_text_codegen(cg, run_state, module, handle, buffer, size)
+}
+
+// Each line's buffer is kept until the next line has been written and settled, as the handle's current
+// value points into it until then.
+fn stdin_lines_codegen<'ctx>(cg: &mut CodegenState<'ctx>, run_state: &RunState<'ctx>, module: &ast::Module, handle: &ast::Handle) -> CodegenStatus {
+  let previous_ptr = cg.entry_block_alloca(cg.char_ptr_type().into(), "previous_ptr")?;
+  cg.builder.build_store(previous_ptr, cg.char_ptr_type().const_null());
+  let mode = cg.global_string("r");
+  let stdin = cg.builder.build_call(get_fdopen(cg), &[cg.context.i32_type().const_zero().into(), mode.into()], "stdin")
+    .try_as_basic_value().left().unwrap().into_pointer_value();
+  let read_block = cg.context.append_basic_block(run_state.function, "read_line");
+  let line_block = cg.context.append_basic_block(run_state.function, "line");
+  let done_block = cg.context.append_basic_block(run_state.function, "done");
+  cg.builder.build_unconditional_branch(read_block);
+
+  cg.builder.position_at_end(read_block);
+  let (buffer, length) = read_until_codegen(cg, run_state, stdin, b'\n');
+  let at_end = cg.builder.build_int_compare(IntPredicate::SLT, length, cg.size_const(0), "at_end");
+  cg.builder.build_conditional_branch(at_end, done_block, line_block);
+
+  cg.builder.position_at_end(line_block);
+  let last_idx = cg.builder.build_int_sub(length, cg.size_const(1), "last_idx");
+  let last_ptr = unsafe { cg.builder.build_in_bounds_gep(buffer, &[last_idx], "last_ptr") };
+  let last = cg.builder.build_load(last_ptr, "last").into_int_value();
+  let newline = cg.builder.build_int_compare(IntPredicate::EQ, last, cg.context.i8_type().const_int(b'\n' as u64, false), "newline");
+  let size = cg.builder.build_select(newline, last_idx, length, "size").into_int_value();
+  write_text_codegen(cg, run_state, module, handle, buffer, size)?;
+  settle_codegen(cg, run_state, module)?;
+  let previous = cg.builder.build_load(previous_ptr, "previous").into_pointer_value();
+  free(cg, previous);
+  cg.builder.build_store(previous_ptr, buffer);
+  cg.builder.build_unconditional_branch(read_block);
+
+  cg.builder.position_at_end(done_block);
+  let previous = cg.builder.build_load(previous_ptr, "previous").into_pointer_value();
+  free(cg, previous);
+  free(cg, buffer);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::super::super::parser;
+
+  #[test]
+  fn bindings_are_checked_against_the_module() {
+    assert_eq!(Binding::parse("input=stdin-lines"), Some(Binding::Input("input".to_string(), BindSource::StdinLines)));
+    assert_eq!(Binding::parse("input=file:in.txt"), Some(Binding::Input("input".to_string(), BindSource::File("in.txt".to_string()))));
+    assert_eq!(Binding::parse("input=arg:2"), Some(Binding::Input("input".to_string(), BindSource::Arg(2))));
+    assert_eq!(Binding::parse("result=stdout"), Some(Binding::Output("result".to_string(), BindSink::Stdout)));
+    assert_eq!(Binding::parse("input=arg:0"), None);
+    assert_eq!(Binding::parse("input"), None);
+
+    let (_, ast) = parser::parse("module Checker {\n  input: reads String;\n  count: reads Int;\n  flag: reads Bool;\n  result: writes Int;\n  error: writes String;\n}").unwrap();
+    let module = ast::modules(&ast)[0];
+    let bindings = vec!(Binding::parse("input=stdin-lines").unwrap(), Binding::parse("result=stdout").unwrap());
+    assert_eq!(run_bindings(module, &bindings).unwrap().last(), Some(&Binding::Output("error".to_string(), BindSink::Error)));
+    let bindings = vec!(Binding::parse("error=stdout").unwrap());
+    assert_eq!(run_bindings(module, &bindings), Ok(bindings.clone()));
+
+    for binding in &["missing=stdout", "result=stdin-lines", "input=error", "flag=arg:1"] {
+      assert!(matches!(run_bindings(module, &vec!(Binding::parse(binding).unwrap())), Err(CodegenError::BadBinding(_))), "{}", binding);
+    }
+    let twice = vec!(Binding::parse("input=stdin-lines").unwrap(), Binding::parse("count=stdin-lines").unwrap());
+    assert!(matches!(run_bindings(module, &twice), Err(CodegenError::BadBinding(_))));
+  }
+}
@@ -1,16 +1,20 @@
 use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
+use inkwell::execution_engine::ExecutionEngine;
 use inkwell::module::Module;
 use inkwell::passes::{PassManager, PassManagerBuilder};
 use inkwell::values::{FunctionValue, IntValue, PointerValue};
-use inkwell::targets::{TargetMachine, TargetTriple};
-use inkwell::types::PointerType;
-use inkwell::AddressSpace;
+use inkwell::targets::{InitializationConfig, Target, TargetData, TargetMachine, TargetTriple};
+use inkwell::types::{BasicTypeEnum, IntType, PointerType};
+use inkwell::{AddressSpace, OptimizationLevel};
 
 use std::collections::HashMap;
+use std::ffi::c_void;
+use std::ptr;
 use super::state_values::*;
-use super::ast::ExpressionValue;
+use super::ast;
+use super::llvm_type_for_primitive;
 
 #[derive(Debug, PartialEq)]
 pub enum CodegenError {
@@ -26,6 +30,76 @@ pub enum CodegenError {
   TypeMismatch(String),
   InvalidTupleID(usize),
   NakedBreak,
+  UndefinedLocal(String),
+  BadBinding(String),
+  // two field names that are the same once made into C identifiers
+  CIdentifierClash(String, String),
+  // a module with submodules needs a bit past its handles' bits for reporting that one didn't settle
+  TooManyHandles(String, usize),
+}
+
+// -O0 to -O3, and -Os
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OptLevel {
+  O0,
+  O1,
+  O2,
+  O3,
+  Os,
+}
+
+impl Default for OptLevel {
+  fn default() -> Self {
+    OptLevel::O2
+  }
+}
+
+impl OptLevel {
+  pub fn parse(flag: &str) -> Option<OptLevel> {
+    match flag {
+      "-O0" => Some(OptLevel::O0),
+      "-O1" => Some(OptLevel::O1),
+      "-O2" => Some(OptLevel::O2),
+      "-O3" => Some(OptLevel::O3),
+      "-Os" => Some(OptLevel::Os),
+      _ => None
+    }
+  }
+
+  pub fn llvm_level(&self) -> OptimizationLevel {
+    match self {
+      OptLevel::O0 => OptimizationLevel::None,
+      OptLevel::O1 => OptimizationLevel::Less,
+      OptLevel::O2 | OptLevel::Os => OptimizationLevel::Default,
+      OptLevel::O3 => OptimizationLevel::Aggressive,
+    }
+  }
+
+  fn pass_manager_builder(&self) -> PassManagerBuilder {
+    let pass_manager_builder = PassManagerBuilder::create();
+    pass_manager_builder.set_optimization_level(self.llvm_level());
+    pass_manager_builder.set_size_level(if *self == OptLevel::Os { 1 } else { 0 });
+    // Listeners are only ever called from _update, so inlining them there is most of the win.
+    match self {
+      OptLevel::O0 => (),
+      OptLevel::O1 | OptLevel::Os => pass_manager_builder.set_inliner_with_threshold(75),
+      OptLevel::O2 => pass_manager_builder.set_inliner_with_threshold(225),
+      OptLevel::O3 => pass_manager_builder.set_inliner_with_threshold(275),
+    }
+    pass_manager_builder
+  }
+
+  fn pass_managers<'ctx>(&self, module: &Module<'ctx>) -> (PassManager<FunctionValue<'ctx>>, PassManager<Module<'ctx>>) {
+    let pass_manager_builder = self.pass_manager_builder();
+    let function_pass_manager = PassManager::create(module);
+    pass_manager_builder.populate_function_pass_manager(&function_pass_manager);
+    let module_pass_manager = PassManager::create(());
+    pass_manager_builder.populate_module_pass_manager(&module_pass_manager);
+    if *self != OptLevel::O0 {
+      module_pass_manager.add_global_dce_pass();
+    }
+    (function_pass_manager, module_pass_manager)
+  }
 }
 
 pub type CodegenStatus = Result<(), CodegenError>;
@@ -36,23 +110,28 @@ pub struct CodegenState<'ctx> {
   pub module: Module<'ctx>,
   pub builder: Builder<'ctx>,
   pub function_pass_manager: PassManager<FunctionValue<'ctx>>,
-  pub locals: HashMap<String, StatePointer<'ctx>>,
+  // run over the whole module once codegen for it is finished
+  pub module_pass_manager: PassManager<Module<'ctx>>,
+  // size_t on the target
+  pub size_type: IntType<'ctx>,
+  // One map per lexical scope; the innermost scope is last.
+  pub locals: Vec<HashMap<String, StatePointer<'ctx>>>,
   pub break_target: Vec<BasicBlock<'ctx>>,
-  pub considering: Option<&'ctx ExpressionValue>,
-  pub registered_strings: HashMap<String, PointerValue<'ctx>>
+  pub registered_strings: HashMap<String, PointerValue<'ctx>>,
+  // the name of the top-level module being compiled, which gets host callbacks
+  pub root_module: Option<String>,
 }
 
 impl <'ctx> CodegenState<'ctx> {
 
-  pub fn new(context: &'ctx Context, target_machine: &TargetMachine, target_triple: &TargetTriple, name: &str) -> CodegenState<'ctx> {
+  pub fn new(context: &'ctx Context, target_machine: &TargetMachine, target_triple: &TargetTriple, opt_level: OptLevel, name: &str) -> CodegenState<'ctx> {
     let module = context.create_module(name);
     module.set_data_layout(&target_machine.get_target_data().get_data_layout());
     module.set_triple(target_triple);
     let builder = context.create_builder();
-    let pass_manager_builder = PassManagerBuilder::create();
-    let function_pass_manager = PassManager::create(&module);
-    pass_manager_builder.populate_function_pass_manager(&function_pass_manager);
-    CodegenState { context, module, builder, function_pass_manager, locals: HashMap::new(), break_target: Vec::new(), considering: None, registered_strings: HashMap::new() }
+    let (function_pass_manager, module_pass_manager) = opt_level.pass_managers(&module);
+    let size_type = size_type_for(context, &module);
+    CodegenState { context, module, builder, function_pass_manager, module_pass_manager, size_type, locals: vec!(HashMap::new()), break_target: Vec::new(), registered_strings: HashMap::new(), root_module: None }
   }
 
   
@@ -64,27 +143,74 @@ impl <'ctx> CodegenState<'ctx> {
     self.context.i32_type().const_int(value as u64, false)
   }
 
-  pub fn add_local(&mut self, name: &str, value: StateValue<'ctx>) -> CodegenStatus {
-    if let Some(ptr) = self.locals.get(&name.to_string()) {
-      value.store(self, ptr)
-    } else {
-      let alloca = self.builder.build_alloca(value.llvm_type(), &("alloca_".to_string() + name));
-      let ptr = StatePointer::new_from_type_primitive(alloca, value.value_type.clone());
-      value.store(self, &ptr)?;
+  pub fn size_const(&self, value: u64) -> IntValue<'ctx> {
+    self.size_type.const_int(value, false)
+  }
+
+  pub fn has_host_callbacks(&self, module: &ast::Module) -> bool {
+    self.root_module.as_ref() == Some(&module.name)
+  }
+
+  pub fn push_scope(&mut self) {
+    self.locals.push(HashMap::new());
+  }
 
-      self.locals.insert(name.to_string(), ptr);
-      Ok(())
+  // The outermost (function-level) scope is never popped; use reset_locals to clear it.
+  pub fn pop_scope(&mut self) {
+    if self.locals.len() > 1 {
+      self.locals.pop();
     }
   }
 
+  // Locals are function-local, so this should be called before generating each function body.
+  pub fn reset_locals(&mut self) {
+    self.locals = vec!(HashMap::new());
+  }
+
+  // Declare a new local in the innermost scope. This shadows any local of the same name in an outer
+  // scope (or earlier in the same scope).
+  pub fn add_local(&mut self, name: &str, value: StateValue<'ctx>) -> CodegenStatus {
+    let alloca_type = llvm_type_for_primitive(self, &value.value_type);
+    let alloca = self.entry_block_alloca(alloca_type, &("alloca_".to_string() + name))?;
+    let ptr = StatePointer::new_from_type_primitive(alloca, value.value_type.clone());
+    value.store(self, &ptr)?;
+
+    self.locals.last_mut().unwrap().insert(name.to_string(), ptr);
+    Ok(())
+  }
+
+  // Assign to the innermost visible local with this name.
+  pub fn update_local(&self, name: &str, value: StateValue<'ctx>) -> CodegenStatus {
+    let ptr = self.find_local(name).ok_or_else(|| CodegenError::UndefinedLocal(name.to_string()))?;
+    value.store(self, ptr)
+  }
+
   pub fn get_local(&self, name: &str) -> Option<StateValue<'ctx>> {
-    if let Some(ptr) = self.locals.get(&name.to_string()) {
+    if let Some(ptr) = self.find_local(name) {
       Some(ptr.load(self, name).ok()?)
     } else {
       None
     }
   }
 
+  fn find_local(&self, name: &str) -> Option<&StatePointer<'ctx>> {
+    self.locals.iter().rev().find_map(|scope| scope.get(name))
+  }
+
+  // Allocas go at the top of the entry block, so that locals declared inside loops don't grow the
+  // stack on every iteration.
+  pub fn entry_block_alloca(&self, alloca_type: BasicTypeEnum<'ctx>, name: &str) -> CodegenResult<PointerValue<'ctx>> {
+    let block = self.builder.get_insert_block().ok_or(CodegenError::NotInABlock)?;
+    let function = block.get_parent().ok_or(CodegenError::NotInAFunction)?;
+    let entry = function.get_first_basic_block().ok_or(CodegenError::NotInABlock)?;
+    let entry_builder = self.context.create_builder();
+    match entry.get_first_instruction() {
+      Some(instruction) => entry_builder.position_before(&instruction),
+      None => entry_builder.position_at_end(entry),
+    }
+    Ok(entry_builder.build_alloca(alloca_type, name))
+  }
+
   pub fn char_ptr_type(&self) -> PointerType<'ctx> {
     self.context.i8_type().ptr_type(AddressSpace::Generic)
   }
@@ -98,6 +224,11 @@ impl <'ctx> CodegenState<'ctx> {
   }
 }
 
+fn size_type_for<'ctx>(context: &'ctx Context, module: &Module<'ctx>) -> IntType<'ctx> {
+  let target_data = TargetData::create(module.get_data_layout().as_str().to_str().unwrap());
+  context.ptr_sized_int_type(&target_data, None)
+}
+
 pub trait CodegenStateConstructor<'ctx> {
   fn construct(&mut self, context: &'ctx Context, name: &str) -> CodegenState<'ctx>;
 }
@@ -105,46 +236,59 @@ pub trait CodegenStateConstructor<'ctx> {
 pub struct TargetInfo<'a> {
   pub target_machine: &'a TargetMachine,
   pub target_triple: &'a TargetTriple,
+  pub opt_level: OptLevel,
 }
 
 impl <'a, 'ctx> CodegenStateConstructor<'ctx> for TargetInfo<'a> {
   fn construct(&mut self, context: &'ctx Context, name: &str) -> CodegenState<'ctx> {
-    CodegenState::new(context, self.target_machine, self.target_triple, name)
+    CodegenState::new(context, self.target_machine, self.target_triple, self.opt_level, name)
   }
 }
 
-pub mod tests {
-  use super::*;
-
-  use inkwell::execution_engine::{ExecutionEngine};
-  use inkwell::OptimizationLevel;
+// Builds modules into an execution engine on the host, for tests, examples and the repl.
+pub struct JitInfo<'ctx> {
+  pub execution_engine: Option<ExecutionEngine<'ctx>>,
+  pub opt_level: OptLevel,
+}
 
-  pub struct JitInfo<'ctx> {
-    pub execution_engine: Option<ExecutionEngine<'ctx>>,
+impl <'ctx> JitInfo<'ctx> {
+  pub fn new() -> JitInfo<'ctx> {
+    JitInfo::with_opt_level(OptLevel::default())
   }
 
-  impl <'ctx> JitInfo<'ctx> {
-    pub fn new() -> JitInfo<'ctx> {
-      JitInfo { execution_engine: None }
-    }
+  pub fn with_opt_level(opt_level: OptLevel) -> JitInfo<'ctx> {
+    Target::initialize_native(&InitializationConfig::default()).unwrap();
+    JitInfo { execution_engine: None, opt_level }
   }
+}
+
+extern "C" {
+  fn fflush(stream: *mut c_void) -> i32;
+}
 
-  impl <'ctx> CodegenStateConstructor<'ctx> for JitInfo<'ctx> {
-    fn construct(&mut self, context: &'ctx Context, name: &str) -> CodegenState<'ctx> {
-      let module = context.create_module(name);
-      match &self.execution_engine {
-        None => {
-          self.execution_engine = Some(module.create_jit_execution_engine(OptimizationLevel::None).unwrap());
-        }
-        Some(execution_engine) => {
-          execution_engine.add_module(&module).unwrap();
-        }
+// Code run through the JIT prints with C's stdio, which buffers separately from Rust's.
+pub fn flush_jit_output() {
+  unsafe { fflush(ptr::null_mut()); }
+}
+
+impl <'ctx> CodegenStateConstructor<'ctx> for JitInfo<'ctx> {
+  fn construct(&mut self, context: &'ctx Context, name: &str) -> CodegenState<'ctx> {
+    let module = context.create_module(name);
+    match &self.execution_engine {
+      None => {
+        self.execution_engine = Some(module.create_jit_execution_engine(self.opt_level.llvm_level()).unwrap());
+      }
+      Some(execution_engine) => {
+        execution_engine.add_module(&module).unwrap();
       }
-      let builder = context.create_builder();
-      let pass_manager_builder = PassManagerBuilder::create();
-      let function_pass_manager = PassManager::create(&module);
-      pass_manager_builder.populate_function_pass_manager(&function_pass_manager);
-      CodegenState { context, module, builder, function_pass_manager, locals: HashMap::new(), break_target: Vec::new(), considering: None, registered_strings: HashMap::new() }
     }
+    // The JIT runs on the host, so its modules share the host's layout.
+    let target_data = self.execution_engine.as_ref().unwrap().get_target_data();
+    module.set_data_layout(&target_data.get_data_layout());
+    module.set_triple(&TargetMachine::get_default_triple());
+    let builder = context.create_builder();
+    let (function_pass_manager, module_pass_manager) = self.opt_level.pass_managers(&module);
+    let size_type = size_type_for(context, &module);
+    CodegenState { context, module, builder, function_pass_manager, module_pass_manager, size_type, locals: vec!(HashMap::new()), break_target: Vec::new(), registered_strings: HashMap::new(), root_module: None }
   }
-}
\ No newline at end of file
+}
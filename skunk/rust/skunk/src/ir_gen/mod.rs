@@ -1,8 +1,10 @@
 pub mod codegen;
 pub mod codegen_state;
+pub mod c_header;
+pub mod rust_bindings;
 mod state_values;
 
 use super::ast;
 use codegen::{malloc, llvm_type_for_primitive};
 
-pub use codegen::{codegen, main_for_examples};
\ No newline at end of file
+pub use codegen::{codegen, main_for_examples, main_for_run};
\ No newline at end of file
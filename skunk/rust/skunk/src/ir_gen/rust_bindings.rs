@@ -0,0 +1,376 @@
+// Rust bindings for compiled modules (--emit rust-bindings).
+//
+// <Main>_bindings.rs is meant to be used as a module (`mod main_bindings;`). For each compiled module Echo it has
+// - EchoState, the #[repr(C)] state struct (see c_header for the layout)
+// - Echo, which owns an EchoState from Echo_init and frees it with Echo_deinit when dropped. It has
+//   set_<handle> (writes a pending update), get_<handle> (reads the current value), run_until_quiescent
+//   (calls Echo_run to run updates until none are pending) and dump. These go through the accessor functions
+//   (see accessor_codegen) rather than EchoState's fields. The top-level module's wrapper also has
+//   on_<output>(closure), which has the closure called with each new value of that output.
+// Setters for handles whose values point at memory are unsafe, as the state doesn't own that memory.
+//
+// <Main>_build.rs has link_skunk_objects(dir), for build.rs to link the objects the modules were compiled to,
+// along with libc, which they call into.
+
+use super::ast;
+use super::state_values::{TypePrimitive, type_primitive_for_type};
+use super::codegen::{compiled_modules, update_limit, is_by_value, RUN_NOT_CONVERGED};
+use super::c_header::c_identifier;
+
+use std::collections::HashSet;
+
+static KEYWORDS: &[&str] = &[
+  "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern", "false", "fn", "for",
+  "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static", "struct",
+  "trait", "true", "type", "unsafe", "use", "where", "while",
+];
+
+fn field_name(name: &str) -> String {
+  let name = c_identifier(name);
+  if KEYWORDS.contains(&name.as_str()) { format!("r#{}", name) } else { name }
+}
+
+// Pair -> Pair, split0.low -> Split0Low
+fn camel_case(name: &str) -> String {
+  name.split(|c: char| !c.is_ascii_alphanumeric()).map(|part| {
+    let mut chars = part.chars();
+    match chars.next() {
+      Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+      None => String::new(),
+    }
+  }).collect()
+}
+
+fn state_type_name(module: &ast::Module) -> String {
+  format!("{}State", camel_case(&module.name))
+}
+
+struct RustType {
+  name: String,
+  // whether values of this type point at memory
+  points: bool,
+}
+
+// The Rust type for a primitive, adding #[repr(C)] structs for tuples to lines. Tuple structs are named
+// after the handle they belong to, with the member index appended for nested tuples.
+fn rust_type_for_primitive(lines: &mut Vec<String>, type_name: &str, primitive_type: &Vec<TypePrimitive>) -> RustType {
+  if primitive_type.len() != 1 {
+    return tuple_struct(lines, type_name, primitive_type);
+  }
+  match &primitive_type[0] {
+    TypePrimitive::Int => RustType { name: "i64".to_string(), points: false },
+    TypePrimitive::Char => RustType { name: "u8".to_string(), points: false },
+    TypePrimitive::Bool => RustType { name: "bool".to_string(), points: false },
+    TypePrimitive::MemRegion => RustType { name: "MemRegion".to_string(), points: true },
+    TypePrimitive::DynamicArrayOf(x) => {
+      let element = rust_type_for_primitive(lines, type_name, x);
+      RustType { name: format!("DynamicArray<{}>", element.name), points: true }
+    }
+    TypePrimitive::PointerTo(x) => {
+      let target = tuple_struct(lines, type_name, x);
+      RustType { name: format!("*mut {}", target.name), points: true }
+    }
+    TypePrimitive::FixedArrayOf(x, _s) => {
+      let element = rust_type_for_primitive(lines, type_name, x);
+      RustType { name: format!("*mut {}", element.name), points: true }
+    }
+    TypePrimitive::TupleOf(x) => tuple_struct(lines, type_name, x),
+  }
+}
+
+fn tuple_struct(lines: &mut Vec<String>, type_name: &str, members: &Vec<TypePrimitive>) -> RustType {
+  let mut points = false;
+  let mut member_types = Vec::new();
+  for (idx, member) in members.iter().enumerate() {
+    let member_type = rust_type_for_primitive(lines, &format!("{}{}", type_name, idx), &vec!(member.clone()));
+    points |= member_type.points;
+    member_types.push(format!("pub {}", member_type.name));
+  }
+  lines.push("#[repr(C)]".to_string());
+  lines.push("#[derive(Clone, Copy, Debug, PartialEq)]".to_string());
+  lines.push(format!("pub struct {}({});", type_name, member_types.join(", ")));
+  lines.push(String::new());
+  RustType { name: type_name.to_string(), points }
+}
+
+fn field_type(lines: &mut Vec<String>, module: &ast::Module, name: &str, field_type: &ast::Type) -> RustType {
+  let type_name = camel_case(&module.name) + &camel_case(name);
+  rust_type_for_primitive(lines, &type_name, &type_primitive_for_type(field_type))
+}
+
+// What callbacks for a handle are passed: the value itself, or a pointer to it.
+fn callback_value_type(rust_type: &RustType, by_value: bool) -> String {
+  if by_value { rust_type.name.clone() } else { format!("*const {}", rust_type.name) }
+}
+
+fn callback_type(rust_type: &RustType, by_value: bool) -> String {
+  format!("Option<unsafe extern \"C\" fn(*mut std::ffi::c_void, {})>", callback_value_type(rust_type, by_value))
+}
+
+// Only the top-level module (root) has callbacks.
+fn state_struct(lines: &mut Vec<String>, defined: &mut HashSet<String>, module: &ast::Module, root: bool) {
+  if defined.contains(&module.name) {
+    return;
+  }
+  defined.insert(module.name.clone());
+  for submodule in &module.submodules {
+    state_struct(lines, defined, &submodule.module, false);
+  }
+
+  let mut fields = Vec::new();
+  for handle in &module.handles {
+    let rust_type = field_type(lines, module, &handle.name, &handle.h_type);
+    let name = c_identifier(&handle.name);
+    fields.push(format!("  pub {}: {},", field_name(&name), rust_type.name));
+    fields.push(format!("  pub {}_upd: {},", name, rust_type.name));
+  }
+  fields.push("  // bit n is set when handle n has a pending update".to_string());
+  fields.push("  pub bitfield: u64,".to_string());
+  if module.tuples.len() > 0 {
+    fields.push("  pub tuple_bitfield: u64,".to_string());
+  }
+  for param in &module.value_params {
+    let rust_type = field_type(lines, module, &param.name, &param.vp_type);
+    fields.push(format!("  pub {}: {},", field_name(&param.name), rust_type.name));
+  }
+  for (idx, submodule) in module.submodules.iter().enumerate() {
+    fields.push(format!("  pub sub{}: {},", idx, state_type_name(&submodule.module)));
+  }
+  if root {
+    for handle in module.outputs() {
+      let rust_type = field_type(&mut Vec::new(), module, &handle.name, &handle.h_type);
+      let by_value = is_by_value(&type_primitive_for_type(&handle.h_type));
+      let name = c_identifier(&handle.name);
+      fields.push(format!("  pub on_{}: {},", name, callback_type(&rust_type, by_value)));
+      fields.push(format!("  pub on_{}_userdata: *mut std::ffi::c_void,", name));
+    }
+  }
+
+  lines.push("#[repr(C)]".to_string());
+  lines.push("#[derive(Debug)]".to_string());
+  lines.push(format!("pub struct {} {{", state_type_name(module)));
+  lines.append(&mut fields);
+  lines.push("}".to_string());
+  lines.push(String::new());
+}
+
+fn wrapper(lines: &mut Vec<String>, module: &ast::Module, root: bool) {
+  let name = c_identifier(&module.name);
+  let wrapper_type = camel_case(&module.name);
+  let state_type = state_type_name(module);
+
+  let mut scratch = Vec::new();
+  let handle_types: Vec<(String, RustType, bool)> = module.handles.iter().map(|handle| {
+    let rust_type = field_type(&mut scratch, module, &handle.name, &handle.h_type);
+    (c_identifier(&handle.name), rust_type, is_by_value(&type_primitive_for_type(&handle.h_type)))
+  }).collect();
+  let outputs: Vec<&(String, RustType, bool)> = if root {
+    module.handles.iter().zip(handle_types.iter()).filter(|(handle, _)| handle.is_output()).map(|(_, handle_type)| handle_type).collect()
+  } else {
+    Vec::new()
+  };
+
+  lines.push("extern \"C\" {".to_string());
+  lines.push(format!("  fn {}_init() -> *mut {};", name, state_type));
+  lines.push(format!("  fn {}_deinit(state: *mut {});", name, state_type));
+  lines.push(format!("  fn {}_update(state: *mut {});", name, state_type));
+  lines.push(format!("  fn {}__dump(state: *mut {});", name, state_type));
+  lines.push(format!("  fn {}_run(state: *mut {}, max_steps: u64) -> i64;", name, state_type));
+  lines.push(format!("  fn {}_has_pending(state: *mut {}) -> bool;", name, state_type));
+  for (handle_name, rust_type, by_value) in &handle_types {
+    if *by_value {
+      lines.push(format!("  fn {}_read_{}(state: *mut {}) -> {};", name, handle_name, state_type, rust_type.name));
+      lines.push(format!("  fn {}_write_{}(state: *mut {}, value: {});", name, handle_name, state_type, rust_type.name));
+    } else {
+      lines.push(format!("  fn {}_read_{}(state: *mut {}, value: *mut {});", name, handle_name, state_type, rust_type.name));
+      lines.push(format!("  fn {}_write_{}(state: *mut {}, value: *const {});", name, handle_name, state_type, rust_type.name));
+    }
+  }
+  for (handle_name, rust_type, by_value) in &outputs {
+    lines.push(format!("  fn {}_on_{}(state: *mut {}, callback: {}, userdata: *mut std::ffi::c_void);", name, handle_name, state_type, callback_type(rust_type, *by_value)));
+  }
+  lines.push("}".to_string());
+  lines.push(String::new());
+
+  lines.push(format!("pub struct {} {{", wrapper_type));
+  lines.push(format!("  state: *mut {},", state_type));
+  lines.push("  // closures registered with on_<handle>, kept alive for as long as the state".to_string());
+  lines.push("  callbacks: Vec<Box<dyn std::any::Any>>,".to_string());
+  lines.push("}".to_string());
+  lines.push(String::new());
+
+  lines.push(format!("impl {} {{", wrapper_type));
+  lines.push(format!("  pub const UPDATE_LIMIT: u64 = {};", update_limit(module)));
+  lines.push(String::new());
+  lines.push("  pub fn new() -> Self {".to_string());
+  lines.push(format!("    {} {{ state: unsafe {{ {}_init() }}, callbacks: Vec::new() }}", wrapper_type, name));
+  lines.push("  }".to_string());
+  lines.push(String::new());
+  lines.push(format!("  pub fn state(&self) -> &{} {{", state_type));
+  lines.push("    unsafe { &*self.state }".to_string());
+  lines.push("  }".to_string());
+  lines.push(String::new());
+  lines.push(format!("  pub unsafe fn state_mut(&mut self) -> &mut {} {{", state_type));
+  lines.push("    &mut *self.state".to_string());
+  lines.push("  }".to_string());
+
+  for (handle_name, rust_type, by_value) in &handle_types {
+    let unsafety = if rust_type.points { "unsafe " } else { "" };
+    lines.push(String::new());
+    lines.push(format!("  pub {}fn set_{}(&mut self, value: {}) {{", unsafety, handle_name, rust_type.name));
+    if *by_value {
+      lines.push(format!("    unsafe {{ {}_write_{}(self.state, value) }}", name, handle_name));
+    } else {
+      lines.push(format!("    unsafe {{ {}_write_{}(self.state, &value) }}", name, handle_name));
+    }
+    lines.push("  }".to_string());
+    lines.push(String::new());
+    lines.push(format!("  pub fn get_{}(&self) -> {} {{", handle_name, rust_type.name));
+    if *by_value {
+      lines.push(format!("    unsafe {{ {}_read_{}(self.state) }}", name, handle_name));
+    } else {
+      lines.push("    let mut value = std::mem::MaybeUninit::uninit();".to_string());
+      lines.push("    unsafe {".to_string());
+      lines.push(format!("      {}_read_{}(self.state, value.as_mut_ptr());", name, handle_name));
+      lines.push("      value.assume_init()".to_string());
+      lines.push("    }".to_string());
+    }
+    lines.push("  }".to_string());
+  }
+
+  for (handle_name, rust_type, by_value) in &outputs {
+    let (argument, call) = if *by_value { (rust_type.name.clone(), "value") } else { (format!("&{}", rust_type.name), "&*value") };
+    lines.push(String::new());
+    lines.push(format!("  // Calls callback with each new value of {}.", handle_name));
+    lines.push(format!("  pub fn on_{}<F: FnMut({}) + 'static>(&mut self, callback: F) {{", handle_name, argument));
+    lines.push(format!("    unsafe extern \"C\" fn trampoline<F: FnMut({})>(userdata: *mut std::ffi::c_void, value: {}) {{", argument, callback_value_type(rust_type, *by_value)));
+    lines.push(format!("      (*(userdata as *mut F))({})", call));
+    lines.push("    }".to_string());
+    lines.push("    let mut callback = Box::new(callback);".to_string());
+    lines.push("    let userdata = &mut *callback as *mut F as *mut std::ffi::c_void;".to_string());
+    lines.push(format!("    unsafe {{ {}_on_{}(self.state, Some(trampoline::<F>), userdata) }};", name, handle_name));
+    lines.push("    self.callbacks.push(callback);".to_string());
+    lines.push("  }".to_string());
+  }
+
+  lines.push(String::new());
+  lines.push("  // Runs updates until none are pending, returning how many it took.".to_string());
+  lines.push("  pub fn run_until_quiescent(&mut self) -> Result<u64, NotQuiescent> {".to_string());
+  lines.push("    self.run(Self::UPDATE_LIMIT)".to_string());
+  lines.push("  }".to_string());
+  lines.push(String::new());
+  lines.push("  // Like run_until_quiescent, but gives up after max_steps updates (UPDATE_LIMIT if it's 0).".to_string());
+  lines.push("  pub fn run(&mut self, max_steps: u64) -> Result<u64, NotQuiescent> {".to_string());
+  lines.push("    // as _run does, so NotQuiescent reports the limit that was actually used".to_string());
+  lines.push("    let max_steps = if max_steps == 0 { Self::UPDATE_LIMIT } else { max_steps };".to_string());
+  lines.push(format!("    match unsafe {{ {}_run(self.state, max_steps) }} {{", name));
+  lines.push(format!("      {} => Err(NotQuiescent {{ module: \"{}\", steps: max_steps }}),", RUN_NOT_CONVERGED, module.name));
+  lines.push("      steps => Ok(steps as u64),".to_string());
+  lines.push("    }".to_string());
+  lines.push("  }".to_string());
+  lines.push(String::new());
+  lines.push("  pub fn dump(&self) {".to_string());
+  lines.push(format!("    unsafe {{ {}__dump(self.state) }}", name));
+  lines.push("  }".to_string());
+  lines.push("}".to_string());
+  lines.push(String::new());
+
+  lines.push(format!("impl Drop for {} {{", wrapper_type));
+  lines.push("  fn drop(&mut self) {".to_string());
+  lines.push(format!("    unsafe {{ {}_deinit(self.state) }}", name));
+  lines.push("  }".to_string());
+  lines.push("}".to_string());
+  lines.push(String::new());
+}
+
+pub fn rust_bindings(module: &ast::Module) -> String {
+  let mut lines = vec!(
+    format!("// Generated by skunk for module {}. Do not edit.", module.name),
+    "#![allow(dead_code, non_camel_case_types)]".to_string(),
+    String::new(),
+    "#[repr(C)]".to_string(),
+    "#[derive(Clone, Copy, Debug, PartialEq)]".to_string(),
+    "pub struct DynamicArray<T> {".to_string(),
+    "  pub data: *mut T,".to_string(),
+    "  pub size: i64,".to_string(),
+    "}".to_string(),
+    String::new(),
+    "pub type MemRegion = DynamicArray<u8>;".to_string(),
+    String::new(),
+    "#[derive(Debug)]".to_string(),
+    "pub struct NotQuiescent {".to_string(),
+    "  pub module: &'static str,".to_string(),
+    "  pub steps: u64,".to_string(),
+    "}".to_string(),
+    String::new(),
+  );
+  let mut defined = HashSet::new();
+  for (idx, compiled) in compiled_modules(module).into_iter().enumerate() {
+    state_struct(&mut lines, &mut defined, compiled, idx == 0);
+    wrapper(&mut lines, compiled, idx == 0);
+  }
+  lines.join("\n")
+}
+
+// cargo only passes rustc-link-arg on to binaries, so the objects are archived into OUT_DIR and linked as a
+// static library, which works for lib crates too.
+pub fn build_helper(module: &ast::Module) -> String {
+  let objects: Vec<String> = compiled_modules(module).iter().map(|compiled| format!("\"{}.o\"", compiled.name)).collect();
+  let library = format!("skunk_{}", c_identifier(&module.name));
+  let lines = vec!(
+    format!("// Generated by skunk for module {}. Do not edit.", module.name),
+    "// Call link_skunk_objects from build.rs with the directory skunk wrote its objects to.".to_string(),
+    String::new(),
+    "pub fn link_skunk_objects(dir: &str) {".to_string(),
+    "  let out_dir = std::env::var(\"OUT_DIR\").expect(\"link_skunk_objects must be called from build.rs\");".to_string(),
+    format!("  let archive = std::path::Path::new(&out_dir).join(\"lib{}.a\");", library),
+    "  let _ = std::fs::remove_file(&archive);".to_string(),
+    "  let mut ar = std::process::Command::new(\"ar\");".to_string(),
+    "  ar.arg(\"crs\").arg(&archive);".to_string(),
+    format!("  for object in &[{}] {{", objects.join(", ")),
+    "    let path = std::path::Path::new(dir).join(object);".to_string(),
+    "    println!(\"cargo:rerun-if-changed={}\", path.display());".to_string(),
+    "    ar.arg(path);".to_string(),
+    "  }".to_string(),
+    "  let status = ar.status().expect(\"Can't run ar\");".to_string(),
+    "  assert!(status.success(), \"ar failed to archive the skunk objects\");".to_string(),
+    "  println!(\"cargo:rustc-link-search=native={}\", out_dir);".to_string(),
+    format!("  println!(\"cargo:rustc-link-lib=static={}\");", library),
+    "  // the objects call printf, malloc and friends".to_string(),
+    "  println!(\"cargo:rustc-link-lib=dylib=c\");".to_string(),
+    "}".to_string(),
+  );
+  lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::super::parser;
+
+  #[test]
+  fn bindings_wrap_each_module() {
+    let (_, ast) = parser::parse("module Pairer {\n  input: reads Int;\n  pair: writes (Int, Char);\n  name: writes String;\n}").unwrap();
+    let module = ast::modules(&ast)[0];
+    let bindings = rust_bindings(module);
+    assert!(bindings.contains("pub struct PairerPair(pub i64, pub u8);"));
+    assert!(bindings.contains(
+      "pub struct PairerState {\n  pub input: i64,\n  pub input_upd: i64,\n  pub pair: PairerPair,\n  pub pair_upd: PairerPair,\n  pub name: DynamicArray<u8>,\n"
+    ));
+    assert!(bindings.contains("  fn Pairer_read_input(state: *mut PairerState) -> i64;"));
+    assert!(bindings.contains("  pub fn set_pair(&mut self, value: PairerPair) {\n    unsafe { Pairer_write_pair(self.state, &value) }"));
+    assert!(bindings.contains("  pub unsafe fn set_name(&mut self, value: DynamicArray<u8>) {"));
+    assert!(bindings.contains("  pub fn get_input(&self) -> i64 {"));
+    assert!(bindings.contains("    unsafe { Pairer_deinit(self.state) }"));
+    assert!(bindings.contains("  pub on_pair: Option<unsafe extern \"C\" fn(*mut std::ffi::c_void, *const PairerPair)>,"));
+    assert!(bindings.contains("  pub fn on_pair<F: FnMut(&PairerPair) + 'static>(&mut self, callback: F) {"));
+    assert!(!bindings.contains("fn on_input"));
+    assert!(bindings.contains("    let max_steps = if max_steps == 0 { Self::UPDATE_LIMIT } else { max_steps };\n    match unsafe { Pairer_run(self.state, max_steps) } {"));
+
+    let helper = build_helper(module);
+    assert!(helper.contains("for object in &[\"Pairer.o\"] {"));
+    assert!(helper.contains("  println!(\"cargo:rustc-link-lib=static=skunk_Pairer\");"));
+    assert!(helper.contains("  println!(\"cargo:rustc-link-lib=dylib=c\");"));
+    assert!(!helper.contains("rustc-link-arg"));
+  }
+}
@@ -46,18 +46,23 @@ pub fn memregion_size_ptr<'ctx>(cg: &CodegenState<'ctx>, ptr: PointerValue<'ctx>
 // be canonicalized into a known order, to maximize compatibility.
 //
 // What do we want to represent?
-// (1) values that are directly inline. This should just be a Vec<TypePrimitive>
-//    i.e. vec!(Int, Char, Char) represents 2 contiguous words in memory
+// (1) values that are directly inline. Inline aggregates are a TupleOf(Vec<TypePrimitive>)
+//    i.e. TupleOf(vec!(Int, Char, Char)) represents 2 contiguous words in memory
 // (2) pointers to regions of a known size. These are themselves a known size (1 word) and so can be directly inlined.
 //      (a) directly inlined values.
 //      (b) arrays of known size of direcly inlined values.
 // (3) pointers to runtime-sized regions. These are also a known size (2 words) and so can be directly inlined.
 // (4) MemRegions (as a special bottoming-out pointer-to-region-of-known-size primitive where more type information isn't available).
 //
-// So (Int, String) is vec!(Int, DynamicArrayOf(vec!(Char)))
+// Tuples follow a single layout rule (see tuple_type_primitive): every member is lowered to exactly one
+// TypePrimitive, and the tuple itself is stored inline (TupleOf) when its members fit in 16 bytes, or
+// behind a pointer (PointerTo) otherwise. Because members are lowered by the same rule, nesting is preserved.
+//
+// So (Int, String) is vec!(PointerTo(vec!(Int, DynamicArrayOf(vec!(Char))))),
+// (Int, Int) is vec!(TupleOf(vec!(Int, Int))), and ((Char, Char), Int) is vec!(TupleOf(vec!(TupleOf(vec!(Char, Char)), Int)))
 #[derive(Clone, Debug, PartialEq)]
 pub enum TypePrimitive {
-  Int, Char, Bool, MemRegion, PointerTo(Vec<TypePrimitive>), FixedArrayOf(Vec<TypePrimitive>, u64), DynamicArrayOf(Vec<TypePrimitive>)
+  Int, Char, Bool, MemRegion, PointerTo(Vec<TypePrimitive>), FixedArrayOf(Vec<TypePrimitive>, u64), DynamicArrayOf(Vec<TypePrimitive>), TupleOf(Vec<TypePrimitive>)
 }
 
 // PointerKind describes operationally how a pointer should be treated. This includes an understanding of how to move values
@@ -75,19 +80,59 @@ pub enum PointerKind {
   ConstPointer
 }
 
+// Size in bytes of a sequence of primitives laid out as an LLVM struct (i.e. including alignment padding).
+// This assumes 64-bit pointers whatever the target, so that the tuple layout rule doesn't change between
+// targets; anything that allocates memory should ask LLVM for the real size instead.
 pub fn type_size(type_vec: &Vec<TypePrimitive>) -> u64 {
   let mut size = 0;
   for h_type in type_vec {
+    size = align_to(size, primitive_align(h_type));
     size += match h_type {
       TypePrimitive::Int => 8,
       TypePrimitive::Char | TypePrimitive::Bool => 1,
       TypePrimitive::MemRegion => 16,
       TypePrimitive::PointerTo(_x) => 8,
       TypePrimitive::FixedArrayOf(_x, _s) => 8,
-      TypePrimitive::DynamicArrayOf(_x) => 16
+      TypePrimitive::DynamicArrayOf(_x) => 16,
+      TypePrimitive::TupleOf(x) => type_size(x),
     };
   }
-  size
+  align_to(size, type_align(type_vec))
+}
+
+pub fn type_align(type_vec: &Vec<TypePrimitive>) -> u64 {
+  type_vec.iter().map(primitive_align).max().unwrap_or(1)
+}
+
+fn primitive_align(primitive: &TypePrimitive) -> u64 {
+  match primitive {
+    TypePrimitive::Char | TypePrimitive::Bool => 1,
+    TypePrimitive::TupleOf(x) => type_align(x),
+    _ => 8
+  }
+}
+
+fn align_to(size: u64, align: u64) -> u64 {
+  (size + align - 1) / align * align
+}
+
+// The tuple layout rule. members must already be lowered (one TypePrimitive per tuple member).
+pub fn tuple_type_primitive(members: Vec<TypePrimitive>) -> Vec<TypePrimitive> {
+  if type_size(&members) <= 16 {
+    vec!(TypePrimitive::TupleOf(members))
+  } else {
+    vec!(TypePrimitive::PointerTo(members))
+  }
+}
+
+// Returns the member primitives of a tuple, regardless of whether it is stored inline or behind a pointer.
+pub fn tuple_members(value_type: &Vec<TypePrimitive>) -> CodegenResult<&Vec<TypePrimitive>> {
+  if value_type.len() == 1 {
+    if let TypePrimitive::TupleOf(members) | TypePrimitive::PointerTo(members) = &value_type[0] {
+      return Ok(members);
+    }
+  }
+  Err(CodegenError::TypeMismatch(format!("{:?} is not a tuple type", value_type)))
 }
 
 #[derive(Debug)]
@@ -105,14 +150,7 @@ pub fn type_primitive_for_type(h_type: &ast::Type) -> Vec<TypePrimitive> {
     ast::Type::MemRegion => vec!(TypePrimitive::MemRegion),
     ast::Type::String => vec!(TypePrimitive::DynamicArrayOf(vec!(TypePrimitive::Char))),
     ast::Type::NewType(_, t) => type_primitive_for_type(t),
-    ast::Type::Tuple(members) => {
-      let member_vec = members.iter().map(|t| type_primitive_for_type(t)).flatten().collect();
-      if type_size(&member_vec) <= 16 {
-        member_vec
-      } else {
-        vec!(TypePrimitive::PointerTo(member_vec))
-      }
-    },
+    ast::Type::Tuple(members) => tuple_type_primitive(members.iter().map(|t| type_primitive_for_type(t)).flatten().collect()),
     ast::Type::Unresolved => panic!("shouldn't be seeing unresolved types here"),
     _ => todo!("Need a type primitive for {:?}", h_type)
   }
@@ -124,6 +162,7 @@ pub fn pointer_kind_for_type_primitive(primitive: &Vec<TypePrimitive>) -> Pointe
   }
   match &primitive[0] {
     TypePrimitive::Int | TypePrimitive::Char | TypePrimitive::Bool => PointerKind::SimplePrimitive,
+    TypePrimitive::TupleOf(_x) => PointerKind::CompoundPrimitive,
     TypePrimitive::MemRegion => PointerKind::DynamicMemRegion,
     TypePrimitive::DynamicArrayOf(_x) => PointerKind::DynamicMemRegion,
     TypePrimitive::FixedArrayOf(_x, _s) => PointerKind::StaticMemRegion,
@@ -177,13 +216,7 @@ impl <'ctx> StatePointer<'ctx> {
       }
       PointerKind::CompoundPrimitive => {
         let ptr = self.pointer.into_pointer_value();
-        let mut pos = 0;
-        for sub_type in &self.pointer_type {
-          let sub_ptr = cg.builder.build_struct_gep(ptr, pos, &format!("sub_ptr{}", pos)).or(Err(CodegenError::InvalidStructPointer("weirdness deconstructing compound primitive".to_string())))?;
-          let sub_state_ptr = StatePointer::new_from_type_primitive(sub_ptr, vec!(sub_type.clone()));
-          sub_state_ptr.clear_update_pointer(cg)?;
-          pos += 1;
-        }
+        cg.builder.build_store(ptr, ptr.get_type().get_element_type().into_struct_type().const_zero());
         Ok(())
       }
       PointerKind::StaticMemRegion => {
@@ -201,13 +234,13 @@ impl <'ctx> StatePointer<'ctx> {
   }
 
   pub fn get_element_pointer(&self, cg: &CodegenState<'ctx>, idx: usize) -> CodegenResult<StatePointer<'ctx>> {
-    if self.pointer_type.len() == 1 {
+    if self.pointer_kind == PointerKind::CompoundPrimitive {
+      let members = tuple_members(&self.pointer_type)?;
+      let ptr = cg.builder.build_struct_gep(self.pointer.into_pointer_value(), idx as u32, "ptr").or_else(|_| Err(CodegenError::InvalidTupleID(idx)))?;
+      Ok(Self::new_from_type_primitive(ptr, vec!(members[idx].clone())))
+    } else {
       let value = self.load(cg, "value")?;
       value.tuple_index_ptr(cg, idx as u32)
-    } else {
-      let ptr = cg.builder.build_struct_gep(self.pointer.into_pointer_value(), idx as u32, "ptr").or_else(|_| Err(CodegenError::InvalidTupleID(idx)))?;
-      let ptr_type = self.pointer_type[idx].clone();
-      Ok(Self::new_from_type_primitive(ptr, vec!(ptr_type)))
     }
   }
 
@@ -265,12 +298,12 @@ impl <'ctx> StateValue<'ctx> {
   }
   pub fn new_tuple(cg: &CodegenState<'ctx>, tuple_type: Vec<TypePrimitive>) -> CodegenResult<Self> {
     if let TypePrimitive::PointerTo(members) = &tuple_type[0] {
-      let tuple_size = type_size(&members);
-      let tuple_ptr = super::malloc(cg, cg.uint32_const(tuple_size as u32).into(), "tuple_memory").into_pointer_value();
       let tuple_llvm_type = super::llvm_type_for_primitive(cg, &tuple_type);
+      let tuple_size = tuple_llvm_type.into_pointer_type().get_element_type().into_struct_type().size_of().unwrap();
+      let tuple_ptr = super::malloc(cg, tuple_size, "tuple_memory").into_pointer_value();
       let typed_tuple_ptr = cg.builder.build_bitcast(tuple_ptr, tuple_llvm_type, "ptr_as_struct_ptr").into_pointer_value();
       Ok(StateValue::new_static_mem_region_of_type(typed_tuple_ptr, tuple_type))
-    } else if tuple_type.len() > 1 {
+    } else if let TypePrimitive::TupleOf(_members) = &tuple_type[0] {
       let struct_type = llvm_type_for_primitive(cg, &tuple_type);
       let struct_value = struct_type.const_zero();
       Ok(StateValue { value: ValueParts::CompoundPrimitive(struct_value.into_struct_value()), value_type: tuple_type })
@@ -300,6 +333,36 @@ impl <'ctx> StateValue<'ctx> {
     }
   }
 
+  // Produce a single LLVM value for this StateValue, e.g. for insertion into an inline tuple.
+  pub fn into_basic_value(&self, cg: &CodegenState<'ctx>) -> CodegenResult<BasicValueEnum<'ctx>> {
+    match self.value {
+      ValueParts::SimplePrimitive(v) => Ok(v),
+      ValueParts::CompoundPrimitive(v) => Ok(v.into()),
+      ValueParts::StaticMemRegion(ptr) => Ok(ptr.into()),
+      ValueParts::DynamicMemRegion(data, size) => {
+        let struct_type = llvm_type_for_primitive(cg, &self.value_type).into_struct_type();
+        let with_data = cg.builder.build_insert_value(struct_type.get_undef(), data, 0, "with_data").unwrap().into_struct_value();
+        Ok(cg.builder.build_insert_value(with_data, size, 1, "with_size").unwrap().into_struct_value().into())
+      }
+      ValueParts::None | ValueParts::Suppress => Err(CodegenError::TypeMismatch("Can't convert a None value into an LLVM value".to_string())),
+    }
+  }
+
+  // The inverse of into_basic_value.
+  pub fn from_basic_value(cg: &CodegenState<'ctx>, value: BasicValueEnum<'ctx>, value_type: Vec<TypePrimitive>) -> CodegenResult<Self> {
+    match pointer_kind_for_type_primitive(&value_type) {
+      PointerKind::SimplePrimitive => Ok(StateValue::new_prim_of_type(value, value_type)),
+      PointerKind::CompoundPrimitive => Ok(StateValue { value: ValueParts::CompoundPrimitive(value.into_struct_value()), value_type }),
+      PointerKind::StaticMemRegion => Ok(StateValue::new_static_mem_region_of_type(value.into_pointer_value(), value_type)),
+      PointerKind::DynamicMemRegion => {
+        let data = cg.builder.build_extract_value(value.into_struct_value(), 0, "data").unwrap().into_pointer_value();
+        let size = cg.builder.build_extract_value(value.into_struct_value(), 1, "size").unwrap().into_int_value();
+        Ok(StateValue::new_dynamic_mem_region_of_type(data, size, value_type))
+      }
+      PointerKind::ConstPointer => Err(CodegenError::TypeMismatch("Can't construct a StateValue from a const pointer".to_string())),
+    }
+  }
+
   pub fn llvm_type(&self) -> BasicTypeEnum<'ctx> {
     match self.value {
       ValueParts::SimplePrimitive(value) => value.get_type(),
@@ -347,7 +410,7 @@ impl <'ctx> StateValue<'ctx> {
         let value_type = self.only_value_type()?;
         match value_type {
           TypePrimitive::Int => {
-            printer.printf(cg, "[Int %ld]", &[v])
+            printer.printf(cg, "[Int %lld]", &[v])
           }
           TypePrimitive::Bool => {
             printer.printf(cg, "[Bool %d]", &[v])
@@ -359,9 +422,9 @@ impl <'ctx> StateValue<'ctx> {
           _ => todo!("Implement debug for {:?}", value_type)
         }
       }
-      ValueParts::CompoundPrimitive(v) => {
+      ValueParts::CompoundPrimitive(_v) => {
         printer.open_bracket(cg, "(")?;
-        for type_idx in 0..self.value_type.len() {
+        for type_idx in 0..tuple_members(&self.value_type)?.len() {
           let value = self.get_tuple_index(cg, type_idx as u32).unwrap();
           value.debug(cg, printer)?;
           printer.sep(cg, ",")?;
@@ -481,8 +544,11 @@ impl <'ctx> StateValue<'ctx> {
   }
   pub fn set_tuple_index(&mut self, cg: &CodegenState<'ctx>, index: u32, value: StateValue<'ctx>) -> CodegenStatus {
     if let ValueParts::CompoundPrimitive(v) = self.value {
-      // TODO: This only works for SimplePrimitives, make it work for everything..
-      let val = value.into_int_value().unwrap();
+      let member_type = vec!(tuple_members(&self.value_type)?[index as usize].clone());
+      if member_type != value.value_type {
+        return Err(CodegenError::TypeMismatch(format!("Attempt to set tuple member of type {:?} to value of type {:?}", member_type, value.value_type)));
+      }
+      let val = value.into_basic_value(cg)?;
       self.value = ValueParts::CompoundPrimitive(cg.builder.build_insert_value(v, val, index, "insert_value").unwrap().into_struct_value());
       Ok(())
     } else {
@@ -492,8 +558,9 @@ impl <'ctx> StateValue<'ctx> {
   }
   pub fn get_tuple_index(&self, cg: &CodegenState<'ctx>, index: u32) -> CodegenResult<StateValue<'ctx>> {
     if let ValueParts::CompoundPrimitive(v) = self.value {
-      let value = cg.builder.build_extract_value(v, index, "value").unwrap();
-      Ok(StateValue::new_prim_of_type(value, vec!(self.value_type[index as usize].clone())))
+      let member_type = vec!(tuple_members(&self.value_type)?[index as usize].clone());
+      let value = cg.builder.build_extract_value(v, index, "value").ok_or(CodegenError::InvalidIndex)?;
+      StateValue::from_basic_value(cg, value, member_type)
     } else {
       let typed_ptr = self.tuple_index_ptr(cg, index)?;
       typed_ptr.load(cg, "tuple_at_idx")
@@ -509,21 +576,21 @@ impl <'ctx> StateValue<'ctx> {
     Ok(())
   }
   pub fn equals(&self, cg: &mut CodegenState<'ctx>, other: &StateValue<'ctx>) -> CodegenResult<StateValue<'ctx>> {
-    if self.value_type.len() > 1 {
-      if self.value_type.len() != other.value_type.len() {
-        panic!("can't compare a {:?} with a {:?}", self.value_type, other.value_type);
-      }
-      let mut result = StateValue::new_bool(cg.context.bool_type().const_int(1, false));
-      for idx in 0..self.value_type.len() {
-        let lhs = self.get_tuple_index(cg, idx as u32)?;
-        let rhs = other.get_tuple_index(cg, idx as u32)?;
-        result = expression_logical_and(cg, move |_cg| Ok(result.clone()), |cg| lhs.equals(cg, &rhs))?;
-      }
-      return Ok(result);
-    }
     let value_type = self.only_value_type()?;
     match value_type {
       TypePrimitive::Char | TypePrimitive::Int | TypePrimitive::Bool => self.apply_int_predicate(cg, IntPredicate::EQ, other),
+      TypePrimitive::TupleOf(members) => {
+        if self.value_type != other.value_type {
+          return Err(CodegenError::TypeMismatch(format!("Can't compare a {:?} with a {:?}", self.value_type, other.value_type)));
+        }
+        let mut result = StateValue::new_true(cg);
+        for idx in 0..members.len() {
+          let lhs = self.get_tuple_index(cg, idx as u32)?;
+          let rhs = other.get_tuple_index(cg, idx as u32)?;
+          result = expression_logical_and(cg, move |_cg| Ok(result.clone()), |cg| lhs.equals(cg, &rhs))?;
+        }
+        Ok(result)
+      }
       TypePrimitive::PointerTo(struct_type) => {
         // TODO: need to check whether self and/or other is NULL.
         let self_as_int = cg.builder.build_ptr_to_int(self.into_pointer_value()?, cg.context.i64_type(), "self_as_int");
@@ -677,7 +744,7 @@ pub enum UpdatePtrPurpose {
 
 impl <'ctx> CodegenState<'ctx> {
   pub fn read_ptr_for_field(&self, module: &ast::Module, state: PointerValue<'ctx>, name: &str) -> CodegenResult<StatePointer<'ctx>> {
-    let h_type = module.type_for_field(name).unwrap(); //ok_or(CodegenError::BadReadFieldName(name.to_string()))?;
+    let h_type = module.type_for_field(name).ok_or(CodegenError::BadReadFieldName(name.to_string()))?;
     if let Some(idx) = module.idx_for_field(name) {
       let struct_idx = (2 * idx).try_into().or(Err(CodegenError::InvalidIndex))?;
       let ptr = self.builder.build_struct_gep(state, struct_idx, &("read_ptr_to".to_string() + name)).or(Err(CodegenError::InvalidStructPointer("read_ptr_for_field given bad state pointer".to_string())))?;
@@ -0,0 +1,71 @@
+// JSON dumps of the parsed AST (--emit ast-json) and of modules after graph_to_module (--emit module-json).
+//
+// Both use serde's default representation of the ast types, inside an envelope that names the format:
+//
+//   { "format": "skunk-ast", "version": 1, "file": "Pair.skunk", "top_levels": [TopLevel, ...] }
+//   { "format": "skunk-module", "version": 1, "module": Module }
+//
+// Within that:
+// - structs are objects keyed by field name, e.g. a Handle is
+//   { "position": { "offset": 24, "line": 2 }, "name": "input", "usages": ["Read"], "h_type": "Int" }
+// - enum variants without data are strings ("Read", "Int"); variants with data are objects with a single
+//   key, e.g. { "Tuple": ["Int", "Char"] }, { "NamedModule": ["a", "UnsignedInt"] } or { "Module": {...} }
+// - positions are { "offset", "line" }: a byte offset into the file and a line number starting at 1
+// - maps (Module.tuples, ModuleInfo.handle_map) are objects in key order; handle_map goes from the
+//   submodule's handle names to the parent's
+// - Module.synthetic_handles names the handles that graph_to_module added rather than the source declared
+//
+// FORMAT_VERSION goes up whenever a change to the ast types changes this output.
+
+use super::ast;
+
+use serde::Serialize;
+
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct AstDump<'a> {
+  format: &'static str,
+  version: u32,
+  file: &'a str,
+  top_levels: &'a Vec<ast::TopLevel>,
+}
+
+#[derive(Serialize)]
+struct ModuleDump<'a> {
+  format: &'static str,
+  version: u32,
+  module: &'a ast::Module,
+}
+
+pub fn ast_json(file: &str, top_levels: &Vec<ast::TopLevel>) -> String {
+  serde_json::to_string_pretty(&AstDump { format: "skunk-ast", version: FORMAT_VERSION, file, top_levels }).unwrap()
+}
+
+pub fn module_json(module: &ast::Module) -> String {
+  serde_json::to_string_pretty(&ModuleDump { format: "skunk-module", version: FORMAT_VERSION, module }).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::parser;
+
+  #[test]
+  fn dumps_follow_the_documented_format() {
+    let (_, ast) = parser::parse("module Echo {\n  input: reads Int;\n  output: writes Int;\n\n  input.onChange: output <- input;\n}").unwrap();
+    let dump: serde_json::Value = serde_json::from_str(&ast_json("echo.skunk", &ast)).unwrap();
+    assert_eq!(dump["format"], "skunk-ast");
+    assert_eq!(dump["version"], FORMAT_VERSION);
+    let module = &dump["top_levels"][0]["Module"];
+    assert_eq!(module["name"], "Echo");
+    assert_eq!(module["handles"][0]["position"]["line"], 2);
+    assert_eq!(module["handles"][0]["usages"][0], "Read");
+    assert_eq!(module["handles"][1]["h_type"], "Int");
+    assert_eq!(module["listeners"][0]["trigger"], "input");
+
+    let dump: serde_json::Value = serde_json::from_str(&module_json(ast::modules(&ast)[0])).unwrap();
+    assert_eq!(dump["format"], "skunk-module");
+    assert_eq!(dump["module"]["synthetic_handles"], serde_json::json!([]));
+  }
+}
@@ -4,17 +4,22 @@ mod ir_gen;
 mod graph;
 mod graph_builder;
 mod graph_to_module;
+mod dot;
+mod json_dump;
+mod repl;
 
 use inkwell::targets::{InitializationConfig, Target, TargetMachine, TargetTriple, RelocMode, CodeModel, FileType};
-use inkwell::OptimizationLevel;
 use inkwell::context::Context;
+use inkwell::execution_engine::JitFunction;
+
+use ir_gen::codegen_state::OptLevel;
 
 use nom::Err;
 
 use std::env;
 use std::path::Path;
 use std::fs::File;
-use std::io::{prelude::*, stdout, stderr};
+use std::io::{prelude::*, stderr};
 
 use std::collections::HashMap;
 
@@ -58,13 +63,59 @@ struct FileData {
 }
 
 
+// What gets written out: object files unless --emit asks for something else.
+#[derive(Copy, Clone, PartialEq)]
+enum Emit {
+  Object,
+  GraphDot,
+  AstJson,
+  ModuleJson,
+  LlvmIr,
+  LlvmBc,
+  Asm,
+  // objects, plus Rust bindings for them
+  RustBindings,
+}
+
+impl Emit {
+  fn parse(kind: &str) -> Option<Emit> {
+    match kind {
+      "obj" => Some(Emit::Object),
+      "graph-dot" => Some(Emit::GraphDot),
+      "ast-json" => Some(Emit::AstJson),
+      "module-json" => Some(Emit::ModuleJson),
+      "llvm-ir" => Some(Emit::LlvmIr),
+      "llvm-bc" => Some(Emit::LlvmBc),
+      "asm" => Some(Emit::Asm),
+      "rust-bindings" => Some(Emit::RustBindings),
+      _ => None
+    }
+  }
+
+  // Whether this is a dump of the compiler's state before codegen, rather than of generated code.
+  fn is_dump(&self) -> bool {
+    match self {
+      Emit::GraphDot | Emit::AstJson | Emit::ModuleJson => true,
+      Emit::Object | Emit::LlvmIr | Emit::LlvmBc | Emit::Asm | Emit::RustBindings => false,
+    }
+  }
+}
+
 struct MainData {
   file_info: RefCell<HashMap<String, Rc<FileData>>>,
+  emit: Emit,
+  // (file name, contents) for the intermediate state that --emit asked for, collected while loading
+  dumps: RefCell<Vec<(String, String)>>,
 }
 
 impl MainData {
   fn new() -> Self {
-    Self { file_info: RefCell::new(HashMap::new()) }
+    Self { file_info: RefCell::new(HashMap::new()), emit: Emit::Object, dumps: RefCell::new(Vec::new()) }
+  }
+  fn dump(&self, kind: Emit, file_name: String, contents: impl FnOnce() -> String) {
+    if self.emit == kind {
+      self.dumps.borrow_mut().push((file_name, contents()));
+    }
   }
   fn load_file(&self, location: &str) -> Result<(), SkunkError> {
     let mut file_data = FileData::new();
@@ -108,15 +159,21 @@ impl FileData {
     let (remainder, mut ast) = match parser::parse(&self.buffer) {
       Ok(result) => result,
       Err(Err::Failure(e) | Err::Error(e)) => { 
-        println!("{}", e);
+        eprintln!("{}", e);
         return Err(SkunkError::ParseFailed);
       }
       Err(Err::Incomplete(_n)) => panic!("Should not be possible")
     };
     
     if remainder.fragment().len() > 0 {
-      println!("Left over: {}", remainder);
+      eprintln!("Left over: {}", remainder);
     }
+
+    let file_name = match slash {
+      None => location,
+      Some(pos) => &location[pos+1..]
+    };
+    main_data.dump(Emit::AstJson, format!("{}.ast.json", file_name.trim_end_matches(".skunk")), || json_dump::ast_json(location, &ast));
   
     let dependencies = ast::uses(&ast);
     let mut processed_modules = Vec::new();
@@ -128,18 +185,21 @@ impl FileData {
     }
 
     let newtypes = ast::newtypes(&ast).iter().map(|a| (*a).clone()).collect();
+    let fragments = ast::fragments(&ast).iter().map(|f| (*f).clone()).collect();
 
     { 
       let mut modules = ast::modules_mut(&mut ast);
       for i in 0..modules.len() {
         modules[i].resolve_types(&newtypes);
         if modules[i].graph.len() > 0 {
-          let mut graph = graph_builder::make_graph(modules[i].graph.iter().collect());
+          let mut graph = graph_builder::make_graph(modules[i].graph.iter().collect(), &fragments)?;
           let processed_refs = processed_modules.iter().map(|r| r.as_ref()).collect();
           graph_builder::resolve_graph(modules[i], &processed_refs, &mut graph)?;
+          main_data.dump(Emit::GraphDot, format!("{}.graph.dot", modules[i].name), || dot::graph_to_dot(&modules[i].name, &graph));
           graph_to_module::graph_to_module(modules[i], graph, processed_refs)?;
-          println!("{}", modules[i].minidump());
+          eprintln!("{}", modules[i].minidump());
         }
+        main_data.dump(Emit::ModuleJson, format!("{}.module.json", modules[i].name), || json_dump::module_json(&modules[i]));
         processed_modules.push(Rc::new(modules[i].clone()))
       }
     }
@@ -150,12 +210,14 @@ impl FileData {
     // TODO: Instead of duplicating graph processing logic, push the main module onto the end of the mutable modules list and
     // deal with it in the same pass as the rest.
     if ast_graphs.len() > 0 {
-      let mut graph = graph_builder::make_graph(ast::graphs(&self.ast));
+      let mut graph = graph_builder::make_graph(ast::graphs(&self.ast), &fragments)?;
       let mut main = ast::Module::create("Main", Vec::new(), Vec::new(), Vec::new(), ast::Examples { examples: Vec::new() }, Vec::new(), Vec::new());
 
       graph_builder::resolve_graph(&main, &processed_refs, &mut graph)?;
+      main_data.dump(Emit::GraphDot, format!("{}.graph.dot", main.name), || dot::graph_to_dot(&main.name, &graph));
 
       graph_to_module::graph_to_module(&mut main, graph, processed_refs)?;
+      main_data.dump(Emit::ModuleJson, format!("{}.module.json", main.name), || json_dump::module_json(&main));
       self.main_module = Some(Rc::new(main));
     } else if processed_refs.len() == 1 {
       // TODO: This isn't really correct - there needs to be some way
@@ -176,87 +238,294 @@ impl FileData {
   }
 }
 
+// Command line options, for compiling and for the examples, run and repl commands.
+struct Options {
+  file: String,
+  emit: Emit,
+  opt_level: OptLevel,
+  // None means the host
+  target: Option<String>,
+  target_cpu: String,
+  target_features: String,
+  // --bind values, for the run command
+  bindings: Vec<String>,
+  // everything after --, passed on by the run command
+  program_args: Vec<String>,
+  // for the examples command: link an executable with clang rather than running examples through the JIT
+  link: bool,
+}
+
+impl Options {
+  fn parse(args: &[String]) -> Options {
+    let mut options = Options {
+      file: "test.skunk".to_string(),
+      emit: Emit::Object,
+      opt_level: OptLevel::default(),
+      target: None,
+      target_cpu: "generic".to_string(),
+      target_features: String::new(),
+      bindings: Vec::new(),
+      program_args: Vec::new(),
+      link: false,
+    };
+    let mut remaining = args.iter();
+    while let Some(arg) = remaining.next() {
+      if let Some(opt_level) = OptLevel::parse(arg) {
+        options.opt_level = opt_level;
+        continue;
+      }
+      match arg.as_str() {
+        "--emit" => {
+          let kind = option_value(&mut remaining, arg);
+          options.emit = Emit::parse(&kind).unwrap_or_else(|| usage_error(&format!("Unknown --emit kind '{}'", kind)));
+        }
+        "--target" => options.target = Some(option_value(&mut remaining, arg)),
+        "--target-cpu" => options.target_cpu = option_value(&mut remaining, arg),
+        "--target-features" => options.target_features = option_value(&mut remaining, arg),
+        "--link" => options.link = true,
+        "--bind" => options.bindings.push(option_value(&mut remaining, arg)),
+        "--" => options.program_args.extend(remaining.by_ref().cloned()),
+        _ => options.file = arg.clone()
+      }
+    }
+    options
+  }
+}
+
+fn option_value<'a>(remaining: &mut impl Iterator<Item = &'a String>, flag: &str) -> String {
+  remaining.next().cloned().unwrap_or_else(|| usage_error(&format!("{} needs a value", flag)))
+}
+
+fn usage_error(message: &str) -> ! {
+  eprintln!("{}", message);
+  std::process::exit(1);
+}
+
 fn main() {
   let args: Vec<String> = env::args().collect();
 
   if args.len() > 2 && args[1] == "examples" {
-    let file = &args[2];
+    let options = Options::parse(&args[2..]);
     let mut main_data = MainData::new();
-    build_test_examples(&mut main_data, file).unwrap();
+    std::process::exit(build_test_examples(&mut main_data, &options).unwrap());
+  }
+
+  if args.len() > 2 && args[1] == "repl" {
+    let options = Options::parse(&args[2..]);
+    repl::repl(&options.file);
     return;
   }
 
-  let (target_triple, target_machine) = target_triple_and_machine();
-  let context = Context::create();
+  if args.len() > 2 && args[1] == "run" {
+    let options = Options::parse(&args[2..]);
+    let mut main_data = MainData::new();
+    match run_module(&mut main_data, &options) {
+      Ok(code) => std::process::exit(code),
+      Err(error) => {
+        eprintln!("Can't run {}: {:?}", options.file, error);
+        std::process::exit(1);
+      }
+    }
+  }
 
+  let options = Options::parse(&args[1..]);
   let mut main_data = MainData::new();
-  main_data.load_file("test.skunk").unwrap();
-  let main = main_data.main_module_for_file("test.skunk").unwrap();
+  main_data.emit = options.emit;
+  main_data.load_file(&options.file).unwrap();
+  let main = main_data.main_module_for_file(&options.file).unwrap();
+
+  if main_data.emit.is_dump() {
+    for (name, contents) in main_data.dumps.borrow().iter() {
+      write_output(name, contents);
+    }
+    if main_data.emit == Emit::GraphDot {
+      write_output(&format!("{}.modules.dot", main.name), &dot::module_to_dot(&main));
+    }
+    return;
+  }
+
+  let (target_triple, target_machine) = target_triple_and_machine(&options);
+  let context = Context::create();
 
-  let mut target_info = ir_gen::codegen_state::TargetInfo { target_machine: &target_machine, target_triple: &target_triple };
+  let mut target_info = ir_gen::codegen_state::TargetInfo { target_machine: &target_machine, target_triple: &target_triple, opt_level: options.opt_level };
   let cg_modules = ir_gen::codegen(&context, &mut target_info, &main).unwrap();
 
-  for module in cg_modules {
-    let name = module.get_name().to_str().unwrap();
-    println!("Outputting object file for {}", name);
-    // module.print_to_stderr();
-    let object_name = name.to_string() + ".o";
-    let path = Path::new(&object_name);
-    target_machine.write_to_file(&module, FileType::Object, path).unwrap();
+  for module in &cg_modules {
+    write_module(&target_machine, module, &main_data.emit);
+  }
+  for (name, contents) in ir_gen::c_header::c_headers(&main) {
+    write_output(&name, &contents);
+  }
+  if main_data.emit == Emit::RustBindings {
+    write_output(&format!("{}_bindings.rs", main.name), &ir_gen::rust_bindings::rust_bindings(&main));
+    write_output(&format!("{}_build.rs", main.name), &ir_gen::rust_bindings::build_helper(&main));
   }
 
+  // Objects are linked against a main by `examples --link`; when inspecting generated code, that main is wanted too.
+  if let Emit::LlvmIr | Emit::LlvmBc | Emit::Asm = main_data.emit {
+    let examples_main = ir_gen::main_for_examples(&context, &target_machine, &target_triple, options.opt_level, &cg_modules).unwrap();
+    write_module(&target_machine, &examples_main, &main_data.emit);
+  }
+}
+
+// Writes <module name>.o, .s, .ll or .bc, and returns the file name.
+fn write_module(target_machine: &TargetMachine, module: &inkwell::module::Module, emit: &Emit) -> String {
+  let name = module.get_name().to_str().unwrap();
+  let extension = match emit {
+    Emit::LlvmIr => "ll",
+    Emit::LlvmBc => "bc",
+    Emit::Asm => "s",
+    _ => "o",
+  };
+  let file_name = format!("{}.{}", name, extension);
+  eprintln!("Writing {}", file_name);
+  let path = Path::new(&file_name);
+  let written = match emit {
+    Emit::LlvmIr => module.print_to_file(path).map_err(|e| e.to_string()),
+    Emit::LlvmBc => if module.write_bitcode_to_path(path) { Ok(()) } else { Err("LLVM couldn't write the bitcode".to_string()) },
+    Emit::Asm => target_machine.write_to_file(module, FileType::Assembly, path).map_err(|e| e.to_string()),
+    _ => target_machine.write_to_file(module, FileType::Object, path).map_err(|e| e.to_string()),
+  };
+  if let Err(error) = written {
+    eprintln!("Can't write {}: {}", file_name, error);
+    std::process::exit(1);
+  }
+  file_name
 }
 
 
 
+fn write_output(name: &str, contents: &str) {
+  eprintln!("Writing {}", name);
+  std::fs::write(name, contents).unwrap();
+}
+
 // TODO: have this return a TargetInfo instead?
-fn target_triple_and_machine() -> (TargetTriple, TargetMachine) {
+fn target_triple_and_machine(options: &Options) -> (TargetTriple, TargetMachine) {
   Target::initialize_all(&InitializationConfig::default());
 
-  let target_triple = TargetMachine::get_default_triple();
-  let target = Target::from_triple(&target_triple).unwrap();
-  let target_machine = target.create_target_machine(&target_triple, "generic", "", OptimizationLevel::Default, RelocMode::Default, CodeModel::Default).unwrap();
+  let target_triple = match &options.target {
+    Some(triple) => TargetTriple::create(triple),
+    None => TargetMachine::get_default_triple(),
+  };
+  let target = Target::from_triple(&target_triple)
+    .unwrap_or_else(|e| usage_error(&format!("Unknown target '{}': {}", target_triple.as_str().to_string_lossy(), e)));
+  let target_machine = target.create_target_machine(
+    &target_triple, &options.target_cpu, &options.target_features, options.opt_level.llvm_level(), RelocMode::Default, CodeModel::Default
+  ).unwrap_or_else(|| usage_error(&format!("Can't create a target machine for cpu '{}' with features '{}'", options.target_cpu, options.target_features)));
   (target_triple, target_machine)
 }
 
-fn build_test_examples(main_data: &mut MainData, location: &str) -> Result<(), SkunkError> {
+// Runs every example in the main module and its submodules in-process through the JIT, and returns the
+// number that failed (up to 255) as an exit code. With --link, builds <file>_examples with clang instead,
+// whose exit code is the same, and returns whether that worked.
+fn build_test_examples(main_data: &mut MainData, options: &Options) -> Result<i32, SkunkError> {
+  let location = &options.file;
   main_data.load_file(location)?;
   let main_module = main_data.main_module_for_file(location).unwrap();
+  if options.link {
+    return Ok(if link_examples(&main_module, options)? { 0 } else { 1 });
+  }
+  if options.target.is_some() {
+    usage_error("Examples are run on this machine; use --link to build them for another target");
+  }
 
-  let (target_triple, target_machine) = target_triple_and_machine();
-  let mut target_info = ir_gen::codegen_state::TargetInfo { target_machine: &target_machine, target_triple: &target_triple };
+  let context = Context::create();
+  let mut jit_info = ir_gen::codegen_state::JitInfo::with_opt_level(options.opt_level);
+  let cg_modules = ir_gen::codegen(&context, &mut jit_info, &main_module)?;
+  let execution_engine = jit_info.execution_engine.unwrap();
+
+  let mut count = 0;
+  let mut failures = 0;
+  for module in &cg_modules {
+    let name = module.get_name().to_str().unwrap();
+    let fn_name = name.to_string() + "_run_examples";
+    if module.get_function(&fn_name).is_none() {
+      continue;
+    }
+    unsafe {
+      let run_examples: JitFunction<unsafe extern "C" fn() -> u64> = execution_engine.get_function(&fn_name).unwrap();
+      let example_count: JitFunction<unsafe extern "C" fn() -> u64> = execution_engine.get_function(&(name.to_string() + "__get_example_count")).unwrap();
+      failures += run_examples.call();
+      count += example_count.call();
+    }
+    ir_gen::codegen_state::flush_jit_output();
+  }
+  println!("\n{} examples, {} failed", count, failures);
+  Ok(failures.min(255) as i32)
+}
+
+fn link_examples(main_module: &ast::Module, options: &Options) -> Result<bool, SkunkError> {
+  let (target_triple, target_machine) = target_triple_and_machine(options);
+  let mut target_info = ir_gen::codegen_state::TargetInfo { target_machine: &target_machine, target_triple: &target_triple, opt_level: options.opt_level };
 
   let context = Context::create();
 
   // we need object code for all of these
-  let cg_modules = ir_gen::codegen(&context, &mut target_info, &main_module)?;
+  let cg_modules = ir_gen::codegen(&context, &mut target_info, main_module)?;
 
   let mut objects: Vec<String> = Vec::new();
   for module in &cg_modules {
-    // module.print_to_stderr();
-    let name = module.get_name().to_str().unwrap();
-    let object_name = name.to_string() + ".o";
-    let path = Path::new(&object_name);
-    target_machine.write_to_file(&module, FileType::Object, path).unwrap();
-    objects.push(object_name);
+    objects.push(write_module(&target_machine, module, &Emit::Object));
   }
 
-  let main_module = ir_gen::main_for_examples(&context, &target_machine, &target_triple, &cg_modules)?;
-  let object_name = "main.o";
-  let path = Path::new(&object_name);
-  target_machine.write_to_file(&main_module, FileType::Object, path).unwrap();
-  objects.push(object_name.to_string());
+  let examples_main = ir_gen::main_for_examples(&context, &target_machine, &target_triple, options.opt_level, &cg_modules)?;
+  objects.push(write_module(&target_machine, &examples_main, &Emit::Object));
+
+  Ok(link(options, objects, &(options.file.clone() + "_examples")))
+}
+
+// Builds <file>_run, whose main feeds the main module's inputs and prints its outputs as the --bind
+// options ask, then runs it with the arguments after --. Returns its exit code.
+fn run_module(main_data: &mut MainData, options: &Options) -> Result<i32, SkunkError> {
+  let bindings: Vec<ir_gen::codegen::Binding> = options.bindings.iter().map(|binding| {
+    ir_gen::codegen::Binding::parse(binding).unwrap_or_else(|| usage_error(&format!(
+      "Can't understand --bind {}; expected <handle>=stdin-lines, file:<path>, arg:<n>, stdout or error", binding
+    )))
+  }).collect();
+
+  let location = &options.file;
+  main_data.load_file(location)?;
+  let main_module = main_data.main_module_for_file(location).ok_or(SkunkError::FileNotFound(location.to_string()))?;
+
+  let (target_triple, target_machine) = target_triple_and_machine(options);
+  let mut target_info = ir_gen::codegen_state::TargetInfo { target_machine: &target_machine, target_triple: &target_triple, opt_level: options.opt_level };
+
+  let context = Context::create();
+  let cg_modules = ir_gen::codegen(&context, &mut target_info, &main_module)?;
+
+  let mut objects: Vec<String> = Vec::new();
+  for module in &cg_modules {
+    objects.push(write_module(&target_machine, module, &Emit::Object));
+  }
+  let run_main = match ir_gen::main_for_run(&context, &mut target_info, &main_module, &bindings) {
+    Err(ir_gen::codegen_state::CodegenError::BadBinding(message)) => usage_error(&message),
+    result => result?,
+  };
+  objects.push(write_module(&target_machine, &run_main, &Emit::Object));
+
+  let executable = location.to_string() + "_run";
+  if !link(options, objects, &executable) {
+    return Ok(1);
+  }
+  let status = Command::new(Path::new(".").join(&executable)).args(&options.program_args).status().expect("failed to run the linked program");
+  Ok(status.code().unwrap_or(1))
+}
 
+// Links objects against the C library with clang, and returns whether that worked.
+fn link(options: &Options, objects: Vec<String>, executable: &str) -> bool {
   let mut command = Command::new("clang");
-  let mut cmd = command.arg("-o").arg(&(location.to_string() + "_examples"));
-  dbg!(&objects);
+  let mut cmd = command.arg("-o").arg(executable);
+  if let Some(target) = &options.target {
+    cmd = cmd.arg(format!("--target={}", target));
+  }
   for object in objects {
     cmd = cmd.arg(object);
   }
 
+  // Anything clang says goes to stderr, leaving stdout to the program that skunk run runs.
   let output = cmd.arg("-lc").output().expect("failed to run clang");
-  stdout().write_all(&output.stdout).unwrap();
+  stderr().write_all(&output.stdout).unwrap();
   stderr().write_all(&output.stderr).unwrap();
-
-  Ok(())
+  output.status.success()
 }
\ No newline at end of file
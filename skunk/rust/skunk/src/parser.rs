@@ -8,9 +8,9 @@ use nom::{
   branch::alt,
   bytes::complete::{tag, is_a, take, take_until},
   character::complete::{alpha1, char, multispace0, multispace1, digit1}, 
-  combinator::{verify, eof, cut, opt},
+  combinator::{verify, eof, cut, opt, map, map_res},
   error::{Error, ErrorKind, VerboseError, VerboseErrorKind},
-  multi::{separated_list0, separated_list1, many0, many_till},
+  multi::{separated_list0, separated_list1, many0, many1, many_till},
   sequence::{tuple, delimited, terminated, preceded},
 };
 
@@ -345,15 +345,22 @@ fn example_value(i: Span) -> ParseResult<(String, ast::ExampleInfo)> {
   Ok((i, (name.fragment().to_string(), ast::ExampleInfo { value: expr, is_update: is_update.is_some() })))
 }
 
+// `!name = expression` or `name = expression`, as typed at the repl.
+fn repl_write(i: Span) -> ParseResult<(String, ast::ExampleInfo)> {
+  let (i, (is_update, name, expr)) = tuple((opt(char('!')), name, preceded(tuple((multispace0, char('='), multispace0)), expression(0))))(i)?;
+  Ok((i, (name.fragment().to_string(), ast::ExampleInfo { value: expr, is_update: is_update.is_some() })))
+}
+
 fn example_values(i: Span) -> ParseResult<Vec<(String, ast::ExampleInfo)>> {
   separated_list0(tuple((multispace0, char(','), multispace0)), example_value)(i)
 }
 
 fn example(i: Span) -> ParseResult<ast::Example> {
+  let (i, position) = position(i)?;
   let (i, (mut inputs, mut expected)) = tuple((
     example_values, preceded(tuple((multispace0, tag("->"), multispace0)), example_values)
   ))(i)?;
-  Ok((i, ast::Example { inputs: inputs.drain(..).collect(), expected: expected.drain(..).collect() }))
+  Ok((i, ast::Example { position: position.safe(), inputs: inputs.drain(..).collect(), expected: expected.drain(..).collect() }))
 }
 
 fn examples(i: Span) -> ParseResult<ast::Examples> {
@@ -441,11 +448,54 @@ fn field_specifier(i: Span) -> ParseResult<ast::GraphModuleInfo> {
   Ok((i, ast::GraphModuleInfo::Field(ast::ModuleSpecifier::This, name.to_string())))
 }
 
+fn port_owner(i: Span) -> ParseResult<ast::ModuleSpecifier> {
+  alt((
+    map(tuple((delimited(char('$'), name, tuple((multispace0, char(':'), multispace0))), uppercase_name)),
+      |(local_name, module_name)| ast::ModuleSpecifier::NamedModule(local_name.to_string(), module_name.to_string())),
+    map(preceded(char('$'), name), |local_name| ast::ModuleSpecifier::Name(local_name.to_string())),
+    map(uppercase_name, |module_name| ast::ModuleSpecifier::Module(module_name.to_string())),
+  ))(i)
+}
+
+// $a.output, $a:UnsignedInt.result or UnsignedInt.result
+fn port_specifier(i: Span) -> ParseResult<ast::GraphModuleInfo> {
+  let (i, (specifier, port)) = tuple((port_owner, preceded(char('.'), name)))(i)?;
+  Ok((i, ast::GraphModuleInfo::Field(specifier, port.to_string())))
+}
+
+// 42, "abc", 'c' or a tuple of these
+fn graph_literal(i: Span) -> ParseResult<ast::Expression> {
+  alt((int_literal, string_literal, char_literal, graph_literal_tuple))(i)
+}
+
+fn graph_literal_tuple(i: Span) -> ParseResult<ast::Expression> {
+  let (i, position) = position(i)?;
+  let (i, members) = delimited(
+    tuple((char('('), multispace0)),
+    separated_list1(tuple((multispace0, char(','), multispace0)), graph_literal),
+    tuple((multispace0, char(')')))
+  )(i)?;
+  Ok((i, ast::Expression::tuple(position.safe(), members)))
+}
+
+// |x| expression
+fn lambda(i: Span) -> ParseResult<(String, ast::Expression)> {
+  let (i, (param, body)) = tuple((
+    delimited(tuple((char('|'), multispace0)), name, tuple((multispace0, char('|'), multispace0))),
+    expression(0)
+  ))(i)?;
+  Ok((i, (param.to_string(), body)))
+}
+
+fn graph_transform(i: Span) -> ParseResult<ast::GraphModuleInfo> {
+  map(lambda, |(param, body)| ast::GraphModuleInfo::Transform(param, body))(i)
+}
+
 fn graph_module_info(i: Span) -> ParseResult<ast::GraphModuleInfo> {
-  alt((graph_module_tuple, graph_module_specifier, graph_name_specifier, field_specifier))(i)
+  alt((graph_transform, map(graph_literal, ast::GraphModuleInfo::Literal), graph_module_tuple, port_specifier, graph_module_specifier, graph_name_specifier, field_specifier))(i)
 }
 
-fn graph(i: Span) -> ParseResult<ast::GraphDirective> {
+fn graph_chain(i: Span) -> ParseResult<ast::GraphDirective> {
   let (input, names) = terminated(
     separated_list1(tuple((multispace0, tag("->"), multispace0)), graph_module_info), 
     tuple((multispace0, char(';')))
@@ -456,6 +506,79 @@ fn graph(i: Span) -> ParseResult<ast::GraphDirective> {
   ))
 }
 
+fn merge_combiner(i: Span) -> ParseResult<ast::MergePolicy> {
+  let (i, (pending_name, incoming_name, expr)) = tuple((
+    preceded(tuple((char('|'), multispace0)), name),
+    delimited(tuple((multispace0, char(','), multispace0)), name, tuple((multispace0, char('|'), multispace0))),
+    expression(0)
+  ))(i)?;
+  Ok((i, ast::MergePolicy::Combine(pending_name.to_string(), incoming_name.to_string(), Box::new(expr.into()))))
+}
+
+fn merge_policy(i: Span) -> ParseResult<ast::MergePolicy> {
+  alt((
+    token("first", ast::MergePolicy::FirstWriteWins),
+    token("last", ast::MergePolicy::LastWriteWins),
+    merge_combiner
+  ))(i)
+}
+
+fn graph_merge(i: Span) -> ParseResult<ast::GraphDirective> {
+  let (i, (_, h_name, _, policy, _)) = tuple((
+    tuple((tag("merge"), multispace1)),
+    name,
+    tuple((multispace0, char(':'), multispace0)),
+    cut(merge_policy),
+    tuple((multispace0, char(';')))
+  ))(i)?;
+  Ok((i, ast::GraphDirective::Merge(h_name.to_string(), policy)))
+}
+
+// output, |x| predicate or _
+fn switch_case(i: Span) -> ParseResult<ast::SwitchCase> {
+  alt((
+    map(lambda, |(param, predicate)| ast::SwitchCase::When(param, predicate)),
+    token("_", ast::SwitchCase::Otherwise),
+    map(name, |output| ast::SwitchCase::Output(output.to_string()))
+  ))(i)
+}
+
+// case -> Module -> ...;
+fn switch_arm(i: Span) -> ParseResult<(ast::SwitchCase, Vec<ast::GraphModuleInfo>)> {
+  tuple((
+    terminated(switch_case, tuple((multispace0, tag("->"), multispace0))),
+    terminated(
+      separated_list1(tuple((multispace0, tag("->"), multispace0)), graph_module_info),
+      tuple((multispace0, char(';')))
+    )
+  ))(i)
+}
+
+fn graph_switch(i: Span) -> ParseResult<ast::GraphDirective> {
+  let (i, (_, source, _, arms, _)) = tuple((
+    tuple((tag("switch"), multispace1)),
+    graph_module_info,
+    tuple((multispace0, char('{'), multispace0)),
+    cut(many1(terminated(switch_arm, multispace0))),
+    char('}')
+  ))(i)?;
+  Ok((i, ast::GraphDirective::Switch(source, arms)))
+}
+
+// iterate 16;
+fn graph_iterate(i: Span) -> ParseResult<ast::GraphDirective> {
+  let (i, (_, bound, _)) = tuple((
+    tuple((tag("iterate"), multispace1)),
+    map_res(digit1, |bound: Span| bound.parse::<u64>()),
+    tuple((multispace0, char(';')))
+  ))(i)?;
+  Ok((i, ast::GraphDirective::Iterate(bound)))
+}
+
+fn graph(i: Span) -> ParseResult<ast::GraphDirective> {
+  alt((graph_iterate, graph_merge, graph_switch, graph_chain))(i)
+}
+
 fn use_statement(i: Span) -> ParseResult<ast::Use> {
   let (input, name) = delimited(
     tuple((tag("uses"), multispace1)),
@@ -474,6 +597,29 @@ fn newtype(i: Span) -> ParseResult<ast::NewType> {
   Ok((i, ast::NewType { name: name.to_string(), nt_type }))
 }
 
+// graph Name<param, ...> { directives }
+fn graph_fragment(i: Span) -> ParseResult<ast::GraphFragment> {
+  let (i, (_, name, params, _, graph, _)) = tuple((
+    tag("graph"),
+    delimited(multispace1, uppercase_name, multispace0),
+    opt(terminated(delimited(
+      tuple((char('<'), multispace0)),
+      separated_list1(tuple((multispace0, char(','), multispace0)), name),
+      tuple((multispace0, char('>')))
+    ), multispace0)),
+    tuple((char('{'), multispace0)),
+    module_graphs,
+    tuple((multispace0, char('}')))
+  ))(i)?;
+  let params = params.unwrap_or(vec!()).iter().map(|param| param.to_string()).collect();
+  Ok((i, ast::GraphFragment { name: name.to_string(), params, graph }))
+}
+
+fn fragment_top_level(i: Span) -> ParseResult<ast::TopLevel> {
+  let (input, fragment) = graph_fragment(i)?;
+  Ok((input, ast::TopLevel::Fragment(fragment)))
+}
+
 fn graph_top_level(i: Span) -> ParseResult<ast::TopLevel> {
   let (input, graph) = graph(i)?;
   Ok((input, ast::TopLevel::Graph(graph)))
@@ -495,7 +641,7 @@ fn newtype_top_level(i: Span) -> ParseResult<ast::TopLevel> {
 }
 
 fn top_level(i: Span) -> ParseResult<ast::TopLevel> {
-  alt((graph_top_level, module_top_level, use_top_level, newtype_top_level))(i)
+  alt((fragment_top_level, graph_top_level, module_top_level, use_top_level, newtype_top_level))(i)
 }
 
 // TODO: Make this private, and provide a public wrapper that is nicer
@@ -511,6 +657,14 @@ pub fn parse<'a>(i: &'a str) -> ParseResult<Vec<ast::TopLevel>> {
   Ok((input, result))
 }
 
+pub fn parse_repl_write<'a>(i: &'a str) -> ParseResult<(String, ast::ExampleInfo)> {
+  let (input, (result, _)) = tuple((
+    delimited(multispace0, repl_write, multispace0),
+    eof
+  ))(Span::new(i))?;
+  Ok((input, result))
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -678,6 +832,22 @@ mod tests {
     )
   }
 
+  #[test]
+  fn parse_graph_fragment() {
+    assert_eq!(
+      top_level(Span::new("graph Listed<sep> {\n  $a:UnsignedInt -> Char<sep> -> $b:UnsignedInt;\n}")).unwrap().1,
+      ast::TopLevel::Fragment(ast::GraphFragment {
+        name: "Listed".to_string(),
+        params: vec!("sep".to_string()),
+        graph: vec!(ast::GraphDirective::Chain(vec!(
+          ast::GraphModuleInfo::module("UnsignedInt", Some("a"), vec!()),
+          ast::GraphModuleInfo::module("Char", None, vec!(ast::Expression::state_reference(ast::SafeSpan { offset: 45, line: 2 }, "sep"))),
+          ast::GraphModuleInfo::module("UnsignedInt", Some("b"), vec!()),
+        )))
+      })
+    );
+  }
+
   #[test]
   fn parse_top_level() {
     assert_eq!(
@@ -699,6 +869,91 @@ mod tests {
     )
   }
   
+  #[test]
+  fn parse_graph_ports() {
+    assert_eq!(
+      graph(Span::new("$a.output -> $b:Spaces.input -> Spaces;")).unwrap().1,
+      ast::GraphDirective::Chain(vec!(
+        ast::GraphModuleInfo::Field(ast::ModuleSpecifier::Name("a".to_string()), "output".to_string()),
+        ast::GraphModuleInfo::Field(ast::ModuleSpecifier::NamedModule("b".to_string(), "Spaces".to_string()), "input".to_string()),
+        gmi("Spaces"),
+      ))
+    );
+    assert_eq!(
+      graph(Span::new("UnsignedInt.result -> result;")).unwrap().1,
+      ast::GraphDirective::Chain(vec!(
+        ast::GraphModuleInfo::Field(ast::ModuleSpecifier::Module("UnsignedInt".to_string()), "result".to_string()),
+        ast::GraphModuleInfo::Field(ast::ModuleSpecifier::This, "result".to_string()),
+      ))
+    );
+  }
+
+  #[test]
+  fn parse_graph_literals() {
+    assert_eq!(
+      graph(Span::new("(\"abc\", 0) -> $p.input;")).unwrap().1,
+      ast::GraphDirective::Chain(vec!(
+        ast::GraphModuleInfo::Literal(ast::Expression::tuple(ast::SafeSpan { offset: 0, line: 1 }, vec!(
+          ast::Expression::string_literal(ast::SafeSpan { offset: 1, line: 1 }, "abc"),
+          ast::Expression::int_literal(ast::SafeSpan { offset: 8, line: 1 }, 0),
+        ))),
+        ast::GraphModuleInfo::Field(ast::ModuleSpecifier::Name("p".to_string()), "input".to_string()),
+      ))
+    );
+  }
+
+  #[test]
+  fn parse_graph_transforms() {
+    assert_eq!(
+      graph(Span::new("$a -> |x| x + 1 -> $b;")).unwrap().1,
+      ast::GraphDirective::Chain(vec!(
+        ast::GraphModuleInfo::module_name("a"),
+        ast::GraphModuleInfo::Transform("x".to_string(), ast::Expression::binary_operator(ast::SafeSpan { offset: 12, line: 1 },
+          ast::Expression::state_reference(ast::SafeSpan { offset: 10, line: 1 }, "x"),
+          ast::Operator::Add,
+          ast::Expression::int_literal(ast::SafeSpan { offset: 14, line: 1 }, 1))),
+        ast::GraphModuleInfo::module_name("b"),
+      ))
+    );
+  }
+
+  #[test]
+  fn parse_graph_switch() {
+    assert_eq!(
+      graph(Span::new("switch $p {\n  error -> Fallback;\n  _ -> $next;\n}")).unwrap().1,
+      ast::GraphDirective::Switch(ast::GraphModuleInfo::module_name("p"), vec!(
+        (ast::SwitchCase::Output("error".to_string()), vec!(gmi("Fallback"))),
+        (ast::SwitchCase::Otherwise, vec!(ast::GraphModuleInfo::module_name("next"))),
+      ))
+    );
+    if let ast::GraphDirective::Switch(_, arms) = graph(Span::new("switch input { |x| x < 10 -> Small -> output; }")).unwrap().1 {
+      assert!(matches!(&arms[0].0, ast::SwitchCase::When(param, _) if param == "x"));
+      assert_eq!(arms[0].1, vec!(gmi("Small"), ast::GraphModuleInfo::Field(ast::ModuleSpecifier::This, "output".to_string())));
+    } else {
+      panic!("expected a switch");
+    }
+  }
+
+  #[test]
+  fn parse_graph_iterate() {
+    assert_eq!(graph(Span::new("iterate 16;")).unwrap().1, ast::GraphDirective::Iterate(16));
+    assert!(graph(Span::new("iterate 99999999999999999999;")).is_err());
+  }
+
+  #[test]
+  fn parse_graph_merge() {
+    assert_eq!(
+      graph(Span::new("merge error: first;")).unwrap().1,
+      ast::GraphDirective::Merge("error".to_string(), ast::MergePolicy::FirstWriteWins)
+    );
+    assert_eq!(
+      graph(Span::new("merge total: |a, b| a + b;")).unwrap().1,
+      ast::GraphDirective::Merge("total".to_string(), ast::MergePolicy::Combine("a".to_string(), "b".to_string(),
+        Box::new(Expr::sref(20, 0, "a").op(2, 0, ast::Operator::Add, Expr::sref(2, 0, "b")).build())
+      ))
+    );
+  }
+
   #[test]
   fn parse_graph_tuple() {
     let test_str = "(Module1, Module2)";
@@ -752,4 +1007,16 @@ mod tests {
       ).build()
     );
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn parse_repl_writes() {
+    let (_, (name, info)) = parse_repl_write(" !input = (\"3, 4\", 0) ").unwrap();
+    assert_eq!(name, "input");
+    assert!(info.is_update);
+    let (_, (name, info)) = parse_repl_write("count=3").unwrap();
+    assert_eq!(name, "count");
+    assert!(!info.is_update);
+    assert!(parse_repl_write("count: 3").is_err());
+    assert!(parse_repl_write("count = 3 4").is_err());
+  }
+}
@@ -0,0 +1,334 @@
+// skunk repl <file>: JIT-compiles the file's main module and reads commands against one state:
+//   !handle = expression   writes a pending update, as !handle: expression does in an example
+//   handle = expression    sets the current value without an update
+//   :step                  runs the update function once
+//   :run [max steps]       runs updates until none are pending
+//   :dump                  prints the state
+//   :reset                 starts again from a new state, and forgets the history
+//   :history               lists the commands that got the state where it is
+//   :quit
+// When the file changes, the module is rebuilt and the history replayed against it.
+
+use super::{MainData, SkunkError, ast, ir_gen, parser};
+
+use ir_gen::codegen::{RUN_NOT_CONVERGED, update_limit, write_function_codegen};
+use ir_gen::codegen_state::{CodegenStateConstructor, JitInfo, flush_jit_output};
+
+use inkwell::context::Context;
+use inkwell::execution_engine::{JitFunction, UnsafeFunctionPointer};
+
+use std::ffi::c_void;
+use std::fs;
+use std::io::{prelude::*, stdin, stdout};
+use std::ptr;
+use std::time::SystemTime;
+
+type StateFunc = unsafe extern "C" fn(*mut c_void);
+type InitFunc = unsafe extern "C" fn() -> *mut c_void;
+type RunFunc = unsafe extern "C" fn(*mut c_void, u64) -> i64;
+
+enum Outcome {
+  Quit,
+  // the file changed; the command that noticed is run after the history is replayed
+  Reload(String),
+}
+
+pub fn repl(file: &str) {
+  let mut history = Vec::new();
+  let stdin = stdin();
+  let mut lines = stdin.lock().lines().map(|line| line.unwrap());
+  let mut pending = None;
+  loop {
+    let modified = modified_time(file);
+    match session(file, &mut history, pending.take(), &mut lines) {
+      Ok(Outcome::Quit) => return,
+      Ok(Outcome::Reload(line)) => {
+        println!("{} changed; reloading", file);
+        pending = Some(line);
+      }
+      Err(error) => {
+        println!("Can't load {}: {:?}", file, error);
+        println!("Waiting for {} to change", file);
+        loop {
+          prompt();
+          if lines.next().is_none() {
+            return;
+          }
+          if modified_time(file) != modified {
+            break;
+          }
+        }
+      }
+    }
+  }
+}
+
+fn modified_time(file: &str) -> Option<SystemTime> {
+  fs::metadata(file).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn prompt() {
+  print!("> ");
+  stdout().flush().unwrap();
+}
+
+fn session(file: &str, history: &mut Vec<String>, pending: Option<String>, lines: &mut impl Iterator<Item = String>) -> Result<Outcome, SkunkError> {
+  let modified = modified_time(file);
+  let context = Context::create();
+  let main_data = MainData::new();
+  main_data.load_file(file)?;
+  let main = main_data.main_module_for_file(file).ok_or(SkunkError::FileNotFound(file.to_string()))?;
+
+  let mut jit_info = JitInfo::new();
+  ir_gen::codegen(&context, &mut jit_info, &main)?;
+  let mut repl = Repl::new(&context, jit_info, &main);
+
+  for command in history.iter() {
+    if let Err(message) = repl.command(command, true) {
+      println!("{}: {}", command, message);
+    }
+  }
+  let mut next = pending;
+  loop {
+    let line = match next.take() {
+      Some(line) => line,
+      None => {
+        prompt();
+        match lines.next() {
+          Some(line) => line,
+          None => return Ok(Outcome::Quit),
+        }
+      }
+    };
+    if modified_time(file) != modified {
+      return Ok(Outcome::Reload(line));
+    }
+    let line = line.trim().to_string();
+    match line.as_str() {
+      "" => continue,
+      ":quit" => return Ok(Outcome::Quit),
+      ":history" => {
+        for (idx, command) in history.iter().enumerate() {
+          println!("{:4}  {}", idx + 1, command);
+        }
+      }
+      ":reset" => {
+        repl.reset();
+        history.clear();
+      }
+      _ => match repl.command(&line, false) {
+        Ok(true) => history.push(line),
+        Ok(false) => (),
+        Err(message) => println!("{}", message),
+      }
+    }
+  }
+}
+
+struct Repl<'ctx> {
+  context: &'ctx Context,
+  jit_info: JitInfo<'ctx>,
+  module: &'ctx ast::Module,
+  state: *mut c_void,
+  // for naming the modules that writes are compiled into
+  writes: usize,
+}
+
+impl <'ctx> Repl<'ctx> {
+  fn new(context: &'ctx Context, jit_info: JitInfo<'ctx>, module: &'ctx ast::Module) -> Self {
+    let mut repl = Repl { context, jit_info, module, state: ptr::null_mut(), writes: 0 };
+    repl.state = unsafe { repl.function::<InitFunc>("_init").call() };
+    repl
+  }
+
+  // <Module><suffix>, e.g. function("_init") for <Module>_init.
+  fn function<F: UnsafeFunctionPointer>(&self, suffix: &str) -> JitFunction<'ctx, F> {
+    let name = format!("{}{}", self.module.name, suffix);
+    unsafe { self.jit_info.execution_engine.as_ref().unwrap().get_function(&name) }.unwrap_or_else(|e| panic!("{} is missing: {:?}", name, e))
+  }
+
+  fn reset(&mut self) {
+    unsafe {
+      self.function::<StateFunc>("_deinit").call(self.state);
+      self.state = self.function::<InitFunc>("_init").call();
+    }
+  }
+
+  fn dump(&self) {
+    unsafe {
+      self.function::<StateFunc>("__dump").call(self.state);
+      flush_jit_output();
+    }
+  }
+
+  // Runs one command; quiet is for replaying the history. Returns whether the command changed the state.
+  fn command(&mut self, line: &str, quiet: bool) -> Result<bool, String> {
+    let mut words = line.split_whitespace();
+    match words.next().unwrap_or("") {
+      ":dump" => {
+        self.dump();
+        Ok(false)
+      }
+      ":step" => {
+        unsafe { self.function::<StateFunc>("_update").call(self.state) };
+        if !quiet {
+          self.dump();
+        }
+        Ok(true)
+      }
+      ":run" => {
+        let max_steps = match words.next() {
+          Some(steps) => steps.parse().map_err(|_| format!("Expected a number of steps, not {}", steps))?,
+          None => update_limit(self.module),
+        };
+        let steps = unsafe { self.function::<RunFunc>("_run").call(self.state, max_steps) };
+        if !quiet {
+          self.dump();
+          if steps == RUN_NOT_CONVERGED {
+            println!("Updates still pending after {} steps", max_steps);
+          } else {
+            println!("Settled after {} steps", steps);
+          }
+        }
+        Ok(true)
+      }
+      word if word.starts_with(':') => Err(format!("Unknown command {}", word)),
+      _ => {
+        self.write(line)?;
+        Ok(true)
+      }
+    }
+  }
+
+  fn write(&mut self, line: &str) -> Result<(), String> {
+    let (field, value) = parser::parse_repl_write(line).map_err(|e| format!("Expected !handle = value or handle = value: {}", e))?.1;
+    if self.module.type_for_field(&field).is_none() {
+      return Err(format!("{} has no field called {}", self.module.name, field));
+    }
+    self.writes += 1;
+    let suffix = format!("__repl_write_{}", self.writes);
+    let name = format!("{}{}", self.module.name, suffix);
+    let mut cg = self.jit_info.construct(self.context, &name);
+    cg.root_module = Some(self.module.name.clone());
+    let result = write_function_codegen(&mut cg, self.module, &field, &value, &name);
+    if result.is_ok() {
+      unsafe { self.function::<StateFunc>(&suffix).call(self.state) };
+    }
+    // Each write gets a module of its own, which is only needed until it's been called (or has failed to
+    // build, when it mustn't be left for the execution engine to trip over).
+    self.jit_info.execution_engine.as_ref().unwrap().remove_module(&cg.module).unwrap();
+    result.map(|_| ()).map_err(|error| format!("Can't write {}: {:?}", field, error))
+  }
+}
+
+impl <'ctx> Drop for Repl<'ctx> {
+  fn drop(&mut self) {
+    unsafe { self.function::<StateFunc>("_deinit").call(self.state) };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::iter;
+  use std::thread;
+  use std::time::Duration;
+
+  type ReadFunc = unsafe extern "C" fn(*mut c_void) -> i64;
+
+  static COUNTER_STRING: &str = "
+module Counter {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 1;
+}
+";
+
+  static COUNTER_BY_TWO_STRING: &str = "
+module Counter {
+  input: reads Int;
+  output: writes Int;
+
+  input.onChange: output <- input + 2;
+}
+";
+
+  fn with_repl<T>(source: &str, func: impl FnOnce(&mut Repl) -> T) -> T {
+    let (_, ast) = parser::parse(source).unwrap();
+    let module = ast::modules(&ast)[0];
+    let context = Context::create();
+    let mut jit_info = JitInfo::new();
+    ir_gen::codegen(&context, &mut jit_info, module).unwrap();
+    let mut repl = Repl::new(&context, jit_info, module);
+    func(&mut repl)
+  }
+
+  fn read(repl: &Repl, handle: &str) -> i64 {
+    unsafe { repl.function::<ReadFunc>(&format!("_read_{}", handle)).call(repl.state) }
+  }
+
+  #[test]
+  fn commands_step_run_and_reset_one_state() {
+    let history = with_repl(COUNTER_STRING, |repl| {
+      let history = vec!("!input = 7".to_string(), ":step".to_string());
+      for command in &history {
+        assert_eq!(repl.command(command, true), Ok(true), "{}", command);
+      }
+      assert_eq!((read(repl, "input"), read(repl, "output")), (7, 0));
+      assert_eq!(repl.command(":run", true), Ok(true));
+      assert_eq!((read(repl, "input"), read(repl, "output")), (7, 8));
+      assert_eq!(repl.command(":dump", true), Ok(false));
+      for command in &[":run lots", ":bogus", "missing = 1", "input: 3"] {
+        assert!(repl.command(command, true).is_err(), "{}", command);
+      }
+      // failed commands leave the state alone
+      assert_eq!((read(repl, "input"), read(repl, "output")), (7, 8));
+      assert_eq!(repl.command("output = 3", true), Ok(true));
+      assert_eq!(read(repl, "output"), 3);
+
+      repl.reset();
+      assert_eq!((read(repl, "input"), read(repl, "output")), (0, 0));
+      history
+    });
+
+    // Replaying the history gets a new state to the same place.
+    with_repl(COUNTER_STRING, |repl| {
+      for command in &history {
+        assert_eq!(repl.command(command, true), Ok(true), "{}", command);
+      }
+      assert_eq!(repl.command(":run 5", true), Ok(true));
+      assert_eq!(read(repl, "output"), 8);
+    });
+  }
+
+  #[test]
+  fn edits_reload_the_module_and_replay_the_history() {
+    let file = std::env::temp_dir().join(format!("skunk_repl_{}.skunk", std::process::id())).to_str().unwrap().to_string();
+    fs::write(&file, COUNTER_STRING).unwrap();
+    let mut history = Vec::new();
+    let mut lines = vec!("!input = 7", ":run").into_iter().map(|line| line.to_string()).chain(iter::once_with(|| {
+      // file times are coarse, so make sure the edit gets a new one
+      thread::sleep(Duration::from_millis(50));
+      fs::write(&file, COUNTER_BY_TWO_STRING).unwrap();
+      ":dump".to_string()
+    }));
+    let pending = match session(&file, &mut history, None, &mut lines) {
+      Ok(Outcome::Reload(line)) => line,
+      Ok(Outcome::Quit) => panic!("{} was edited, but the session didn't reload it", file),
+      Err(error) => panic!("Can't load {}: {:?}", file, error),
+    };
+    assert_eq!(pending, ":dump");
+    assert_eq!(history, vec!("!input = 7", ":run"));
+    assert!(matches!(session(&file, &mut history, Some(pending), &mut lines), Ok(Outcome::Quit)));
+    fs::remove_file(&file).unwrap();
+
+    with_repl(COUNTER_BY_TWO_STRING, |repl| {
+      for command in &history {
+        assert_eq!(repl.command(command, true), Ok(true), "{}", command);
+      }
+      assert_eq!(read(repl, "output"), 9);
+    });
+  }
+}